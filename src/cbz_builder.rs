@@ -1,28 +1,48 @@
 use anyhow::{Context, Result};
-use std::fs::File;
+use std::fs::{self, File};
+use std::path::Path;
 use zip::write::SimpleFileOptions;
 use zip::ZipWriter;
 
-use crate::comic::Comic;
+use crate::comic::{Comic, OutputFormat, PageNaming};
+
+/// Written next to the pages when `record_encoding` is set
+const ENCODING_SIDECAR: &str = "comically.json";
 
 pub fn build_cbz(comic: &Comic) -> Result<()> {
     log::info!("Building CBZ: {:?}", comic);
 
-    let output_path = comic.output_path();
+    let output_path = comic.output_path(OutputFormat::Cbz);
     let file = File::create(&output_path)?;
     let mut zip = ZipWriter::new(file);
+    if let Some(producer) = comic.config.producer() {
+        zip.set_comment(producer);
+    }
 
-    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let mut options =
+        SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    if comic.config.deterministic {
+        options = options.last_modified_time(zip::DateTime::default());
+    }
 
     // Add images in order
-    for image in comic.processed_files.iter() {
-        let file_name = image.path.file_name().unwrap().to_string_lossy();
-        zip.start_file(file_name, options)?;
+    for (i, image) in comic.processed_files.iter().enumerate() {
+        zip.start_file(page_file_name(comic, i, &image.path), options)?;
         let image_data = std::fs::read(&image.path)
             .with_context(|| format!("Failed to read image: {:?}", image.path))?;
         std::io::Write::write_all(&mut zip, &image_data)?;
     }
 
+    if comic.config.record_encoding {
+        let format = comic.config.image_format;
+        let sidecar = serde_json::json!({
+            "encoding": format.encoding(),
+            "image_format": format,
+        });
+        zip.start_file(ENCODING_SIDECAR, options)?;
+        std::io::Write::write_all(&mut zip, sidecar.to_string().as_bytes())?;
+    }
+
     // TODO: Add ComicInfo.xml if we have metadata
 
     zip.finish()?;
@@ -31,3 +51,43 @@ pub fn build_cbz(comic: &Comic) -> Result<()> {
 
     Ok(())
 }
+
+/// Copies the pages into a folder named after the comic, named as they would be in a CBZ.
+/// They're copied next to it first, so a failed copy leaves an existing folder as it was
+pub fn build_folder(comic: &Comic) -> Result<()> {
+    let output_path = comic.output_path(OutputFormat::Folder);
+    log::info!("Building folder: {:?}", output_path);
+
+    let parent = output_path.parent().unwrap_or(Path::new("."));
+    let staging = tempfile::tempdir_in(parent)?;
+    for (i, image) in comic.processed_files.iter().enumerate() {
+        fs::copy(
+            &image.path,
+            staging.path().join(page_file_name(comic, i, &image.path)),
+        )
+        .with_context(|| format!("Failed to copy image: {:?}", image.path))?;
+    }
+
+    if output_path.is_dir() {
+        fs::remove_dir_all(&output_path)
+            .with_context(|| format!("Failed to replace {:?}", output_path))?;
+    }
+    fs::rename(staging.path(), &output_path)
+        .with_context(|| format!("Failed to move pages to {:?}", output_path))?;
+
+    log::info!("Created folder: {:?}", output_path);
+    Ok(())
+}
+
+/// Name of page `i` inside the output
+fn page_file_name(comic: &Comic, i: usize, path: &Path) -> String {
+    match comic.config.page_naming {
+        PageNaming::Sequential => {
+            // pad sequential names so every reader sorts them correctly
+            let width = comic.processed_files.len().to_string().len();
+            let extension = path.extension().unwrap().to_string_lossy();
+            format!("{:0width$}.{extension}", i + 1)
+        }
+        PageNaming::Original => path.file_name().unwrap().to_string_lossy().into(),
+    }
+}
@@ -1,14 +1,19 @@
 use std::{
     borrow::Cow,
+    collections::HashMap,
     fs,
-    path::PathBuf,
-    sync::mpsc,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, LazyLock,
+    },
     time::{Duration, Instant},
 };
 
 use crate::Event;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ComicStage {
     Process,
     Package, // Building the output format (EPUB/CBZ)
@@ -25,7 +30,92 @@ impl std::fmt::Display for ComicStage {
     }
 }
 
+/// A supported file format, as shown to users
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FormatInfo {
+    pub name: &'static str,
+    pub extension: &'static str,
+}
+
+/// Output formats this build can produce
+pub fn supported_output_formats() -> Vec<FormatInfo> {
+    OutputFormat::ALL
+        .iter()
+        .map(|format| FormatInfo {
+            name: format.display_name(),
+            extension: format.extension(),
+        })
+        .collect()
+}
+
 impl OutputFormat {
+    pub const ALL: [OutputFormat; 4] = [
+        OutputFormat::Mobi,
+        OutputFormat::Epub,
+        OutputFormat::Cbz,
+        OutputFormat::Folder,
+    ];
+
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            OutputFormat::Mobi => "AZW3/MOBI",
+            OutputFormat::Epub => "EPUB",
+            OutputFormat::Cbz => "CBZ",
+            OutputFormat::Folder => "Folder",
+        }
+    }
+
+    /// Empty for `Folder`, which is a directory of pages rather than a file
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Mobi => "mobi",
+            OutputFormat::Epub => "epub",
+            OutputFormat::Cbz => "cbz",
+            OutputFormat::Folder => "",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| {
+            !format.extension().is_empty() && extension.eq_ignore_ascii_case(format.extension())
+        })
+    }
+
+    /// Format implied by a concrete output file like `out/Book.epub`,
+    /// `None` when the path is a directory
+    pub fn infer_from_path(path: &Path) -> anyhow::Result<Option<Self>> {
+        // ebook and archive extensions that look like an output file but can't be written
+        const UNSUPPORTED: &[&str] = &["azw", "azw3", "kfx", "pdf", "cbr", "cb7", "rar", "zip"];
+
+        if path.is_dir() {
+            return Ok(None);
+        }
+        let Some(extension) = path.extension().map(|ext| ext.to_string_lossy()) else {
+            return Ok(None);
+        };
+        if let Some(format) = Self::from_extension(&extension) {
+            return Ok(Some(format));
+        }
+        if UNSUPPORTED
+            .iter()
+            .any(|unsupported| extension.eq_ignore_ascii_case(unsupported))
+        {
+            anyhow::bail!(
+                "Can't write .{} files ({}), the output extension must be one of {}",
+                extension,
+                path.display(),
+                Self::ALL
+                    .iter()
+                    .filter(|format| !format.extension().is_empty())
+                    .map(|format| format!(".{}", format.extension()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        // anything else is a directory with a dot in its name
+        Ok(None)
+    }
+
     pub fn stage_weight(&self, stage: ComicStage) -> f64 {
         match (self, stage) {
             // MOBI format weights
@@ -42,6 +132,11 @@ impl OutputFormat {
             (OutputFormat::Cbz, ComicStage::Process) => 0.85,
             (OutputFormat::Cbz, ComicStage::Package) => 0.05, // CBZ building
             (OutputFormat::Cbz, ComicStage::Convert) => 0.0,  // Not used
+
+            // Folder format weights
+            (OutputFormat::Folder, ComicStage::Process) => 0.9,
+            (OutputFormat::Folder, ComicStage::Package) => 0.02, // copying pages
+            (OutputFormat::Folder, ComicStage::Convert) => 0.0,
         }
     }
 }
@@ -53,12 +148,17 @@ pub enum ComicStatus {
         stage: ComicStage,
         progress: f64,
         start: Instant,
+        // what the stage is doing, MOBI packages an EPUB and then runs KindleGen
+        step: &'static str,
     },
     ImageProcessingStart {
         total_images: usize,
         start: Instant,
     },
-    ImageProcessed,
+    // pages finished since the last update, more than one with `progress_granularity`
+    ImageProcessed {
+        count: usize,
+    },
     ImageProcessingComplete {
         duration: Duration,
     },
@@ -66,18 +166,200 @@ pub enum ComicStatus {
         stage: ComicStage,
         duration: Duration,
     },
-    Success,
+    Success {
+        stats: ComicStats,
+    },
+    // Finished, but pages were skipped or the output needs a second look
+    CompletedWithWarnings {
+        stats: ComicStats,
+        warnings: Vec<String>,
+    },
     Failed {
-        error: anyhow::Error,
+        error: ComicError,
     },
 }
 
+/// Why a comic failed, for callers that handle some failures differently
+#[derive(Debug)]
+pub enum ComicError {
+    // the archive's pages need a password
+    EncryptedArchive,
+    UnsupportedFormat(String),
+    // none of the pages could be read or decoded
+    NoPages,
+    KindleGenMissing,
+    // another comic failed with `fail_fast` set
+    Cancelled,
+    // stopped on its own through `CancelComics`, the rest of the batch went on
+    CancelledByUser,
+    // not started, `batch_size_budget` was used up
+    OverBudget,
+    // a write ran out of space on the temp or output disk
+    DiskFull(anyhow::Error),
+    // not started, the output is there and wasn't to be replaced
+    OutputExists(PathBuf),
+    Other(anyhow::Error),
+}
+
+impl std::fmt::Display for ComicError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ComicError::EncryptedArchive => write!(f, "Archive is password protected"),
+            ComicError::UnsupportedFormat(extension) => {
+                write!(f, "Unsupported archive format: {}", extension)
+            }
+            ComicError::NoPages => write!(f, "No readable pages found"),
+            ComicError::KindleGenMissing => write!(f, "KindleGen is not installed"),
+            ComicError::Cancelled => write!(f, "Cancelled, another comic in the batch failed"),
+            ComicError::CancelledByUser => write!(f, "Cancelled"),
+            ComicError::OverBudget => write!(f, "Skipped, the batch size budget is used up"),
+            ComicError::DiskFull(e) => write!(f, "Disk full: {}", e),
+            ComicError::OutputExists(path) => {
+                write!(f, "Skipped, {} already exists", path.display())
+            }
+            ComicError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ComicError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ComicError::DiskFull(e) | ComicError::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Finds a `ComicError` raised anywhere below, even under added context
+impl From<anyhow::Error> for ComicError {
+    fn from(error: anyhow::Error) -> Self {
+        error.downcast().unwrap_or_else(|error| {
+            if is_disk_full(&error) {
+                ComicError::DiskFull(error)
+            } else {
+                ComicError::Other(error)
+            }
+        })
+    }
+}
+
+/// Whether a write failed for lack of space, anywhere in the error's chain
+pub fn is_disk_full(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::StorageFull)
+    })
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ComicStats {
+    // size of the input archive
+    pub input_bytes: u64,
+    // combined size of every output written for the comic
+    pub output_bytes: u64,
+    // size of the biggest single output, for comparing against the input
+    pub largest_output_bytes: u64,
+    // JPEG quality picked to hit target_size, None without a target
+    pub target_quality: Option<u8>,
+    // every output's pages were encoded losslessly, an archival copy rather than a
+    // device-optimized one
+    pub lossless: bool,
+    // pages by the format they were saved in, passthrough keeps the source's
+    pub jpeg_pages: usize,
+    pub png_pages: usize,
+    pub webp_pages: usize,
+}
+
+impl ComicStats {
+    /// output size as a fraction of the input size, below 1.0 means space was saved
+    pub fn compression_ratio(&self) -> f64 {
+        if self.input_bytes == 0 {
+            return 1.0;
+        }
+        self.output_bytes as f64 / self.input_bytes as f64
+    }
+
+    /// an output bigger than its input usually means upscaling or lossless photos
+    pub fn output_larger_than_input(&self) -> bool {
+        self.largest_output_bytes > self.input_bytes
+    }
+
+    /// Page counts like "40 jpg, 2 png", only the formats that were used
+    pub fn page_formats(&self) -> String {
+        [
+            ("jpg", self.jpeg_pages),
+            ("png", self.png_pages),
+            ("webp", self.webp_pages),
+        ]
+        .iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(format, count)| format!("{count} {format}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+
+    /// Pages were saved in more than one format
+    pub fn mixed_formats(&self) -> bool {
+        [self.jpeg_pages, self.png_pages, self.webp_pages]
+            .iter()
+            .filter(|count| **count > 0)
+            .count()
+            > 1
+    }
+}
+
 pub enum ProgressEvent {
-    RegisterComic { id: usize, file_name: String },
-    ComicUpdate { id: usize, status: ComicStatus },
+    RegisterComic {
+        id: usize,
+        file_name: String,
+    },
+    // A worker picked the comic up, which can be in any order after registration
+    ComicStarted {
+        id: usize,
+    },
+    ComicUpdate {
+        id: usize,
+        status: ComicStatus,
+    },
+    // Every input's page count, sent once the comics are registered and before any is
+    // processed, with `batch_plan` enabled. Inputs that can't be opened count no pages
+    BatchPlan {
+        total_comics: usize,
+        total_pages: usize,
+    },
+    // The comic still completes, but something likely needs attention
+    Warning {
+        id: usize,
+        message: String,
+    },
+    // Every successful output was stored in the `bundle` zip
+    Bundled {
+        path: PathBuf,
+        bytes: u64,
+    },
+    // Source and output dimensions of a page, only sent with `page_events` enabled
+    PageProcessed {
+        id: usize,
+        name: String,
+        original: (u32, u32),
+        processed: Vec<(u32, u32)>,
+    },
     ProcessingComplete,
 }
 
+impl ProgressEvent {
+    /// Wraps the event for the channel, numbered after every event sent before it
+    pub fn into_event(self) -> Event {
+        static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+        Event::Progress {
+            seq: SEQUENCE.fetch_add(1, Ordering::Relaxed),
+            event: self,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum SplitStrategy {
     None,
@@ -86,11 +368,250 @@ pub enum SplitStrategy {
     RotateAndSplit,
 }
 
+/// A turn given to every page whatever its shape, unlike the rotation of wide pages
 #[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum RotateMode {
+    None,
+    Cw90,
+    Ccw90,
+    Rotate180,
+}
+
+impl RotateMode {
+    /// Width and height of a `width` by `height` page once it's turned
+    pub fn dimensions(self, (width, height): (u32, u32)) -> (u32, u32) {
+        match self {
+            RotateMode::Cw90 | RotateMode::Ccw90 => (height, width),
+            RotateMode::None | RotateMode::Rotate180 => (width, height),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub enum OutputFormat {
     Mobi,
     Epub,
     Cbz,
+    // the processed pages as loose files in a directory named after the comic
+    Folder,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum PageNaming {
+    // Zero-padded sequential names (0001.jpg, 0002.jpg, ...)
+    Sequential,
+    // Keep the names of the processed images
+    Original,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum CmykMode {
+    // Inverted when the JPEG has an Adobe APP14 segment, normal otherwise
+    Auto,
+    // Values are stored inverted (0 = full ink), as Photoshop writes them
+    Inverted,
+    // Values are stored as plain ink amounts (255 = full ink)
+    Normal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct ByteSize(pub u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ContactSheetConfig {
+    pub columns: u32,
+    // width of each thumbnail, heights follow the pages' aspect ratio
+    pub thumb_width: u32,
+}
+
+impl Default for ContactSheetConfig {
+    fn default() -> Self {
+        Self {
+            columns: 6,
+            thumb_width: 160,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct WatermarkConfig {
+    // drawn in black with `font`, ignored when `image` is set
+    pub text: Option<String>,
+    // TrueType or OpenType font file for the text
+    pub font: Option<PathBuf>,
+    // stamped as is, its own transparency included
+    pub image: Option<PathBuf>,
+    pub position: WatermarkPosition,
+    // 0 is invisible, 1 fully opaque
+    pub opacity: f32,
+    // width of the watermark as a fraction of the page's
+    pub scale: f32,
+}
+
+impl Default for WatermarkConfig {
+    fn default() -> Self {
+        Self {
+            text: None,
+            font: None,
+            image: None,
+            position: WatermarkPosition::BottomRight,
+            opacity: 0.25,
+            scale: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    Center,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum QualityProfile {
+    // Lossless pages with full color detail, for keeping rather than reading
+    Archival,
+    // The defaults
+    Balanced,
+    // Lower quality JPEGs with subsampled color, noticeably smaller files
+    Small,
+}
+
+impl QualityProfile {
+    /// The defaults with the profile's settings applied
+    pub fn config(self) -> ComicConfig {
+        let base = ComicConfig {
+            profile: Some(self),
+            ..ComicConfig::default()
+        };
+        match self {
+            QualityProfile::Archival => ComicConfig {
+                image_format: ImageFormat::Png {
+                    compression: PngCompression::Best,
+                },
+                jpeg_subsampling: JpegSubsampling::Chroma444,
+                downscale_filter: ResizeFilter::Lanczos3,
+                ..base
+            },
+            QualityProfile::Balanced => base,
+            QualityProfile::Small => ComicConfig {
+                image_format: ImageFormat::Jpeg { quality: 60 },
+                jpeg_subsampling: JpegSubsampling::Chroma420,
+                downscale_filter: ResizeFilter::Triangle,
+                dimension_multiple: Some(16),
+                ..base
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ArchiveFormat {
+    Zip,
+    Rar,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum JpegSubsampling {
+    // Full color resolution
+    Chroma444,
+    // Half horizontal color resolution
+    Chroma422,
+    // Half horizontal and vertical color resolution, the smallest files
+    Chroma420,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum PageCanvas {
+    // Pages keep the size they were resized to
+    Natural,
+    // Pad every page to the largest page of the comic
+    Largest,
+    // Pad every page to the device resolution
+    Device,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ComicLogs {
+    Off,
+    // Each comic's lines also go to `<name>.log` next to its output
+    Also,
+    // Each comic's lines only go to its own log, the global one keeps the batch-wide lines
+    Only,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ProgressGranularity {
+    // An update every this many pages
+    Pages(usize),
+    // An update every this much of the comic's pages
+    Percent(f32),
+}
+
+impl ProgressGranularity {
+    /// Pages per progress update for a comic of `total_pages`
+    pub fn step(&self, total_pages: usize) -> usize {
+        let step = match *self {
+            ProgressGranularity::Pages(pages) => pages,
+            ProgressGranularity::Percent(percent) => {
+                (total_pages as f32 * percent / 100.0).ceil() as usize
+            }
+        };
+        step.max(1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ScaleMode {
+    // The whole page fits the device, leaving bars when the aspect ratios differ
+    Fit,
+    // Fills the device and crops what overflows, keeping the center
+    Fill,
+    // Fills the device exactly, distorting the page
+    Stretch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum OverwritePolicy {
+    Overwrite,
+    // the comic is skipped, its outputs left as they are
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum DuplicateCheck {
+    Off,
+    // same file size and first page, quick since only one page is read
+    Quick,
+    // same contents, every input is read in full
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ReaderProfile {
+    // Works on most readers, Kindle metadata included
+    Compatible,
+    // Kindle devices and apps, through KindleGen or sideloaded
+    Kindle,
+    // Apple Books, which only lays out fixed pages once told to and adds margins to the cover
+    AppleBooks,
+    // KOReader, which lays out one page at a time and keeps the body margins
+    KoReader,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -126,6 +647,19 @@ impl ImageFormat {
         }
     }
 
+    /// PNG keeps every processed pixel, JPEG and WebP re-encode lossily
+    pub fn is_lossless(&self) -> bool {
+        matches!(self, ImageFormat::Png { .. })
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        if self.is_lossless() {
+            "lossless"
+        } else {
+            "lossy"
+        }
+    }
+
     pub fn adjust_quality(&mut self, increase: bool, fine: bool) {
         let step = if fine { 1 } else { 5 };
         match self {
@@ -166,6 +700,7 @@ impl PngCompression {
 }
 
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct ComicConfig {
     pub device: DevicePreset,
     pub right_to_left: bool,
@@ -175,8 +710,160 @@ pub struct ComicConfig {
     // Gamma correction: 0.0-3.0
     pub gamma: f32,
     pub output_format: OutputFormat,
+    // Packaged from the same processed images as `output_format`
+    pub additional_formats: Vec<OutputFormat>,
     pub margin_color: Option<u8>,
     pub image_format: ImageFormat,
+    // Fixed zip timestamps and identifiers so identical inputs produce identical outputs
+    pub deterministic: bool,
+    pub page_naming: PageNaming,
+    // Copy EXIF from source pages into JPEG output, stripped by default
+    pub preserve_metadata: bool,
+    // Only process the first N pages, output is marked with a `.preview` suffix
+    pub preview_pages: Option<usize>,
+    // How CMYK JPEG pages are converted to RGB
+    pub cmyk_mode: CmykMode,
+    // Stop the whole batch as soon as any comic fails
+    pub fail_fast: bool,
+    // Keep colorful pages (e.g. covers) in color, everything else is grayscale
+    pub auto_grayscale: bool,
+    // Pixel density written into JPEG and PNG pages, left unset by default
+    pub dpi: Option<u16>,
+    // Used when the page is larger than the device, Lanczos3 preserves more detail
+    pub downscale_filter: ResizeFilter,
+    // Used when the page fits within the device, CatmullRom avoids ringing
+    pub upscale_filter: ResizeFilter,
+    // Send a PageProcessed event for every page, off by default to keep the event volume down
+    pub page_events: bool,
+    // Pages written to disk at once across the batch, unlimited by default. Helps slow disks
+    pub max_concurrent_writes: Option<usize>,
+    // Give every page the same size so fixed-layout readers don't rescale between pages
+    pub page_canvas: PageCanvas,
+    // Skip a page that takes longer than this to decode, off by default since each timed
+    // decode runs on a thread of its own
+    pub page_timeout_secs: Option<u64>,
+    // Reading order as archive entry names, unlisted pages go last
+    pub page_order: Option<Vec<String>>,
+    // Chroma resolution of color JPEG pages, 4:4:4 keeps fine colored text sharp
+    pub jpeg_subsampling: JpegSubsampling,
+    // Approximate size of each comic's pages, JPEG quality is searched per comic to hit it
+    pub target_size: Option<ByteSize>,
+    // Note the page encoding, lossless or lossy, inside CBZ and EPUB outputs
+    pub record_encoding: bool,
+    // Images under this many pixels wide or tall are thumbnails, not pages
+    pub min_page_dimension: u32,
+    // Keep the cover in color while every other page is grayscaled
+    pub color_cover: bool,
+    // Tag outputs with the comically version that made them
+    pub producer_tag: bool,
+    // Threads that read, decode and process pages, rayon's pool when unset
+    pub decode_threads: Option<usize>,
+    // Pages encoded at once, as many as are being decoded when unset
+    pub encode_threads: Option<usize>,
+    // Pages sort by this regex's first capture group, unmatched pages go last
+    pub sort_key_regex: Option<String>,
+    // Copy pages already sized for the device as they are, when nothing else would change them
+    pub passthrough: bool,
+    // Round each channel to this many levels for tiny line-art files, 2-255
+    pub posterize_levels: Option<u8>,
+    // Read every input as this container instead of detecting it from the file's first bytes
+    pub archive_format: Option<ArchiveFormat>,
+    // Put each comic in a folder named after its series, taken from the file name
+    pub series_folders: bool,
+    // Separate R/G/B gamma, replacing `gamma` on color scans to correct color casts
+    pub channel_gamma: Option<[f32; 3]>,
+    // Images stacked on each EPUB page after the cover, more than 1 suits strip content
+    pub images_per_page: usize,
+    // Write `<name>.error.txt` next to the output of a comic that fails
+    pub error_sidecar: bool,
+    // After the batch, store every successful output in one zip at this path
+    pub bundle: Option<PathBuf>,
+    // Round resized pages down to a multiple of this, e.g. 8 or 16 for JPEG blocks
+    pub dimension_multiple: Option<u32>,
+    // Preset for the image settings, fields set in the config file still win
+    pub profile: Option<QualityProfile>,
+    // Debugging aid: pages are decoded and re-encoded without any transform, which
+    // separates packaging bugs from image processing ones
+    pub debug_identity: bool,
+    // Create a missing output directory up front, otherwise the batch stops before starting
+    pub create_output_dir: bool,
+    // Grid of page thumbnails written next to the output as `<name>.contact.jpg`
+    pub contact_sheet: Option<ContactSheetConfig>,
+    // Invert every page, for scans of negatives
+    pub invert: bool,
+    // Invert only the pages that look like negatives, light lines on a dark background
+    pub auto_invert: bool,
+    // Run after packaging for every output, with its path as the last argument and a
+    // JSON description of the comic on stdin. For metadata formats comically doesn't write
+    pub metadata_command: Option<Vec<String>>,
+    // Stop starting comics once the successful outputs add up to this,
+    // comics already running still finish
+    pub batch_size_budget: Option<ByteSize>,
+    // Page codec for some output formats, the others use image_format.
+    // Each codec is encoded from the processed page, pages restored from the page cache are
+    // re-encoded from the image_format ones instead
+    pub format_codecs: HashMap<OutputFormat, ImageFormat>,
+    // Text or image stamped on every page after resizing, for review copies
+    pub watermark: Option<WatermarkConfig>,
+    // How pages with another aspect ratio than the device are fitted to it
+    pub scale_mode: ScaleMode,
+    // Log file per comic, for following one title through a parallel batch
+    pub comic_logs: ComicLogs,
+    // How often processed pages are reported, coalescing the updates in between
+    pub progress_granularity: ProgressGranularity,
+    // EPUB and MOBI outputs only hold the cover, for placeholder books in a library
+    pub cover_only: bool,
+    // Upper bound on the pages' long edge whatever the device, pages are never enlarged to it
+    pub max_long_edge: Option<u32>,
+    // Folder for each output format, relative ones are inside the output directory.
+    // Formats not listed go straight to the output directory
+    pub format_dirs: HashMap<OutputFormat, PathBuf>,
+    // EPUB reader the markup is tuned for, working around its fixed-layout quirks
+    pub reader_profile: ReaderProfile,
+    // Folder keeping processed pages between runs, so another output format reuses them
+    pub page_cache: Option<PathBuf>,
+    // With auto_crop, every page is cropped by the margins all of them share rather than
+    // its own, so the content doesn't shift from page to page. Takes a pass over the pages first
+    pub shared_crop: bool,
+    // Margins cut from every page instead of auto_crop, as left, top, right and bottom.
+    // shared_crop fills it in from its first pass
+    pub crop_margins: Option<[u32; 4]>,
+    // Whether existing outputs are replaced, when no overwrite hook is there to ask
+    pub overwrite: OverwritePolicy,
+    // Halves of a spread stored as two files, named like 012a/012b or 012_L/012_R,
+    // are stitched back into one page. Pair it with split None, or it's split again
+    pub merge_spreads: bool,
+    // Seed for the steps that pick at random, so a run can be repeated exactly.
+    // None draws a new one every run. Only the EPUB identifier without deterministic uses it
+    pub seed: Option<u64>,
+    // Write <name>.pages.json next to the output, listing each page's archive entry
+    // and what was done to it, for tracking down ordering issues
+    pub page_manifest: bool,
+    // Pages `split` applies to, numbered from 1 in reading order with the end left out,
+    // so 12..14 is pages 12 and 13. Empty applies it to every page.
+    // Pages are numbered from the archive's entry names, pages of nested archives and
+    // renamed duplicates have no number and are always split
+    pub split_ranges: Vec<Range<usize>>,
+    // Convert pages with an embedded ICC profile to sRGB before processing, for scans
+    // that look oversaturated otherwise. Needs comically built with the `icc` feature
+    pub color_profiles: bool,
+    // Count every input's pages before processing starts, so progress has a total from
+    // the start. Off by default since it opens each archive an extra time
+    pub batch_plan: bool,
+    // Wide pages split into more than two panels, as (minimum aspect ratio, panels),
+    // e.g. [2.2, 3] for triple-wide foldouts. The largest matching ratio wins,
+    // anything wider than tall that matches none is split in two
+    pub panorama_splits: Vec<(f32, usize)>,
+    // Entries whose path in the archive matches this regex aren't pages, e.g. `(^|/)ad_[^/]*\.jpg$`
+    pub exclude_pages: Option<String>,
+    // Levels of zips inside an archive whose pages are read too, nested zips are skipped at 0
+    pub nested_archives: usize,
+    // Turn every page before it's cropped, split and resized, for devices read in landscape
+    pub rotate: RotateMode,
+    // Warn about inputs that look like another one in the batch before processing
+    pub duplicate_check: DuplicateCheck,
+    // Progressive JPEG pages, often a little smaller, but some readers only take baseline ones
+    pub jpeg_progressive: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -198,8 +885,75 @@ impl Default for ComicConfig {
             brightness: -10,
             gamma: 1.8,
             output_format: OutputFormat::Mobi,
+            additional_formats: Vec::new(),
             margin_color: None,
             image_format: ImageFormat::Jpeg { quality: 85 },
+            deterministic: false,
+            page_naming: PageNaming::Sequential,
+            preserve_metadata: false,
+            preview_pages: None,
+            cmyk_mode: CmykMode::Auto,
+            fail_fast: false,
+            auto_grayscale: false,
+            dpi: None,
+            downscale_filter: ResizeFilter::Lanczos3,
+            upscale_filter: ResizeFilter::CatmullRom,
+            page_events: false,
+            max_concurrent_writes: None,
+            page_canvas: PageCanvas::Natural,
+            page_timeout_secs: None,
+            page_order: None,
+            jpeg_subsampling: JpegSubsampling::Chroma444,
+            target_size: None,
+            record_encoding: false,
+            min_page_dimension: 64,
+            color_cover: false,
+            producer_tag: true,
+            decode_threads: None,
+            encode_threads: None,
+            sort_key_regex: None,
+            passthrough: false,
+            posterize_levels: None,
+            archive_format: None,
+            series_folders: false,
+            channel_gamma: None,
+            images_per_page: 1,
+            error_sidecar: false,
+            bundle: None,
+            dimension_multiple: None,
+            profile: None,
+            debug_identity: false,
+            create_output_dir: true,
+            contact_sheet: None,
+            invert: false,
+            auto_invert: false,
+            metadata_command: None,
+            batch_size_budget: None,
+            format_codecs: HashMap::new(),
+            watermark: None,
+            scale_mode: ScaleMode::Fit,
+            comic_logs: ComicLogs::Off,
+            progress_granularity: ProgressGranularity::Pages(1),
+            cover_only: false,
+            max_long_edge: None,
+            format_dirs: HashMap::new(),
+            reader_profile: ReaderProfile::Compatible,
+            page_cache: None,
+            shared_crop: false,
+            crop_margins: None,
+            overwrite: OverwritePolicy::Overwrite,
+            merge_spreads: false,
+            seed: Some(0),
+            page_manifest: false,
+            split_ranges: Vec::new(),
+            color_profiles: false,
+            batch_plan: false,
+            panorama_splits: Vec::new(),
+            exclude_pages: None,
+            nested_archives: 0,
+            rotate: RotateMode::None,
+            duplicate_check: DuplicateCheck::Off,
+            jpeg_progressive: false,
         }
     }
 }
@@ -210,7 +964,38 @@ impl ComicConfig {
 
         fs::read_to_string(&config_path)
             .ok()
-            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .and_then(|contents| Self::from_json(&contents).ok())
+    }
+
+    /// Fields missing from `json` come from its profile, or the defaults without one
+    fn from_json(json: &str) -> serde_json::Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        let profile = match value.get("profile") {
+            Some(profile) => serde_json::from_value(profile.clone())?,
+            None => None::<QualityProfile>,
+        };
+        let Some(profile) = profile else {
+            return serde_json::from_value(value);
+        };
+
+        let mut config = serde_json::to_value(profile.config())?;
+        if let (Some(config), serde_json::Value::Object(fields)) = (config.as_object_mut(), value) {
+            config.extend(fields);
+        }
+        serde_json::from_value(config)
+    }
+
+    /// With a profile only the fields that differ from it are kept,
+    /// so they stay overrides instead of pinning every profile setting
+    fn to_json(&self) -> serde_json::Result<String> {
+        let mut value = serde_json::to_value(self)?;
+        if let Some(profile) = self.profile {
+            let base = serde_json::to_value(profile.config())?;
+            if let (Some(fields), Some(base)) = (value.as_object_mut(), base.as_object()) {
+                fields.retain(|key, field| key == "profile" || base.get(key) != Some(field));
+            }
+        }
+        serde_json::to_string_pretty(&value)
     }
 
     pub fn save(&self) -> Option<()> {
@@ -221,7 +1006,7 @@ impl ComicConfig {
             fs::create_dir_all(parent).ok()?;
         }
 
-        serde_json::to_string_pretty(self)
+        self.to_json()
             .ok()
             .and_then(|json| fs::write(&config_path, json).ok())
     }
@@ -231,15 +1016,71 @@ impl ComicConfig {
         Some(home.join(".config").join("comically").join("config.json"))
     }
 
+    /// Size pages are fitted to, the device's scaled down to `max_long_edge`
     pub fn device_dimensions(&self) -> (u32, u32) {
-        self.device.dimensions
+        let (width, height) = self.device.dimensions;
+        match self.max_long_edge {
+            Some(cap) if width.max(height) > cap => {
+                let scale = cap as f64 / width.max(height) as f64;
+                let scaled = |side: u32| ((side as f64 * scale).round() as u32).max(1);
+                (scaled(width), scaled(height))
+            }
+            _ => (width, height),
+        }
+    }
+
+    /// All formats to package, primary format first and without duplicates
+    pub fn output_formats(&self) -> Vec<OutputFormat> {
+        let mut formats = vec![self.output_format];
+        for format in &self.additional_formats {
+            if !formats.contains(format) {
+                formats.push(*format);
+            }
+        }
+        formats
+    }
+
+    /// Page codec packaged into `format`
+    pub fn codec(&self, format: OutputFormat) -> ImageFormat {
+        self.format_codecs
+            .get(&format)
+            .copied()
+            .unwrap_or(self.image_format)
+    }
+
+    /// Codecs of the formats to package that aren't image_format, without duplicates
+    pub fn extra_codecs(&self) -> Vec<ImageFormat> {
+        let mut codecs = Vec::new();
+        for format in self.output_formats() {
+            let codec = self.codec(format);
+            if codec != self.image_format && !codecs.contains(&codec) {
+                codecs.push(codec);
+            }
+        }
+        codecs
+    }
+
+    pub fn has_output_format(&self, format: OutputFormat) -> bool {
+        self.output_format == format || self.additional_formats.contains(&format)
+    }
+
+    /// Written into outputs when `producer_tag` is set
+    pub fn producer(&self) -> Option<String> {
+        self.producer_tag
+            .then(|| format!("produced by comically v{}", env!("CARGO_PKG_VERSION")))
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct ProcessedImage {
     pub path: PathBuf,
     pub dimensions: (u32, u32),
+    // folder within the archive the page came from, used for chapter navigation
+    pub chapter: Option<String>,
+    // archive entry the page was made from
+    pub source: PathBuf,
+    // what turned the entry into this page, like "rotated" or "right half"
+    pub transforms: Vec<String>,
 }
 
 pub struct Comic {
@@ -252,6 +1093,29 @@ pub struct Comic {
     pub output_dir: PathBuf,
     pub input: PathBuf,
     pub config: ComicConfig,
+    // non-fatal issues, reported with the final status
+    pub warnings: Vec<String>,
+    // exact output path when a file like `out/Book.epub` was given instead of a directory
+    pub output_file: Option<PathBuf>,
+    // failed for lack of disk space, which the rest of the batch would run into too
+    pub out_of_space: bool,
+    // the comic's own log with `comic_logs`
+    log_file: Option<ComicLog>,
+}
+
+/// A comic's log file, lines are timed from when the comic was set up
+struct ComicLog {
+    file: fs::File,
+    start: Instant,
+}
+
+impl ComicLog {
+    fn write(&self, level: log::Level, message: impl std::fmt::Display) {
+        use std::io::Write;
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let _ = writeln!(&self.file, "[{elapsed:>9.3}s] {level:<5} {message}");
+    }
 }
 
 impl std::fmt::Debug for Comic {
@@ -266,6 +1130,10 @@ impl std::fmt::Debug for Comic {
             .field("output_dir", &self.output_dir)
             .field("input", &self.input)
             .field("config", &self.config)
+            .field("warnings", &self.warnings)
+            .field("output_file", &self.output_file)
+            .field("out_of_space", &self.out_of_space)
+            .field("log_file", &self.log_file.is_some())
             .finish()
     }
 }
@@ -276,18 +1144,48 @@ impl Drop for Comic {
     }
 }
 
+/// Names this process's scratch directories, so runs sharing a temp dir can tell theirs apart
+static RUN_ID: LazyLock<String> =
+    LazyLock::new(|| format!("{}-{:08x}", std::process::id(), rand::random::<u32>()));
+
+/// A scratch directory for comic `id` in `parent`, `comically-<run>-<id>-<random>`.
+/// It's created exclusively, so another run can never be handed the same one
+fn scratch_dir(parent: &Path, run: &str, id: usize) -> std::io::Result<tempfile::TempDir> {
+    tempfile::Builder::new()
+        .prefix(&format!("comically-{run}-{id}-"))
+        .tempdir_in(parent)
+}
+
 impl Comic {
     pub fn new(
         id: usize,
         file: PathBuf,
         output_dir: PathBuf,
         title: String,
-        config: ComicConfig,
+        mut config: ComicConfig,
         tx: mpsc::Sender<Event>,
     ) -> anyhow::Result<Self> {
-        let temp_dir = tempfile::tempdir()?;
+        // an output file decides the format on its own
+        let (output_dir, output_file) = match OutputFormat::infer_from_path(&output_dir)? {
+            Some(format) => {
+                config.output_format = format;
+                config.additional_formats.clear();
+                let dir = output_dir
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default();
+                fs::create_dir_all(&dir)?;
+                (dir, Some(output_dir))
+            }
+            None => {
+                fs::create_dir_all(&output_dir)?;
+                (output_dir, None)
+            }
+        };
 
-        let comic = Comic {
+        let temp_dir = scratch_dir(&std::env::temp_dir(), &RUN_ID, id)?;
+
+        let mut comic = Comic {
             id,
             tx,
             processed_dir: temp_dir.path().join("Processed"),
@@ -297,9 +1195,35 @@ impl Comic {
             output_dir,
             input: file,
             config,
+            warnings: Vec::new(),
+            output_file,
+            out_of_space: false,
+            log_file: None,
         };
 
         std::fs::create_dir_all(comic.processed_dir())?;
+        for format in comic.config.output_formats() {
+            if let Some(dir) = comic.output_path(format).parent() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+
+        if comic.config.comic_logs != ComicLogs::Off {
+            let path = comic.sidecar_path("log");
+            match fs::File::create(&path) {
+                Ok(file) => {
+                    comic.log_file = Some(ComicLog {
+                        file,
+                        start: Instant::now(),
+                    });
+                    comic.log(
+                        log::Level::Info,
+                        format!("input: {}", comic.input.display()),
+                    );
+                }
+                Err(e) => log::warn!("Failed to create {}: {}", path.display(), e),
+            }
+        }
 
         Ok(comic)
     }
@@ -312,7 +1236,8 @@ impl Comic {
         match result {
             Ok(t) => Some(t),
             Err(e) => {
-                log::error!("Error in comic: {} {e}", self.title);
+                self.log(log::Level::Error, format!("Error in comic: {e:#}"));
+                self.out_of_space |= is_disk_full(&e);
                 self.failed(e);
                 None
             }
@@ -331,55 +1256,237 @@ impl Comic {
         self.epub_dir().join("book.epub")
     }
 
-    pub fn output_path(&self) -> PathBuf {
-        let filename = self.input.file_stem().unwrap().to_string_lossy();
+    pub fn output_path(&self, format: OutputFormat) -> PathBuf {
+        if let Some(file) = &self.output_file {
+            if format == self.config.output_format {
+                return file.clone();
+            }
+        }
 
-        let extension = match self.config.output_format {
-            OutputFormat::Mobi => "mobi",
-            OutputFormat::Epub => "epub",
-            OutputFormat::Cbz => "cbz",
+        // the title is the input's file name unless one was given, which may not suit a file
+        let filename = self
+            .title
+            .replace(['/', '\\', ':', '*', '?', '"', '<', '>', '|'], "_");
+        let extension = format.extension();
+        let suffix = if self.config.preview_pages.is_some() {
+            ".preview"
+        } else {
+            ""
         };
 
         // don't use .with_extension() bc it replaces everything after the first dot
-        self.output_dir.join(format!("{}.{}", filename, extension))
+        let dir = match self.config.format_dirs.get(&format) {
+            Some(dir) => self.output_dir.join(dir),
+            None => self.output_dir.clone(),
+        };
+        if extension.is_empty() {
+            return dir.join(format!("{}{}", filename, suffix));
+        }
+        dir.join(format!("{}{}.{}", filename, suffix, extension))
+    }
+
+    pub fn started(&self) {
+        self.notify(ProgressEvent::ComicStarted { id: self.id });
     }
 
-    pub fn update_status(&self, stage: ComicStage, progress: f64) -> Instant {
+    /// Every file written for the comic, one per output format
+    pub fn outputs(&self) -> Vec<PathBuf> {
+        self.config
+            .output_formats()
+            .into_iter()
+            .map(|format| self.output_path(format))
+            .collect()
+    }
+
+    pub fn update_status(&self, stage: ComicStage, progress: f64, step: &'static str) -> Instant {
         let start = Instant::now();
+        self.trace(format_args!(
+            "{:?} ({}) started at {:.0}%",
+            stage, step, progress
+        ));
         self.notify(ProgressEvent::ComicUpdate {
             id: self.id,
             status: ComicStatus::Progress {
                 stage,
                 progress,
                 start,
+                step,
             },
         });
         start
     }
 
     pub fn stage_completed(&self, stage: ComicStage, duration: Duration) {
+        self.trace(format_args!("{:?} completed in {:?}", stage, duration));
         self.notify(ProgressEvent::ComicUpdate {
             id: self.id,
             status: ComicStatus::StageCompleted { stage, duration },
         });
     }
 
-    pub fn success(&self) {
+    /// Record a non-fatal issue, the comic finishes with warnings instead of plain success
+    pub fn warn(&mut self, message: String) {
+        self.log(log::Level::Warn, &message);
+        self.notify(ProgressEvent::Warning {
+            id: self.id,
+            message: message.clone(),
+        });
+        self.warnings.push(message);
+    }
+
+    pub fn success(&mut self) {
+        let stats = self.stats();
+        self.log(
+            log::Level::Info,
+            format!(
+                "{} bytes -> {} bytes ({:.2}), {} pages",
+                stats.input_bytes,
+                stats.output_bytes,
+                stats.compression_ratio(),
+                if stats.lossless { "lossless" } else { "lossy" }
+            ),
+        );
+        if let Some(quality) = stats.target_quality {
+            self.log(
+                log::Level::Info,
+                format!("target size reached with quality {}", quality),
+            );
+        }
+        if stats.output_larger_than_input() {
+            let message = format!(
+                "output ({} bytes) is larger than input ({} bytes), check upscaling and image format",
+                stats.largest_output_bytes, stats.input_bytes
+            );
+            self.warn(message);
+        }
+        let status = if self.warnings.is_empty() {
+            ComicStatus::Success { stats }
+        } else {
+            ComicStatus::CompletedWithWarnings {
+                stats,
+                warnings: self.warnings.clone(),
+            }
+        };
         self.notify(ProgressEvent::ComicUpdate {
             id: self.id,
-            status: ComicStatus::Success,
+            status,
         });
     }
 
+    pub fn stats(&self) -> ComicStats {
+        // a folder output counts the pages in it
+        let file_size = |path: &Path| match fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok()?.metadata().ok())
+                .map(|metadata| metadata.len())
+                .sum(),
+            Err(_) => fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        };
+
+        let output_sizes: Vec<u64> = self.outputs().iter().map(|path| file_size(path)).collect();
+        let pages = |extensions: &[&str]| {
+            self.processed_files
+                .iter()
+                .filter(|page| {
+                    page.path.extension().is_some_and(|ext| {
+                        extensions
+                            .iter()
+                            .any(|wanted| ext.eq_ignore_ascii_case(wanted))
+                    })
+                })
+                .count()
+        };
+
+        ComicStats {
+            input_bytes: file_size(&self.input),
+            output_bytes: output_sizes.iter().sum(),
+            largest_output_bytes: output_sizes.into_iter().max().unwrap_or(0),
+            target_quality: match (self.config.target_size, self.config.image_format) {
+                (Some(_), ImageFormat::Jpeg { quality }) => Some(quality),
+                _ => None,
+            },
+            lossless: self
+                .config
+                .output_formats()
+                .into_iter()
+                .all(|format| self.config.codec(format).is_lossless()),
+            jpeg_pages: pages(&["jpg", "jpeg"]),
+            png_pages: pages(&["png"]),
+            webp_pages: pages(&["webp"]),
+        }
+    }
+
     pub fn failed(&self, error: anyhow::Error) {
+        if self.config.error_sidecar {
+            if let Err(e) = self.write_error_sidecar(&error) {
+                self.log(
+                    log::Level::Warn,
+                    format!("failed to write error sidecar: {}", e),
+                );
+            }
+        }
         self.notify(ProgressEvent::ComicUpdate {
             id: self.id,
-            status: ComicStatus::Failed { error },
+            status: ComicStatus::Failed {
+                error: error.into(),
+            },
         });
     }
 
+    /// The full error chain, followed by the warnings so far, which name any skipped pages
+    fn write_error_sidecar(&self, error: &anyhow::Error) -> anyhow::Result<()> {
+        use std::fmt::Write;
+
+        let mut report = format!("{}\ninput: {}\n\n", self.title, self.input.display());
+        for (i, cause) in error.chain().enumerate() {
+            let label = if i == 0 { "error" } else { "caused by" };
+            writeln!(report, "{}: {}", label, cause)?;
+        }
+        if !self.warnings.is_empty() {
+            report.push_str("\nwarnings:\n");
+            for warning in &self.warnings {
+                writeln!(report, "- {}", warning)?;
+            }
+        }
+
+        fs::write(self.sidecar_path("error.txt"), report)?;
+        Ok(())
+    }
+
+    /// Random numbers for this comic, the same every run with a `seed`.
+    /// Each input gets its own sequence from its title and contents, so books in one
+    /// batch don't share identifiers even when their titles match
+    pub fn rng(&self) -> rand::rngs::StdRng {
+        use crate::page_cache::{fnv1a, hash_file, FNV_OFFSET};
+        use rand::SeedableRng;
+
+        let Some(seed) = self.config.seed else {
+            return rand::rngs::StdRng::from_os_rng();
+        };
+        // an unreadable input fails before anything random is drawn
+        let contents = hash_file(&self.input).unwrap_or_default();
+        let hash = fnv1a(FNV_OFFSET, &seed.to_le_bytes());
+        let hash = fnv1a(hash, self.title.as_bytes());
+        let hash = fnv1a(hash, &contents.to_le_bytes());
+        rand::rngs::StdRng::seed_from_u64(hash)
+    }
+
+    /// A file next to the output named after it, `<name>.<suffix>`
+    pub fn sidecar_path(&self, suffix: &str) -> PathBuf {
+        let output = self.output_path(self.config.output_format);
+        // a folder's whole name is the title, which can have dots of its own
+        let name = if self.config.output_format == OutputFormat::Folder {
+            output.file_name()
+        } else {
+            output.file_stem()
+        };
+        let name = name.unwrap_or_default().to_string_lossy();
+        output.with_file_name(format!("{}.{}", name, suffix))
+    }
+
     pub fn image_processing_start(&self, total_images: usize) -> Instant {
         let start = Instant::now();
+        self.trace(format_args!("processing {} pages", total_images));
         self.notify(ProgressEvent::ComicUpdate {
             id: self.id,
             status: ComicStatus::ImageProcessingStart {
@@ -391,48 +1498,316 @@ impl Comic {
     }
 
     pub fn image_processing_complete(&self, duration: Duration) {
+        self.trace(format_args!("pages processed in {:?}", duration));
         self.notify(ProgressEvent::ComicUpdate {
             id: self.id,
             status: ComicStatus::ImageProcessingComplete { duration },
         });
     }
 
+    /// Logged with the comic's title, and to its own log with `comic_logs`
+    pub fn log(&self, level: log::Level, message: impl std::fmt::Display) {
+        if self.config.comic_logs != ComicLogs::Only {
+            log::log!(level, "{}: {}", self.title, message);
+        }
+        if let Some(log) = &self.log_file {
+            log.write(level, message);
+        }
+    }
+
+    /// Progress through the stages, only worth a line in the comic's own log
+    fn trace(&self, message: std::fmt::Arguments) {
+        if let Some(log) = &self.log_file {
+            log.write(log::Level::Debug, message);
+        }
+    }
+
     fn notify(&self, event: ProgressEvent) {
-        let _ = self.tx.send(Event::Progress(event));
-    }
-}
-
-#[test]
-fn output_path_with_dots() {
-    use std::sync::mpsc;
-    use tempfile::TempDir;
-
-    let temp_dir = TempDir::new().unwrap();
-    let output_dir = temp_dir.path().join("output");
-    let (tx, _rx) = mpsc::channel();
-
-    let mut config = ComicConfig::default();
-    config.output_format = OutputFormat::Cbz;
-
-    let comic = Comic::new(
-        0,
-        PathBuf::from("Dr. STONE v01 (2018) (Digital) (1r0n).cbz"),
-        output_dir.clone(),
-        "Dr. STONE v01 (2018) (Digital) (1r0n)".to_string(),
-        config,
-        tx,
-    )
-    .unwrap();
-
-    let output_path = comic.output_path();
-    assert_eq!(
-        output_path,
-        output_dir.join("Dr. STONE v01 (2018) (Digital) (1r0n).cbz")
-    );
-
-    assert_eq!(
-        output_path.file_name().unwrap().to_str().unwrap(),
-        "Dr. STONE v01 (2018) (Digital) (1r0n).cbz",
-        "filename is preserved"
-    );
+        let _ = self.tx.send(event.into_event());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_path_with_dots() {
+        use std::sync::mpsc;
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let (tx, _rx) = mpsc::channel();
+
+        let mut config = ComicConfig::default();
+        config.output_format = OutputFormat::Cbz;
+
+        let comic = Comic::new(
+            0,
+            PathBuf::from("Dr. STONE v01 (2018) (Digital) (1r0n).cbz"),
+            output_dir.clone(),
+            "Dr. STONE v01 (2018) (Digital) (1r0n)".to_string(),
+            config,
+            tx,
+        )
+        .unwrap();
+
+        let output_path = comic.output_path(OutputFormat::Cbz);
+        assert_eq!(
+            output_path,
+            output_dir.join("Dr. STONE v01 (2018) (Digital) (1r0n).cbz")
+        );
+
+        assert_eq!(
+            output_path.file_name().unwrap().to_str().unwrap(),
+            "Dr. STONE v01 (2018) (Digital) (1r0n).cbz",
+            "filename is preserved"
+        );
+    }
+
+    #[test]
+    fn output_format_from_output_file() {
+        use std::sync::mpsc;
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let output_file = temp_dir.path().join("out").join("Book.EPUB");
+        let (tx, _rx) = mpsc::channel();
+
+        let comic = Comic::new(
+            0,
+            PathBuf::from("Book v01.cbz"),
+            output_file.clone(),
+            "Book v01".to_string(),
+            ComicConfig::default(),
+            tx,
+        )
+        .unwrap();
+
+        assert_eq!(comic.config.output_format, OutputFormat::Epub);
+        assert_eq!(comic.output_path(OutputFormat::Epub), output_file);
+
+        let dotted_dir = temp_dir.path().join("Dr. STONE");
+        assert_eq!(OutputFormat::infer_from_path(&dotted_dir).unwrap(), None);
+        assert!(OutputFormat::infer_from_path(Path::new("out/Book.azw3")).is_err());
+    }
+
+    #[test]
+    fn error_sidecar_has_the_error_chain() {
+        use std::sync::mpsc;
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let (tx, _rx) = mpsc::channel();
+        let config = ComicConfig {
+            output_format: OutputFormat::Cbz,
+            error_sidecar: true,
+            ..ComicConfig::default()
+        };
+
+        let mut comic = Comic::new(
+            0,
+            PathBuf::from("Book v01.cbz"),
+            temp_dir.path().to_path_buf(),
+            "Book v01".to_string(),
+            config,
+            tx,
+        )
+        .unwrap();
+        comic.warn("skipped page 003.jpg".to_string());
+        let result: Option<()> = comic.with_try(|_| {
+            Err(anyhow::anyhow!("disk full").context("Failed to save image: 004.jpg"))
+        });
+        assert!(result.is_none());
+
+        let report = fs::read_to_string(temp_dir.path().join("Book v01.error.txt")).unwrap();
+        assert!(report.contains("error: Failed to save image: 004.jpg\ncaused by: disk full"));
+        assert!(report.contains("- skipped page 003.jpg"));
+    }
+
+    #[test]
+    fn profile_fields_can_be_overridden() {
+        let config = ComicConfig::from_json(r#"{"profile": "Archival"}"#).unwrap();
+        assert_eq!(
+            config.image_format,
+            ImageFormat::Png {
+                compression: PngCompression::Best
+            }
+        );
+
+        let config = ComicConfig {
+            image_format: ImageFormat::Jpeg { quality: 70 },
+            ..QualityProfile::Small.config()
+        };
+        let json = config.to_json().unwrap();
+        assert!(!json.contains("jpeg_subsampling"));
+
+        let loaded = ComicConfig::from_json(&json).unwrap();
+        assert_eq!(loaded.image_format, ImageFormat::Jpeg { quality: 70 });
+        assert_eq!(loaded.jpeg_subsampling, JpegSubsampling::Chroma420);
+        assert_eq!(loaded.dimension_multiple, Some(16));
+    }
+
+    #[test]
+    fn disk_full_is_found_under_context() {
+        use std::io::{Error, ErrorKind};
+
+        let full = anyhow::Error::new(zip::result::ZipError::Io(Error::from(
+            ErrorKind::StorageFull,
+        )))
+        .context("Failed to write CBZ");
+        let error = ComicError::from(full);
+        assert!(matches!(error, ComicError::DiskFull(_)));
+        assert_eq!(error.to_string(), "Disk full: Failed to write CBZ");
+
+        let denied = anyhow::Error::new(Error::from(ErrorKind::PermissionDenied)).context("Failed");
+        assert!(matches!(ComicError::from(denied), ComicError::Other(_)));
+    }
+
+    #[test]
+    fn max_long_edge_only_scales_down() {
+        let mut config = ComicConfig {
+            device: DevicePreset {
+                name: "custom".into(),
+                dimensions: (1600, 4000),
+            },
+            max_long_edge: Some(2000),
+            ..ComicConfig::default()
+        };
+        assert_eq!(config.device_dimensions(), (800, 2000));
+
+        config.max_long_edge = Some(5000);
+        assert_eq!(config.device_dimensions(), (1600, 4000));
+    }
+
+    #[test]
+    fn format_dirs_route_outputs() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let library = temp_dir.path().join("library");
+        let (tx, _rx) = mpsc::channel();
+
+        let config = ComicConfig {
+            output_format: OutputFormat::Epub,
+            additional_formats: vec![OutputFormat::Cbz, OutputFormat::Mobi],
+            format_dirs: HashMap::from([
+                (OutputFormat::Epub, PathBuf::from("epub")),
+                (OutputFormat::Cbz, library.clone()),
+            ]),
+            ..ComicConfig::default()
+        };
+        let comic = Comic::new(
+            0,
+            PathBuf::from("Book.cbz"),
+            output_dir.clone(),
+            "Book".to_string(),
+            config,
+            tx,
+        )
+        .unwrap();
+
+        assert_eq!(
+            comic.outputs(),
+            [
+                output_dir.join("epub").join("Book.epub"),
+                library.join("Book.cbz"),
+                output_dir.join("Book.mobi"),
+            ]
+        );
+        assert!(output_dir.join("epub").is_dir() && library.is_dir());
+    }
+
+    #[test]
+    fn seeded_rng_repeats_per_input() {
+        use rand::Rng;
+        use std::sync::mpsc;
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let (tx, _rx) = mpsc::channel();
+        let comic_from = |dir: &str, title: &str, seed| {
+            let config = ComicConfig {
+                seed,
+                ..ComicConfig::default()
+            };
+            let input = temp_dir.path().join(dir).join(format!("{title}.cbz"));
+            fs::create_dir_all(input.parent().unwrap()).unwrap();
+            fs::write(&input, format!("{dir}/{title}")).unwrap();
+            Comic::new(
+                0,
+                input,
+                temp_dir.path().to_path_buf(),
+                title.to_string(),
+                config,
+                tx.clone(),
+            )
+            .unwrap()
+        };
+        let comic = |title: &str, seed| comic_from("series", title, seed);
+        let draw = |comic: Comic| comic.rng().random::<u64>();
+
+        assert_eq!(draw(comic("One", Some(7))), draw(comic("One", Some(7))));
+        // same title, another series
+        assert_ne!(
+            draw(comic("One", Some(7))),
+            draw(comic_from("other", "One", Some(7)))
+        );
+        assert_ne!(draw(comic("One", Some(7))), draw(comic("Two", Some(7))));
+        assert_ne!(draw(comic("One", Some(7))), draw(comic("One", Some(8))));
+        assert_ne!(draw(comic("One", None)), draw(comic("One", None)));
+    }
+
+    #[test]
+    fn scratch_dirs_are_unique_across_runs() {
+        let shared = tempfile::tempdir().unwrap();
+        let first = scratch_dir(shared.path(), "100-0000abcd", 0).unwrap();
+        let second = scratch_dir(shared.path(), "200-0000ef01", 0).unwrap();
+        let again = scratch_dir(shared.path(), "100-0000abcd", 0).unwrap();
+
+        assert_ne!(first.path(), second.path());
+        assert_ne!(first.path(), again.path());
+        let name = first.path().file_name().unwrap().to_string_lossy();
+        assert!(name.starts_with("comically-100-0000abcd-0-"));
+    }
+
+    #[test]
+    fn stats_count_pages_by_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _rx) = mpsc::channel();
+        let mut comic = Comic::new(
+            0,
+            PathBuf::from("Book.cbz"),
+            dir.path().to_path_buf(),
+            "Book".to_string(),
+            ComicConfig::default(),
+            tx,
+        )
+        .unwrap();
+        comic.processed_files = ["001.jpg", "002.JPEG", "003.png", "004.jpg"]
+            .iter()
+            .map(|name| ProcessedImage {
+                path: PathBuf::from(name),
+                ..ProcessedImage::default()
+            })
+            .collect();
+
+        let stats = comic.stats();
+        assert_eq!(
+            (stats.jpeg_pages, stats.png_pages, stats.webp_pages),
+            (3, 1, 0)
+        );
+        assert_eq!(stats.page_formats(), "3 jpg, 1 png");
+        assert!(stats.mixed_formats());
+
+        // one lossy output is enough for the comic not to be a lossless copy
+        let png = ImageFormat::Png {
+            compression: PngCompression::Default,
+        };
+        comic.config.image_format = png;
+        comic.config.additional_formats = vec![OutputFormat::Epub];
+        assert!(comic.stats().lossless);
+        comic.config.format_codecs =
+            HashMap::from([(OutputFormat::Epub, ImageFormat::WebP { quality: 80 })]);
+        assert!(!comic.stats().lossless);
+    }
 }
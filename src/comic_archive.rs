@@ -1,7 +1,15 @@
+use crate::comic::{ArchiveFormat, CmykMode, ComicError, DuplicateCheck, FormatInfo};
+use crate::image_processor;
+use crate::page_cache::{fnv1a, hash_file, FNV_OFFSET};
 use anyhow::Context;
+use imageproc::image::{imageops, DynamicImage, ImageFormat};
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
 use unrar::Archive;
 use zip::ZipArchive;
 
@@ -19,24 +27,426 @@ impl ArchiveFile {
     pub fn parent(&self) -> &Path {
         self.file_name.parent().unwrap()
     }
+
+    /// Reads only the image header, unreadable images are left for the decoder to report
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        imageproc::image::ImageReader::new(std::io::Cursor::new(&self.data))
+            .with_guessed_format()
+            .ok()?
+            .into_dimensions()
+            .ok()
+    }
+}
+
+/// Orders names the way people read them, digit runs compare by value so
+/// `Chapter 2/` comes before `Chapter 10/` and page 9 before page 10
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a_chars, mut b_chars) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            // equal by value, like 01 and 1, still need a stable order
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a_chars);
+                let y = take_number(&mut b_chars);
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                let order = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+                if order != Ordering::Equal {
+                    return order;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut number = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        number.push(digit);
+    }
+    number
+}
+
+/// Sort key captured from each entry name by `sort_key_regex`, for naming schemes
+/// like chapter_01_p03 that plain name order gets wrong
+pub struct SortKey {
+    regex: Regex,
+}
+
+impl SortKey {
+    pub fn new(pattern: &str) -> anyhow::Result<Self> {
+        let regex =
+            Regex::new(pattern).with_context(|| format!("Invalid sort_key_regex: {}", pattern))?;
+        Ok(Self { regex })
+    }
+
+    /// Goes in front of the page name, so pages sort by the first capture group,
+    /// or the whole match without one. Numbers compare by value, and names that
+    /// don't match go after the ones that do, in their usual order
+    pub fn prefix(&self, file_name: &Path) -> String {
+        let name = file_name.to_string_lossy();
+        let Some(captures) = self.regex.captures(&name) else {
+            return "1-".to_string();
+        };
+        let key = captures
+            .get(1)
+            .or_else(|| captures.get(0))
+            .unwrap()
+            .as_str();
+
+        let mut prefix = String::from("0");
+        let mut digits = String::new();
+        for c in key.chars().chain(std::iter::once(' ')) {
+            if c.is_ascii_digit() {
+                digits.push(c);
+                continue;
+            }
+            if !digits.is_empty() {
+                prefix.push_str(&format!("{:0>10}", digits));
+                digits.clear();
+            }
+            // '-' sorts before anything kept, so shorter keys come first
+            if c.is_alphanumeric() {
+                prefix.push(c);
+            }
+        }
+        prefix.push('-');
+        prefix
+    }
+}
+
+/// Drop images under `min_dimension` pixels wide or tall, like embedded thumbnails and icons
+pub fn without_thumbnails(
+    pages: impl Iterator<Item = anyhow::Result<ArchiveFile>>,
+    min_dimension: u32,
+) -> impl Iterator<Item = anyhow::Result<ArchiveFile>> {
+    pages.filter(move |page| {
+        let Ok(page) = page else {
+            return true;
+        };
+        match page.dimensions() {
+            Some((width, height)) if width < min_dimension || height < min_dimension => {
+                log::debug!(
+                    "Skipping {}, {}x{} is below the minimum page dimension",
+                    page.file_name.display(),
+                    width,
+                    height
+                );
+                false
+            }
+            _ => true,
+        }
+    })
+}
+
+/// Compiles `exclude_pages` for [`without_excluded`]
+pub fn exclude_pattern(pattern: &str) -> anyhow::Result<Regex> {
+    Regex::new(pattern).with_context(|| format!("Invalid exclude_pages: {}", pattern))
+}
+
+/// Drop entries whose path in the archive matches `exclude`, like ads and logos
+/// shipped next to the pages
+pub fn without_excluded(
+    pages: impl Iterator<Item = anyhow::Result<ArchiveFile>>,
+    exclude: Option<Regex>,
+) -> impl Iterator<Item = anyhow::Result<ArchiveFile>> {
+    pages.filter(move |page| {
+        let (Ok(page), Some(exclude)) = (page, &exclude) else {
+            return true;
+        };
+        let name = page.file_name.to_string_lossy().replace('\\', "/");
+        if exclude.is_match(&name) {
+            log::debug!(
+                "Skipping {}, it matches exclude_pages",
+                page.file_name.display()
+            );
+            return false;
+        }
+        true
+    })
+}
+
+/// Side of the spread a page named as one of its halves goes on
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Left,
+    Right,
+}
+
+/// The spread a page is half of and its side, from names like `012a`/`012b` or `012_L`/`012_R`.
+/// `a` is read first, so it's the right half in right to left books
+fn spread_half(file_name: &Path, right_to_left: bool) -> Option<(PathBuf, Side)> {
+    static HALF: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?i)^(.*\d)[-_ ]?(a|b|l|r|left|right)$").unwrap());
+
+    let stem = file_name.file_stem()?.to_string_lossy();
+    let captures = HALF.captures(&stem)?;
+    let side = match captures[2].to_ascii_lowercase().as_str() {
+        "l" | "left" => Side::Left,
+        "r" | "right" => Side::Right,
+        "a" if right_to_left => Side::Right,
+        "b" if right_to_left => Side::Left,
+        "a" => Side::Left,
+        _ => Side::Right,
+    };
+    Some((file_name.with_file_name(&captures[1]), side))
+}
+
+/// Stitches the halves of spreads stored as two files back into one page, for big screens.
+/// Halves are found by name and wait for their other half whatever the entry order, a half
+/// without one, or one that doesn't line up with it, is passed on as is
+pub fn merge_spreads(
+    mut pages: impl Iterator<Item = anyhow::Result<ArchiveFile>>,
+    right_to_left: bool,
+    cmyk_mode: CmykMode,
+) -> impl Iterator<Item = anyhow::Result<ArchiveFile>> {
+    let mut halves: HashMap<PathBuf, (Side, ArchiveFile)> = HashMap::new();
+    let mut ready = std::collections::VecDeque::new();
+    let mut done = false;
+    std::iter::from_fn(move || loop {
+        if let Some(page) = ready.pop_front() {
+            return Some(Ok(page));
+        }
+        if done {
+            return None;
+        }
+        let page = match pages.next() {
+            Some(Ok(page)) => page,
+            Some(Err(e)) => return Some(Err(e)),
+            None => {
+                done = true;
+                ready.extend(halves.drain().map(|(_, (_, page))| page));
+                continue;
+            }
+        };
+        let Some((spread, side)) = spread_half(&page.file_name, right_to_left) else {
+            return Some(Ok(page));
+        };
+        match halves.remove(&spread) {
+            Some((other_side, other)) if other_side != side => {
+                let (left, right) = match side {
+                    Side::Left => (page, other),
+                    Side::Right => (other, page),
+                };
+                match stitch_spread(&left, &right, cmyk_mode) {
+                    Ok(merged) => ready.push_back(merged),
+                    Err(e) => {
+                        log::debug!("Not merging {}: {}", left.file_name.display(), e);
+                        ready.extend([left, right]);
+                    }
+                }
+            }
+            // the same side twice, only the later one can still find its match
+            Some((other_side, other)) => {
+                ready.push_back(other);
+                halves.insert(spread, (other_side, page));
+            }
+            None => {
+                halves.insert(spread, (side, page));
+            }
+        }
+    })
+}
+
+/// One PNG page with `left` and `right` side by side, named after the left one
+fn stitch_spread(
+    left: &ArchiveFile,
+    right: &ArchiveFile,
+    cmyk_mode: CmykMode,
+) -> anyhow::Result<ArchiveFile> {
+    let left_page = image_processor::load_image(&left.data, cmyk_mode)?;
+    let right_page = image_processor::load_image(&right.data, cmyk_mode)?;
+    // halves of one scan are the same height, anything else is two unrelated pages
+    if left_page.height().abs_diff(right_page.height()) > left_page.height() / 50 {
+        anyhow::bail!(
+            "the halves are {} and {} pixels tall",
+            left_page.height(),
+            right_page.height()
+        );
+    }
+
+    let width = left_page.width() + right_page.width();
+    let height = left_page.height().max(right_page.height());
+    let mut spread = if left_page.color().has_color() || right_page.color().has_color() {
+        DynamicImage::new_rgb8(width, height)
+    } else {
+        DynamicImage::new_luma8(width, height)
+    };
+    imageops::replace(&mut spread, &left_page, 0, 0);
+    imageops::replace(&mut spread, &right_page, left_page.width() as i64, 0);
+
+    let mut data = Vec::new();
+    spread.write_to(&mut std::io::Cursor::new(&mut data), ImageFormat::Png)?;
+    Ok(ArchiveFile {
+        file_name: left.file_name.with_extension("png"),
+        data,
+    })
+}
+
+/// Explicit reading order, overriding the order of the archive entries.
+/// Names match an entry's full path in the archive, or just its file name
+pub struct PageOrder {
+    ranks: HashMap<String, usize>,
+}
+
+impl PageOrder {
+    pub fn new(names: &[String]) -> Self {
+        let mut ranks = HashMap::new();
+        for (rank, name) in names.iter().enumerate() {
+            ranks.entry(name.clone()).or_insert(rank);
+        }
+        Self { ranks }
+    }
+
+    /// Position in the list, unlisted entries all go after the listed ones
+    pub fn rank(&self, file_name: &Path) -> usize {
+        let name = |path: &Path| self.ranks.get(path.to_string_lossy().as_ref()).copied();
+        name(file_name)
+            .or_else(|| file_name.file_name().and_then(|f| name(Path::new(f))))
+            .unwrap_or(self.ranks.len())
+    }
+
+    /// Listed names that didn't match any of the entries
+    pub fn missing(&self, entries: &[PathBuf]) -> Vec<String> {
+        let mut missing: Vec<_> = self
+            .ranks
+            .keys()
+            .filter(|name| {
+                !entries.iter().any(|entry| {
+                    entry.as_os_str() == name.as_str()
+                        || entry.file_name().is_some_and(|f| f == name.as_str())
+                })
+            })
+            .cloned()
+            .collect();
+        missing.sort_by_key(|name| self.ranks[name]);
+        missing
+    }
 }
 
 pub enum ArchiveIter {
     Zip(ZipReader),
+    ZipStream(ZipStreamReader),
     Rar(RarReader),
 }
 
 impl ArchiveIter {
     pub fn num_images(&self) -> usize {
         match self {
-            ArchiveIter::Zip(reader) => reader
-                .archive
-                .file_names()
-                .filter(|name| validate_file(name).is_some())
-                .count(),
+            ArchiveIter::Zip(reader) => reader.num_images(),
+            ArchiveIter::ZipStream(reader) => reader.num_images,
             ArchiveIter::Rar(reader) => reader.files.len(),
         }
     }
+
+    /// Names of the archive's pages, listed from its index without reading any of them
+    pub fn page_names(&self) -> Vec<PathBuf> {
+        match self {
+            ArchiveIter::Zip(reader) => reader.page_names(),
+            ArchiveIter::ZipStream(reader) => reader.page_names.clone(),
+            ArchiveIter::Rar(reader) => reader.files.iter().map(|h| h.filename.clone()).collect(),
+        }
+    }
+
+    fn read_archives(&mut self) {
+        let entries = match self {
+            ArchiveIter::Zip(reader) => &mut reader.entries,
+            ArchiveIter::ZipStream(reader) => &mut reader.entries,
+            ArchiveIter::Rar(reader) => &mut reader.entries,
+        };
+        *entries = Entries::PagesAndArchives;
+    }
+}
+
+/// Which entries a reader yields, nested zips are only read when asked for
+#[derive(Clone, Copy, Default)]
+enum Entries {
+    #[default]
+    Pages,
+    PagesAndArchives,
+}
+
+impl Entries {
+    fn keep(self, path: &Path) -> Option<PathBuf> {
+        match self {
+            Entries::Pages => validate_file(path),
+            Entries::PagesAndArchives => {
+                validate_file(path).or_else(|| is_nested_archive(path).then(|| path.to_owned()))
+            }
+        }
+    }
+}
+
+fn is_nested_archive(path: &Path) -> bool {
+    let Some(file_name) = path.file_name() else {
+        return false;
+    };
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    !should_skip_file(&file_name.to_string_lossy()) && matches!(extension.as_str(), "zip" | "cbz")
+}
+
+type Pages = Box<dyn Iterator<Item = anyhow::Result<ArchiveFile>> + Send>;
+
+/// The archive's pages with those of the zips inside it in place of each zip, named
+/// below it like `bonus.cbz/001.jpg`. `depth` levels are opened, deeper zips are skipped
+pub fn with_nested_archives(mut archive: ArchiveIter, depth: usize) -> Pages {
+    if depth > 0 {
+        archive.read_archives();
+    }
+    flatten_nested(Box::new(archive), depth)
+}
+
+fn flatten_nested(pages: Pages, depth: usize) -> Pages {
+    if depth == 0 {
+        return pages;
+    }
+    Box::new(pages.flat_map(move |page| match page {
+        Ok(page) if is_nested_archive(&page.file_name) => {
+            open_nested(page, depth).unwrap_or_else(|e| Box::new(std::iter::once(Err(e))))
+        }
+        page => Box::new(std::iter::once(page)),
+    }))
+}
+
+fn open_nested(archive: ArchiveFile, depth: usize) -> anyhow::Result<Pages> {
+    let mut reader = ZipReader::from_reader(std::io::Cursor::new(archive.data))
+        .with_context(|| format!("Failed to open nested {}", archive.file_name.display()))?;
+    if depth > 1 {
+        reader.entries = Entries::PagesAndArchives;
+    }
+    let parent = archive.file_name;
+    let pages = reader.map(move |page| {
+        page.map(|page| ArchiveFile {
+            file_name: parent.join(page.file_name),
+            ..page
+        })
+    });
+    Ok(flatten_nested(Box::new(pages), depth - 1))
+}
+
+/// Pages in the archive, counting those in nested zips means reading the whole archive
+pub fn num_pages(
+    path: &Path,
+    format: Option<ArchiveFormat>,
+    nested_depth: usize,
+) -> anyhow::Result<usize> {
+    let archive = unarchive_comic_iter(path, format)?;
+    if nested_depth == 0 {
+        return Ok(archive.num_images());
+    }
+    Ok(with_nested_archives(archive, nested_depth).count())
 }
 
 impl Iterator for ArchiveIter {
@@ -45,12 +455,70 @@ impl Iterator for ArchiveIter {
     fn next(&mut self) -> Option<Self::Item> {
         match self {
             ArchiveIter::Zip(reader) => reader.next(),
+            ArchiveIter::ZipStream(reader) => reader.next(),
             ArchiveIter::Rar(reader) => reader.next(),
         }
     }
 }
 
-pub fn unarchive_comic_iter(comic_file: impl AsRef<Path>) -> anyhow::Result<ArchiveIter> {
+/// Renames repeated entries so pages sharing a name don't overwrite each other
+#[derive(Default)]
+struct UniqueNames {
+    seen: HashMap<PathBuf, usize>,
+}
+
+impl UniqueNames {
+    // keyed on parent + stem, since that's what processed pages are named after
+    fn disambiguate(&mut self, file_name: PathBuf) -> PathBuf {
+        let count = self.seen.entry(file_name.with_extension("")).or_insert(0);
+        *count += 1;
+        let count = *count;
+
+        if count == 1 {
+            return file_name;
+        }
+
+        let stem = file_name.file_stem().unwrap_or_default().to_string_lossy();
+        let renamed = match file_name.extension() {
+            Some(ext) => format!("{stem}~{count}.{}", ext.to_string_lossy()),
+            None => format!("{stem}~{count}"),
+        };
+        log::warn!(
+            "Duplicate archive entry {}, renamed to {renamed}",
+            file_name.display()
+        );
+        file_name.with_file_name(renamed)
+    }
+}
+
+/// Archive formats this build can read
+pub fn supported_input_formats() -> Vec<FormatInfo> {
+    vec![
+        FormatInfo {
+            name: "CBZ",
+            extension: "cbz",
+        },
+        FormatInfo {
+            name: "CBR",
+            extension: "cbr",
+        },
+        FormatInfo {
+            name: "ZIP",
+            extension: "zip",
+        },
+        FormatInfo {
+            name: "RAR",
+            extension: "rar",
+        },
+    ]
+}
+
+/// Opens `comic_file` as `format` when given, otherwise as whatever its first
+/// bytes say it is, so a RAR named .cbz still opens. The extension is the fallback
+pub fn unarchive_comic_iter(
+    comic_file: impl AsRef<Path>,
+    format: Option<ArchiveFormat>,
+) -> anyhow::Result<ArchiveIter> {
     let path = comic_file.as_ref();
     let ext = path
         .extension()
@@ -58,32 +526,233 @@ pub fn unarchive_comic_iter(comic_file: impl AsRef<Path>) -> anyhow::Result<Arch
         .map(|e| e.to_lowercase())
         .unwrap_or_default();
 
-    let reader = match ext.as_str() {
-        "cbz" | "zip" => {
-            let file = File::open(path).context("Failed to open zip file")?;
-            ArchiveIter::Zip(ZipReader::new(file)?)
+    let format = format
+        .or_else(|| detect_format(path))
+        .or(match ext.as_str() {
+            "cbz" | "zip" => Some(ArchiveFormat::Zip),
+            "cbr" | "rar" => Some(ArchiveFormat::Rar),
+            _ => None,
+        });
+
+    let reader = match format {
+        Some(ArchiveFormat::Zip) => {
+            let mut file = File::open(path).context("Failed to open zip file")?;
+            let entries = central_directory_entries(&mut file);
+            let reader = ZipReader::new(file)?;
+
+            // the zip index keeps one entry per name, read the raw entries to keep every page
+            match entries {
+                Some(entries) if entries > reader.archive.len() => {
+                    log::warn!(
+                        "{} has duplicate entry names, reading entries sequentially",
+                        path.display()
+                    );
+                    let duplicates = entries - reader.archive.len();
+                    let file = File::open(path).context("Failed to open zip file")?;
+                    ArchiveIter::ZipStream(ZipStreamReader::new(
+                        file,
+                        reader.num_images() + duplicates,
+                        reader.page_names(),
+                    ))
+                }
+                _ => ArchiveIter::Zip(reader),
+            }
         }
-        "cbr" | "rar" => ArchiveIter::Rar(RarReader::new(path)?),
-        _ => anyhow::bail!("Unsupported archive format: {}", ext),
+        Some(ArchiveFormat::Rar) => ArchiveIter::Rar(RarReader::new(path)?),
+        None => return Err(ComicError::UnsupportedFormat(ext).into()),
     };
 
     Ok(reader)
 }
 
-pub struct ZipReader {
+/// Container format from the file's magic bytes, None when unreadable or unknown
+fn detect_format(path: &Path) -> Option<ArchiveFormat> {
+    let mut magic = [0; 7];
+    File::open(path).ok()?.read_exact(&mut magic).ok()?;
+
+    match magic {
+        // local file header, or the end record of an empty archive
+        [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => Some(ArchiveFormat::Zip),
+        // RAR 4 and 5 share this prefix
+        [b'R', b'a', b'r', b'!', 0x1A, 0x07, _] => Some(ArchiveFormat::Rar),
+        _ => None,
+    }
+}
+
+/// Archive formats that hold comics but can't be read, reported by the pre-check instead of ignored
+const UNSUPPORTED_EXTENSIONS: &[&str] = &["cb7", "7z", "cbt", "tar", "cba", "pdf"];
+
+/// Every file in `dir` that is, or looks like, a comic archive
+pub fn comic_candidates(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let supported = supported_input_formats();
+    let mut files = Vec::new();
+
+    for entry in std::fs::read_dir(dir).context("failed to read dir")? {
+        let path = entry.context("failed to read dir entry")?.path();
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if supported.iter().any(|format| format.extension == extension)
+            || UNSUPPORTED_EXTENSIONS.contains(&extension.as_str())
+        {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Go/no-go for one input before a batch: the format is supported,
+/// the archive opens and its first page can be read. Returns the page count
+pub fn check_input(path: &Path) -> anyhow::Result<usize> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if UNSUPPORTED_EXTENSIONS.contains(&extension.as_str()) {
+        anyhow::bail!("Unsupported format .{}", extension);
+    }
+
+    let mut pages = unarchive_comic_iter(path, None)?;
+    let num_images = pages.num_images();
+    match pages.next() {
+        None => anyhow::bail!("No pages found"),
+        Some(Err(e)) => Err(e.context("First page is unreadable")),
+        Some(Ok(_)) => Ok(num_images),
+    }
+}
+
+/// The first page in reading order, decoded and scaled down to fit in `max_size` square.
+/// Only that page is decoded, and zips don't read the other entries at all
+pub fn first_page_thumbnail(path: &Path, max_size: u32) -> anyhow::Result<DynamicImage> {
+    let first = first_page(path)?;
+    let page = image_processor::load_image(&first.data, CmykMode::Auto)
+        .with_context(|| format!("Failed to decode {}", first.file_name.display()))?;
+    Ok(page.thumbnail(max_size, max_size))
+}
+
+/// A key that's the same for inputs holding the same comic, whatever they're named.
+/// `None` when `check` is off
+pub fn fingerprint(path: &Path, check: DuplicateCheck) -> anyhow::Result<Option<u64>> {
+    match check {
+        DuplicateCheck::Off => Ok(None),
+        DuplicateCheck::Quick => {
+            let size = std::fs::metadata(path)?.len();
+            let page = first_page(path)?;
+            Ok(Some(fnv1a(
+                fnv1a(FNV_OFFSET, &size.to_le_bytes()),
+                &page.data,
+            )))
+        }
+        DuplicateCheck::Full => hash_file(path).map(Some),
+    }
+}
+
+/// The first page in reading order, undecoded
+fn first_page(path: &Path) -> anyhow::Result<ArchiveFile> {
+    let first = match unarchive_comic_iter(path, None)? {
+        ArchiveIter::Zip(mut reader) => reader.first_page(),
+        pages => pages
+            .filter_map(Result::ok)
+            .min_by(|a, b| {
+                natural_cmp(
+                    &a.file_name.to_string_lossy(),
+                    &b.file_name.to_string_lossy(),
+                )
+            })
+            .map(Ok),
+    };
+    first.context("No pages found")?
+}
+
+/// Number of entries recorded in the end of central directory record
+fn central_directory_entries(file: &mut File) -> Option<usize> {
+    const EOCD_SIGNATURE: &[u8] = b"PK\x05\x06";
+    // fixed record size plus the longest possible comment
+    const EOCD_MAX_SIZE: u64 = 22 + u16::MAX as u64;
+
+    let len = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(EOCD_MAX_SIZE)))
+        .ok()?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail).ok()?;
+    file.rewind().ok()?;
+
+    let start = tail.windows(4).rposition(|w| w == EOCD_SIGNATURE)?;
+    let entries = tail.get(start + 10..start + 12)?;
+    let entries = u16::from_le_bytes([entries[0], entries[1]]);
+
+    // zip64 archives store the real count in a separate record
+    (entries != u16::MAX).then_some(entries as usize)
+}
+
+/// Reads entries through the central directory, seeking to each one as it's asked for,
+/// so only the pages being processed are in memory rather than the whole archive
+pub struct ZipReader<R = BufReader<File>> {
     index: usize,
-    archive: ZipArchive<BufReader<File>>,
+    archive: ZipArchive<R>,
+    names: UniqueNames,
+    entries: Entries,
 }
 
 impl ZipReader {
     fn new(file: File) -> anyhow::Result<Self> {
-        let reader = BufReader::new(file);
-        let archive = ZipArchive::new(reader).context("Failed to parse file as zip archive")?;
-        Ok(Self { index: 0, archive })
+        Self::from_reader(BufReader::new(file))
+    }
+}
+
+impl<R: Read + Seek> ZipReader<R> {
+    fn from_reader(reader: R) -> anyhow::Result<Self> {
+        let mut archive = ZipArchive::new(reader).context("Failed to parse file as zip archive")?;
+
+        // every page would be skipped without a password, fail the comic instead
+        for index in 0..archive.len() {
+            if archive
+                .by_index_raw(index)
+                .is_ok_and(|file| file.encrypted())
+            {
+                return Err(ComicError::EncryptedArchive.into());
+            }
+        }
+        Ok(Self {
+            index: 0,
+            archive,
+            names: UniqueNames::default(),
+            entries: Entries::default(),
+        })
+    }
+
+    /// The page whose name sorts first, found from the index without reading any other entry
+    fn first_page(&mut self) -> Option<anyhow::Result<ArchiveFile>> {
+        let (index, _) = (0..self.archive.len())
+            .filter_map(|index| {
+                let name = self.archive.name_for_index(index)?;
+                validate_file(name)?;
+                Some((index, name))
+            })
+            .min_by(|(_, a), (_, b)| natural_cmp(a, b))?;
+        self.index = index;
+        self.next()
+    }
+
+    fn num_images(&self) -> usize {
+        self.archive
+            .file_names()
+            .filter(|name| validate_file(name).is_some())
+            .count()
+    }
+
+    fn page_names(&self) -> Vec<PathBuf> {
+        self.archive
+            .file_names()
+            .filter_map(validate_file)
+            .collect()
     }
 }
 
-impl Iterator for ZipReader {
+impl<R: Read + Seek> Iterator for ZipReader<R> {
     type Item = anyhow::Result<ArchiveFile>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -105,7 +774,7 @@ impl Iterator for ZipReader {
                 None => continue,
             };
 
-            let file_name = match validate_file(&outpath) {
+            let file_name = match self.entries.keep(&outpath) {
                 Some(name) => name,
                 None => continue,
             };
@@ -115,6 +784,73 @@ impl Iterator for ZipReader {
                 return Some(Err(e.into()));
             }
 
+            let file_name = self.names.disambiguate(file_name);
+            return Some(Ok(ArchiveFile { file_name, data }));
+        }
+
+        None
+    }
+}
+
+/// Reads zip entries in the order they are stored instead of through the central directory
+pub struct ZipStreamReader {
+    reader: BufReader<File>,
+    num_images: usize,
+    page_names: Vec<PathBuf>,
+    names: UniqueNames,
+    entries: Entries,
+    finished: bool,
+}
+
+impl ZipStreamReader {
+    fn new(file: File, num_images: usize, page_names: Vec<PathBuf>) -> Self {
+        Self {
+            reader: BufReader::new(file),
+            num_images,
+            page_names,
+            names: UniqueNames::default(),
+            entries: Entries::default(),
+            finished: false,
+        }
+    }
+}
+
+impl Iterator for ZipStreamReader {
+    type Item = anyhow::Result<ArchiveFile>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            let mut file = match zip::read::read_zipfile_from_stream(&mut self.reader) {
+                Ok(Some(file)) => file,
+                // reached the central directory
+                Ok(None) => {
+                    self.finished = true;
+                    return None;
+                }
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(e.into()));
+                }
+            };
+
+            if file.is_dir() {
+                continue;
+            }
+
+            let Some(file_name) = file
+                .enclosed_name()
+                .and_then(|name| self.entries.keep(&name))
+            else {
+                continue;
+            };
+
+            let mut data = Vec::new();
+            if let Err(e) = Read::read_to_end(&mut file, &mut data) {
+                self.finished = true;
+                return Some(Err(e.into()));
+            }
+
+            let file_name = self.names.disambiguate(file_name);
             return Some(Ok(ArchiveFile { file_name, data }));
         }
 
@@ -125,6 +861,8 @@ impl Iterator for ZipReader {
 pub struct RarReader {
     archive: Option<unrar::OpenArchive<unrar::Process, unrar::CursorBeforeHeader>>,
     files: Vec<unrar::FileHeader>,
+    names: UniqueNames,
+    entries: Entries,
     finished: bool,
 }
 
@@ -148,6 +886,8 @@ impl RarReader {
         Ok(Self {
             archive: Some(archive),
             files,
+            names: UniqueNames::default(),
+            entries: Entries::default(),
             finished: false,
         })
     }
@@ -181,7 +921,7 @@ impl Iterator for RarReader {
                     return self.next();
                 }
 
-                let Some(file_name) = validate_file(file_path) else {
+                let Some(file_name) = self.entries.keep(file_path) else {
                     let Ok(archive) = header.skip() else {
                         return None;
                     };
@@ -194,6 +934,7 @@ impl Iterator for RarReader {
                 };
                 self.archive = Some(new_archive);
 
+                let file_name = self.names.disambiguate(file_name);
                 Some(Ok(ArchiveFile { file_name, data }))
             }
             _ => {
@@ -222,7 +963,8 @@ fn should_skip_file(file_name: &str) -> bool {
 }
 
 fn has_image_extension(path: &Path) -> bool {
-    static VALID_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png"];
+    // HEIF is listed so unsupported pages are reported rather than silently dropped
+    static VALID_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "heic", "heif"];
     if let Some(ext) = path.extension() {
         let ext_str = ext.to_string_lossy().to_lowercase();
         for valid_ext in VALID_EXTENSIONS {
@@ -237,8 +979,427 @@ fn has_image_extension(path: &Path) -> bool {
 #[ignore]
 #[test]
 fn test_unarchive_comic_iter() {
-    let files = unarchive_comic_iter(std::path::PathBuf::from("v12.cbz"))
+    let files = unarchive_comic_iter(std::path::PathBuf::from("v12.cbz"), None)
         .unwrap()
         .collect::<Vec<_>>();
     println!("{:?}", files.len());
 }
+
+#[test]
+fn duplicate_entry_names_keep_both_pages() {
+    use std::io::Write;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    zip.start_file("001.jpg", options).unwrap();
+    zip.write_all(b"first").unwrap();
+    zip.start_file("00X.jpg", options).unwrap();
+    zip.write_all(b"second").unwrap();
+    let mut bytes = zip.finish().unwrap().into_inner();
+
+    // the writer refuses duplicate names, so patch the second name in both headers
+    let mut patched = 0;
+    for i in 0..bytes.len() - 7 {
+        if &bytes[i..i + 7] == b"00X.jpg" {
+            bytes[i..i + 7].copy_from_slice(b"001.jpg");
+            patched += 1;
+        }
+    }
+    assert_eq!(patched, 2);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("duplicates.cbz");
+    std::fs::write(&path, bytes).unwrap();
+
+    let iter = unarchive_comic_iter(&path, None).unwrap();
+    assert_eq!(iter.num_images(), 2);
+
+    let files = iter.collect::<anyhow::Result<Vec<_>>>().unwrap();
+    let names: Vec<_> = files.iter().map(|f| f.file_name.clone()).collect();
+    assert_eq!(
+        names,
+        vec![PathBuf::from("001.jpg"), PathBuf::from("001~2.jpg")]
+    );
+    assert_eq!(files[0].data, b"first");
+    assert_eq!(files[1].data, b"second");
+}
+
+#[test]
+fn thumbnails_are_dropped() {
+    let png = |width, height| {
+        let mut data = std::io::Cursor::new(Vec::new());
+        DynamicImage::new_luma8(width, height)
+            .write_to(&mut data, ImageFormat::Png)
+            .unwrap();
+        data.into_inner()
+    };
+    let pages = [
+        ("thumb.png", png(48, 64)),
+        ("page.png", png(800, 1200)),
+        ("narrow.png", png(800, 20)),
+        ("broken.jpg", b"not an image".to_vec()),
+    ]
+    .map(|(name, data)| {
+        Ok(ArchiveFile {
+            file_name: name.into(),
+            data,
+        })
+    });
+
+    let kept: Vec<_> = without_thumbnails(pages.into_iter(), 64)
+        .map(|page| page.unwrap().file_name)
+        .collect();
+    assert_eq!(
+        kept,
+        vec![PathBuf::from("page.png"), PathBuf::from("broken.jpg")]
+    );
+}
+
+#[test]
+fn excluded_entries_are_not_pages() {
+    let pages = ["001.jpg", "ads/002.jpg", "ad_003.jpg", "bad_004.jpg"].map(|name| {
+        Ok(ArchiveFile {
+            file_name: name.into(),
+            data: Vec::new(),
+        })
+    });
+    let exclude = exclude_pattern(r"(^|/)ad_|^ads/").unwrap();
+
+    let kept: Vec<_> = without_excluded(pages.into_iter(), Some(exclude))
+        .map(|page| page.unwrap().file_name)
+        .collect();
+    assert_eq!(
+        kept,
+        vec![PathBuf::from("001.jpg"), PathBuf::from("bad_004.jpg")]
+    );
+    assert!(exclude_pattern("ad_(").is_err());
+}
+
+#[test]
+fn failures_keep_their_kind() {
+    use std::io::Write;
+    use zip::{write::SimpleFileOptions, AesMode, ZipWriter};
+
+    let dir = tempfile::tempdir().unwrap();
+    let locked = dir.path().join("locked.cbz");
+    let mut zip = ZipWriter::new(File::create(&locked).unwrap());
+    let options = SimpleFileOptions::default().with_aes_encryption(AesMode::Aes256, "secret");
+    zip.start_file("001.jpg", options).unwrap();
+    zip.write_all(b"page").unwrap();
+    zip.finish().unwrap();
+
+    let error = unarchive_comic_iter(&locked, None)
+        .err()
+        .unwrap()
+        .context("Failed to open comic");
+    assert!(matches!(
+        ComicError::from(error),
+        ComicError::EncryptedArchive
+    ));
+
+    let error = unarchive_comic_iter(dir.path().join("comic.pdf"), None)
+        .err()
+        .unwrap();
+    assert!(matches!(
+        ComicError::from(error),
+        ComicError::UnsupportedFormat(extension) if extension == "pdf"
+    ));
+}
+
+#[test]
+fn check_input_reports_problems() {
+    use std::io::Write;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    let dir = tempfile::tempdir().unwrap();
+    let write_zip = |name: &str, pages: &[&str]| {
+        let path = dir.path().join(name);
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        for page in pages {
+            zip.start_file(*page, SimpleFileOptions::default()).unwrap();
+            zip.write_all(b"page").unwrap();
+        }
+        zip.finish().unwrap();
+        path
+    };
+
+    let good = write_zip("good.cbz", &["001.jpg", "002.jpg"]);
+    let empty = write_zip("empty.cbz", &["notes.txt"]);
+    let seven = dir.path().join("other.cb7");
+    std::fs::write(&seven, b"7z").unwrap();
+    std::fs::write(dir.path().join("readme.txt"), b"").unwrap();
+
+    assert_eq!(check_input(&good).unwrap(), 2);
+    assert!(check_input(&empty).is_err());
+    assert!(check_input(&seven).is_err());
+    assert_eq!(
+        comic_candidates(dir.path()).unwrap(),
+        vec![empty, good, seven]
+    );
+}
+
+#[test]
+fn sort_key_orders_by_captured_numbers() {
+    let key = SortKey::new(r"chapter_(\d+_p\d+)").unwrap();
+    let mut names = [
+        "chapter_10_p1.jpg",
+        "cover.jpg",
+        "chapter_2_p10.jpg",
+        "chapter_2_p9.jpg",
+    ];
+    names.sort_by_key(|name| key.prefix(Path::new(name)));
+    assert_eq!(
+        names,
+        [
+            "chapter_2_p9.jpg",
+            "chapter_2_p10.jpg",
+            "chapter_10_p1.jpg",
+            "cover.jpg"
+        ]
+    );
+
+    assert!(SortKey::new("(unclosed").is_err());
+}
+
+#[test]
+fn natural_order_across_folders() {
+    let mut names = [
+        "Chapter 10/001.jpg",
+        "Chapter 2/010.jpg",
+        "Chapter 2/9.jpg",
+        "Chapter 1/002.jpg",
+        "Chapter 1/02.jpg",
+    ];
+    names.sort_by(|a, b| natural_cmp(a, b));
+    assert_eq!(
+        names,
+        [
+            "Chapter 1/002.jpg",
+            "Chapter 1/02.jpg",
+            "Chapter 2/9.jpg",
+            "Chapter 2/010.jpg",
+            "Chapter 10/001.jpg",
+        ]
+    );
+}
+
+#[test]
+fn format_detected_from_magic_bytes() {
+    use std::io::Write;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    let dir = tempfile::tempdir().unwrap();
+    // a zip mislabeled as RAR still opens as a zip
+    let mislabeled = dir.path().join("zip.cbr");
+    let mut zip = ZipWriter::new(File::create(&mislabeled).unwrap());
+    zip.start_file("001.jpg", SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(b"page").unwrap();
+    zip.finish().unwrap();
+
+    let pages = unarchive_comic_iter(&mislabeled, None).unwrap();
+    assert!(matches!(pages, ArchiveIter::Zip(_)));
+    assert_eq!(pages.count(), 1);
+
+    let rar = dir.path().join("rar.cbz");
+    std::fs::write(&rar, b"Rar!\x1a\x07\x01\x00rest").unwrap();
+    assert_eq!(detect_format(&rar), Some(ArchiveFormat::Rar));
+
+    // forcing a format skips detection
+    assert!(unarchive_comic_iter(&mislabeled, Some(ArchiveFormat::Rar)).is_err());
+}
+
+#[test]
+fn page_order_ranks_listed_entries_first() {
+    let order = PageOrder::new(&[
+        "ch1/002.jpg".to_string(),
+        "001.jpg".to_string(),
+        "gone.jpg".to_string(),
+    ]);
+
+    assert_eq!(order.rank(Path::new("ch1/002.jpg")), 0);
+    // a bare file name matches in any folder
+    assert_eq!(order.rank(Path::new("ch1/001.jpg")), 1);
+    assert_eq!(order.rank(Path::new("ch1/003.jpg")), 3);
+
+    let entries = [PathBuf::from("ch1/001.jpg"), PathBuf::from("ch1/002.jpg")];
+    assert_eq!(order.missing(&entries), vec!["gone.jpg".to_string()]);
+}
+
+#[test]
+fn nested_archives_are_flattened_to_their_depth() {
+    use std::io::{Cursor, Write};
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    let zip = |entries: Vec<(&str, Vec<u8>)>| {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(&data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    };
+    let deeper = zip(vec![("x.png", b"x".to_vec())]);
+    let bonus = zip(vec![("a.png", b"a".to_vec()), ("deeper.zip", deeper)]);
+    let outer = zip(vec![
+        ("001.png", b"1".to_vec()),
+        ("bonus.cbz", bonus),
+        ("broken.zip", b"not a zip".to_vec()),
+    ]);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("outer.cbz");
+    std::fs::write(&path, outer).unwrap();
+
+    let pages = |depth| {
+        let archive = unarchive_comic_iter(&path, None).unwrap();
+        with_nested_archives(archive, depth)
+            .filter_map(Result::ok)
+            .map(|page| page.file_name)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(pages(0), vec![PathBuf::from("001.png")]);
+    assert_eq!(
+        pages(1),
+        vec![PathBuf::from("001.png"), PathBuf::from("bonus.cbz/a.png")]
+    );
+    assert_eq!(
+        pages(2).last().unwrap(),
+        Path::new("bonus.cbz/deeper.zip/x.png")
+    );
+    // the broken zip counts, it's reported as a page that couldn't be read
+    assert_eq!(num_pages(&path, None, 2).unwrap(), 4);
+
+    // an unreadable nested zip is an error in its place, the other pages still come through
+    let archive = unarchive_comic_iter(&path, None).unwrap();
+    let errors = with_nested_archives(archive, 1)
+        .filter(Result::is_err)
+        .count();
+    assert_eq!(errors, 1);
+}
+
+#[test]
+fn zip_entries_are_read_on_demand() {
+    use std::io::{Cursor, Write};
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    /// Counts the bytes pulled from the archive
+    struct Counting<R>(R, Arc<AtomicU64>);
+    impl<R: Read> Read for Counting<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let read = self.0.read(buf)?;
+            self.1.fetch_add(read as u64, Ordering::Relaxed);
+            Ok(read)
+        }
+    }
+    impl<R: Seek> Seek for Counting<R> {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    // 64 stored pages of 1 MiB each
+    const PAGE: usize = 1 << 20;
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    for page in 0..64u8 {
+        zip.start_file(format!("{page:03}.jpg"), options).unwrap();
+        zip.write_all(&vec![page; PAGE]).unwrap();
+    }
+    let archive = zip.finish().unwrap().into_inner();
+
+    let read = Arc::new(AtomicU64::new(0));
+    let mut reader = ZipReader::from_reader(Counting(Cursor::new(archive), read.clone())).unwrap();
+    assert_eq!(reader.num_images(), 64);
+
+    let first = reader.next().unwrap().unwrap();
+    assert_eq!(first.data, vec![0; PAGE]);
+    // the first page and the index, not the other 63 MiB
+    assert!(read.load(Ordering::Relaxed) < 2 * PAGE as u64);
+
+    assert_eq!(reader.count(), 63);
+    assert!(read.load(Ordering::Relaxed) >= 64 * PAGE as u64);
+}
+
+#[test]
+fn thumbnail_is_the_first_page_in_reading_order() {
+    use imageproc::image::{GenericImageView, GrayImage, Luma};
+    use std::io::Write;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("book.cbz");
+    let mut zip = ZipWriter::new(File::create(&path).unwrap());
+    for (name, luma) in [("page10.png", 255), ("page2.png", 0), ("notes.txt", 0)] {
+        let mut data = Vec::new();
+        GrayImage::from_pixel(400, 600, Luma([luma]))
+            .write_to(&mut std::io::Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        zip.start_file(name, SimpleFileOptions::default()).unwrap();
+        zip.write_all(&data).unwrap();
+    }
+    zip.finish().unwrap();
+
+    let thumbnail = first_page_thumbnail(&path, 100).unwrap();
+    assert_eq!(thumbnail.dimensions(), (67, 100));
+    assert_eq!(thumbnail.to_luma8().get_pixel(30, 50)[0], 0);
+}
+
+#[test]
+fn spread_halves_are_merged_by_name() {
+    use imageproc::image::{GenericImageView, GrayImage, Luma};
+
+    let page = |name: &str, (width, height), luma| {
+        let mut data = Vec::new();
+        GrayImage::from_pixel(width, height, Luma([luma]))
+            .write_to(&mut std::io::Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        Ok(ArchiveFile {
+            file_name: name.into(),
+            data,
+        })
+    };
+    let pages = vec![
+        page("ch1/012b.png", (40, 60), 200),
+        page("ch1/011.png", (40, 60), 100),
+        page("ch1/012a.png", (40, 60), 0),
+        page("ch1/013_L.png", (40, 60), 0),
+        page("ch1/013_R.png", (40, 30), 0),
+        page("ch1/014a.png", (40, 60), 0),
+    ];
+
+    let mut merged: Vec<_> = merge_spreads(pages.into_iter(), true, CmykMode::Auto)
+        .map(Result::unwrap)
+        .collect();
+    merged.sort_by(|a, b| {
+        natural_cmp(
+            &a.file_name.to_string_lossy(),
+            &b.file_name.to_string_lossy(),
+        )
+    });
+    let names: Vec<_> = merged
+        .iter()
+        .map(|page| page.file_name.to_string_lossy().into_owned())
+        .collect();
+    // 013's halves aren't the same height, 014 has no other half
+    assert_eq!(
+        names,
+        [
+            "ch1/011.png",
+            "ch1/012b.png",
+            "ch1/013_L.png",
+            "ch1/013_R.png",
+            "ch1/014a.png"
+        ]
+    );
+
+    // right to left, `a` is read first and goes on the right
+    let spread = image_processor::load_image(&merged[1].data, CmykMode::Auto).unwrap();
+    assert_eq!(spread.dimensions(), (80, 60));
+    assert_eq!(spread.to_luma8().get_pixel(10, 30)[0], 200);
+    assert_eq!(spread.to_luma8().get_pixel(70, 30)[0], 0);
+}
@@ -0,0 +1,117 @@
+use anyhow::Context;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{path::PathBuf, sync::mpsc, thread};
+
+use crate::{
+    comic::{ComicConfig, ProgressEvent},
+    comic_archive,
+    pipeline::{self, InputRoot},
+    progress::{ComicPhase, ProgressTracker},
+    Event,
+};
+
+/// Process every comic in `directories` with the saved config, drawing a console
+/// progress bar for the whole batch instead of the TUI
+pub fn run(directories: Vec<PathBuf>, output_dir: Option<PathBuf>) -> anyhow::Result<()> {
+    let directories = if directories.is_empty() {
+        vec![std::env::current_dir()?]
+    } else {
+        directories
+    };
+    let output_dir = output_dir.unwrap_or_else(|| directories[0].join("comically"));
+    std::fs::create_dir_all(&output_dir)
+        .with_context(|| format!("Failed to create {}", output_dir.display()))?;
+
+    let mut roots = Vec::new();
+    for root in directories {
+        let files = comic_archive::comic_candidates(&root)
+            .with_context(|| format!("Failed to read {}", root.display()))?;
+        roots.push(InputRoot {
+            root,
+            files,
+            ..InputRoot::default()
+        });
+    }
+    let config = ComicConfig::load().unwrap_or_default();
+
+    let (event_tx, event_rx) = mpsc::channel();
+    let processing =
+        thread::spawn(move || pipeline::process_files(roots, config, output_dir, event_tx, None));
+
+    render(event_rx.into_iter().filter_map(|event| match event {
+        Event::Progress { seq, event } => Some((seq, event)),
+        _ => None,
+    }));
+
+    let summary = processing
+        .join()
+        .map_err(|_| anyhow::anyhow!("Processing panicked"))??;
+    // a failed conversion makes the exit code non-zero, for scripts
+    if !summary.failed.is_empty() {
+        anyhow::bail!(
+            "{} of {} comics failed",
+            summary.failed.len(),
+            summary.total()
+        );
+    }
+    Ok(())
+}
+
+/// Draws one bar for the whole batch from the progress events, until `ProcessingComplete`
+pub fn render(events: impl Iterator<Item = (u64, ProgressEvent)>) {
+    let bar = ProgressBar::new(0);
+    bar.set_style(
+        ProgressStyle::with_template("{bar:40.cyan/blue} {percent:>3}% {msg}")
+            .expect("valid progress template")
+            .progress_chars("=> "),
+    );
+
+    let mut tracker = ProgressTracker::new();
+    for (seq, event) in events {
+        tracker.handle(seq, &event);
+        match &event {
+            // printed around the bar, which stays hidden when stderr isn't a terminal
+            ProgressEvent::Warning { message, .. } => {
+                bar.suspend(|| eprintln!("warning: {message}"));
+            }
+            ProgressEvent::BatchPlan {
+                total_comics,
+                total_pages,
+            } => {
+                bar.suspend(|| eprintln!("{total_comics} comics, {total_pages} pages"));
+            }
+            ProgressEvent::Bundled { path, bytes } => {
+                bar.suspend(|| eprintln!("bundled into {} ({} bytes)", path.display(), bytes));
+            }
+            ProgressEvent::ProcessingComplete => break,
+            _ => {}
+        }
+
+        let comics = tracker.snapshot();
+        // every comic counts for 100, so the bar moves as each one does
+        bar.set_length(comics.len() as u64 * 100);
+        bar.set_position(comics.iter().map(|comic| comic.percent as u64).sum());
+
+        let done = comics
+            .iter()
+            .filter(|comic| !matches!(comic.status, ComicPhase::Waiting | ComicPhase::Running))
+            .count();
+        bar.set_message(format!("{done}/{} comics", comics.len()));
+
+        if let ProgressEvent::ComicUpdate { id, .. } = &event {
+            if let Some(comic) = comics.iter().find(|comic| comic.id == *id) {
+                if let ComicPhase::Failed { error } = &comic.status {
+                    bar.suspend(|| eprintln!("failed: {}: {}", comic.title, error));
+                }
+            }
+        }
+    }
+
+    let comics = tracker.snapshot();
+    let succeeded = comics
+        .iter()
+        .filter(|comic| comic.status.is_success())
+        .count();
+    bar.finish_and_clear();
+    eprintln!("{succeeded}/{} comics converted", comics.len());
+}
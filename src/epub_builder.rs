@@ -1,6 +1,7 @@
 use anyhow::Result;
 use std::fs::{self, create_dir_all, File};
 use std::io::{BufWriter, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use uuid::Uuid;
 use walkdir::WalkDir;
@@ -9,7 +10,8 @@ use zip::{
     CompressionMethod,
 };
 
-use crate::comic::{Comic, ProcessedImage};
+use crate::comic::{Comic, ProcessedImage, ReaderProfile};
+use crate::page_cache::{fnv1a, hash_file, FNV_OFFSET};
 
 /// Builds an EPUB file from the processed images
 pub fn build_epub(comic: &Comic) -> Result<()> {
@@ -29,32 +31,149 @@ pub fn build_epub(comic: &Comic) -> Result<()> {
 
     create_mimetype_file(&epub_dir)?;
     create_container_xml(&meta_inf_dir)?;
+    if comic.config.reader_profile == ReaderProfile::AppleBooks {
+        create_ibooks_options(&meta_inf_dir)?;
+    }
+
+    // a placeholder book with just the cover when `cover_only` is set
+    let pages = if comic.config.cover_only {
+        &comic.processed_files[..comic.processed_files.len().min(1)]
+    } else {
+        &comic.processed_files[..]
+    };
 
     let mut image_map: Vec<(ProcessedImage, String)> = Vec::new();
-    for (i, image) in comic.processed_files.iter().enumerate() {
-        let filename = format!("image{:03}.jpg", i + 1);
+    for (i, image) in pages.iter().enumerate() {
+        let extension = image.path.extension().unwrap_or_default().to_string_lossy();
+        let filename = format!("image{:03}.{}", i + 1, extension.to_lowercase());
         image_map.push((image.clone(), format!("Images/{}", filename)));
     }
 
-    let cover_html_path = create_cover_page(&oebps_dir, &image_map)?;
+    let profile = comic.config.reader_profile;
+    let cover_html_path = create_cover_page(&oebps_dir, &image_map, profile)?;
 
-    // Generate HTML for each image
+    // Generate HTML for each group of images
     let html_dir = oebps_dir.clone();
-    let html_files = create_html_files(&html_dir, &image_map)?;
+    let groups = page_groups(image_map.len(), comic.config.images_per_page);
+    let html_files = create_html_files(&html_dir, &image_map, &groups, profile)?;
+
+    let uuid = book_uuid(comic)?;
+
+    let mut chapters = find_chapters(pages);
+    for chapter in &mut chapters {
+        chapter.page = groups
+            .iter()
+            .position(|group| group.contains(&chapter.page))
+            .unwrap_or_default();
+    }
+    if !chapters.is_empty() {
+        create_nav_xhtml(comic, &oebps_dir, &html_files, &chapters)?;
+    }
 
     // Create toc.ncx
-    create_toc_ncx(comic, &oebps_dir, &cover_html_path, &html_files)?;
+    create_toc_ncx(
+        comic,
+        &uuid,
+        &oebps_dir,
+        &cover_html_path,
+        &html_files,
+        &chapters,
+    )?;
 
     // Create content.opf
-    create_content_opf(comic, &oebps_dir, &cover_html_path, &html_files, &image_map)?;
+    create_content_opf(
+        comic,
+        &uuid,
+        &oebps_dir,
+        &cover_html_path,
+        &html_files,
+        &image_map,
+        !chapters.is_empty(),
+    )?;
 
     // Package as EPUB
     let epub_path = comic.epub_file();
-    create_epub_file(&epub_dir, &epub_path, &image_map)?;
+    create_epub_file(comic, &epub_dir, &epub_path, &image_map)?;
 
     Ok(())
 }
 
+/// Unique identifier for the book, derived from the title and the pages' contents when
+/// output must be deterministic, otherwise drawn from the comic's seeded random numbers
+fn book_uuid(comic: &Comic) -> Result<String> {
+    if !comic.config.deterministic {
+        let bytes = rand::Rng::random(&mut comic.rng());
+        return Ok(uuid::Builder::from_random_bytes(bytes)
+            .into_uuid()
+            .to_string());
+    }
+
+    let mut hash = fnv1a(FNV_OFFSET, comic.title.as_bytes());
+    for page in &comic.processed_files {
+        hash = fnv1a(hash, &hash_file(&page.path)?.to_le_bytes());
+    }
+    Ok(Uuid::from_u64_pair(fnv1a(hash, &[0]), fnv1a(hash, &[1])).to_string())
+}
+
+/// Which images go on each HTML page, `per_page` at a time.
+/// The first image stays alone since its page is replaced by the cover in the spine
+fn page_groups(images: usize, per_page: usize) -> Vec<Range<usize>> {
+    let per_page = per_page.max(1);
+    let mut groups = Vec::new();
+    if images > 0 {
+        groups.push(0..1);
+    }
+    let mut start = 1;
+    while start < images {
+        let end = (start + per_page).min(images);
+        groups.push(start..end);
+        start = end;
+    }
+    groups
+}
+
+/// A chapter beginning at `page`, an index into the processed images until they are
+/// grouped into HTML pages, then an index into those
+struct Chapter {
+    title: String,
+    page: usize,
+}
+
+/// Chapters from the archive folders pages came from, empty unless there are at least two
+fn find_chapters(images: &[ProcessedImage]) -> Vec<Chapter> {
+    let mut chapters = Vec::new();
+    let mut current = None;
+
+    for (page, image) in images.iter().enumerate() {
+        let Some(folder) = &image.chapter else {
+            continue;
+        };
+        if current == Some(folder) {
+            continue;
+        }
+        current = Some(folder);
+
+        let title = Path::new(folder)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| folder.clone());
+        chapters.push(Chapter { title, page });
+    }
+
+    // a single folder is just how the archive was packed, not a chapter marker
+    if chapters.len() < 2 {
+        chapters.clear();
+    }
+    chapters
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
 /// Creates the mimetype file (must be first in the EPUB and not compressed)
 fn create_mimetype_file(epub_dir: &Path) -> Result<()> {
     let mimetype_path = epub_dir.join("mimetype");
@@ -78,15 +197,59 @@ fn create_container_xml(meta_inf_dir: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Apple Books lays out an EPUB as reflowable text unless told otherwise
+fn create_ibooks_options(meta_inf_dir: &Path) -> Result<()> {
+    let options_path = meta_inf_dir.join("com.apple.ibooks.display-options.xml");
+    let mut file = File::create(&options_path)?;
+    file.write_all(
+        br#"<?xml version="1.0" encoding="UTF-8"?>
+<display_options>
+  <platform name="*">
+    <option name="fixed-layout">true</option>
+    <option name="open-to-spread">false</option>
+  </platform>
+</display_options>"#,
+    )?;
+    Ok(())
+}
+
+/// Style for the readers that keep their default body margins around fixed pages
+fn page_style(profile: ReaderProfile) -> &'static str {
+    match profile {
+        ReaderProfile::Compatible | ReaderProfile::Kindle => "",
+        ReaderProfile::AppleBooks | ReaderProfile::KoReader => {
+            r#"
+  <style>html, body { margin: 0; padding: 0; } img { display: block; width: 100%; height: 100%; object-fit: contain; }</style>"#
+        }
+    }
+}
+
 /// Creates a cover page using the first image
-fn create_cover_page(oebps_dir: &Path, image_map: &[(ProcessedImage, String)]) -> Result<PathBuf> {
+fn create_cover_page(
+    oebps_dir: &Path,
+    image_map: &[(ProcessedImage, String)],
+    profile: ReaderProfile,
+) -> Result<PathBuf> {
     // If no images, return early
     if image_map.is_empty() {
         return Err(anyhow::anyhow!("No images found to create cover page"));
     }
 
     // Use the first image from the map as cover
-    let (_, rel_path) = &image_map[0];
+    let (image, rel_path) = &image_map[0];
+    // only Kindle sizes the cover to the screen, elsewhere it's sized like the other pages
+    let viewport = match profile {
+        ReaderProfile::Compatible | ReaderProfile::Kindle => {
+            "width=device-width, height=device-height".to_string()
+        }
+        ReaderProfile::AppleBooks | ReaderProfile::KoReader => {
+            format!(
+                "width={}, height={}",
+                image.dimensions.0, image.dimensions.1
+            )
+        }
+    };
+    let style = page_style(profile);
 
     // Create cover HTML with relative path from image_map
     let cover_html = format!(
@@ -95,15 +258,14 @@ fn create_cover_page(oebps_dir: &Path, image_map: &[(ProcessedImage, String)]) -
 <html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
 <head>
   <title>Cover</title>
-  <meta name="viewport" content="width=device-width, height=device-height, initial-scale=1.0, maximum-scale=1.0, user-scalable=no"/>
+  <meta name="viewport" content="{viewport}, initial-scale=1.0, maximum-scale=1.0, user-scalable=no"/>{style}
 </head>
 <body style="background-color:#000000;">
   <div class="cover">
-    <img src="{}" alt="Cover"/>
+    <img src="{rel_path}" alt="Cover"/>
   </div>
 </body>
 </html>"#,
-        rel_path
     );
 
     let cover_html_path = oebps_dir.join("cover.html");
@@ -113,35 +275,52 @@ fn create_cover_page(oebps_dir: &Path, image_map: &[(ProcessedImage, String)]) -
     Ok(cover_html_path)
 }
 
-/// Creates HTML files for each image
+/// Creates an HTML file for each group of images, stacked top to bottom
 fn create_html_files(
     oebps_dir: &Path,
     images: &[(ProcessedImage, String)],
+    groups: &[Range<usize>],
+    profile: ReaderProfile,
 ) -> Result<Vec<PathBuf>> {
     let mut html_files = Vec::new();
+    let style = page_style(profile);
 
-    for (i, (image, rel_path)) in images.iter().enumerate() {
+    for (i, group) in groups.iter().enumerate() {
         let filename = format!("page{:03}.html", i + 1);
         let html_path = oebps_dir.join(&filename);
 
+        let images = &images[group.clone()];
+        let width = images
+            .iter()
+            .map(|(image, _)| image.dimensions.0)
+            .max()
+            .unwrap_or_default();
+        let height: u32 = images.iter().map(|(image, _)| image.dimensions.1).sum();
+        let divs: String = images
+            .iter()
+            .map(|(_, rel_path)| {
+                format!(
+                    r#"
+  <div class="image">
+    <img src="{}"/>
+  </div>"#,
+                    rel_path
+                )
+            })
+            .collect();
+
         let html_content = format!(
             r#"<?xml version="1.0" encoding="UTF-8"?>
 <!DOCTYPE html>
 <html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
 <head>
   <title>Page {}</title>
-  <meta name="viewport" content="width={width}, height={height}, initial-scale=1.0, maximum-scale=1.0, user-scalable=no"/>
+  <meta name="viewport" content="width={width}, height={height}, initial-scale=1.0, maximum-scale=1.0, user-scalable=no"/>{style}
 </head>
-<body>
-  <div class="image">
-    <img src="{}"/>
-  </div>
+<body>{divs}
 </body>
 </html>"#,
             i + 1,
-            rel_path,
-            width = image.dimensions.0,
-            height = image.dimensions.1,
         );
 
         let mut file = File::create(&html_path)?;
@@ -153,15 +332,61 @@ fn create_html_files(
     Ok(html_files)
 }
 
-/// Creates the toc.ncx file (navigation)
+/// Creates the nav.xhtml file (EPUB 3 navigation) listing the chapters
+fn create_nav_xhtml(
+    c: &Comic,
+    oebps_dir: &Path,
+    html_files: &[PathBuf],
+    chapters: &[Chapter],
+) -> Result<()> {
+    let nav_path = oebps_dir.join("nav.xhtml");
+
+    let mut entries = String::new();
+    for chapter in chapters {
+        let filename = html_files[chapter.page]
+            .file_name()
+            .unwrap()
+            .to_string_lossy();
+        entries.push_str(&format!(
+            "      <li><a href=\"{}\">{}</a></li>\n",
+            filename,
+            escape_xml(&chapter.title)
+        ));
+    }
+
+    let nav_content = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head>
+  <title>{title}</title>
+</head>
+<body>
+  <nav epub:type="toc" id="toc">
+    <ol>
+{entries}    </ol>
+  </nav>
+</body>
+</html>"#,
+        title = escape_xml(&c.title)
+    );
+
+    let mut file = File::create(&nav_path)?;
+    file.write_all(nav_content.as_bytes())?;
+
+    Ok(())
+}
+
+/// Creates the toc.ncx file (navigation), with chapter entries when there are any
 fn create_toc_ncx(
     c: &Comic,
+    uuid: &str,
     oebps_dir: &Path,
     cover_html_path: &Path,
     html_files: &[PathBuf],
+    chapters: &[Chapter],
 ) -> Result<()> {
     let toc_path = oebps_dir.join("toc.ncx");
-    let uuid = Uuid::new_v4().to_string();
 
     let mut nav_points = String::new();
 
@@ -176,20 +401,41 @@ fn create_toc_ncx(
         cover_filename
     ));
 
-    // Add content pages to nav points
-    for (i, html_file) in html_files.iter().enumerate() {
-        let filename = html_file.file_name().unwrap().to_string_lossy();
-        nav_points.push_str(&format!(
-            r#"    <navPoint id="navpoint-{}" playOrder="{}">
+    if chapters.is_empty() {
+        // Add content pages to nav points
+        for (i, html_file) in html_files.iter().enumerate() {
+            let filename = html_file.file_name().unwrap().to_string_lossy();
+            nav_points.push_str(&format!(
+                r#"    <navPoint id="navpoint-{}" playOrder="{}">
       <navLabel><text>Page {}</text></navLabel>
       <content src="{}"/>
     </navPoint>
 "#,
-            i + 2, // +2 because cover is 1
-            i + 2,
-            i + 1,
-            filename
-        ));
+                i + 2, // +2 because cover is 1
+                i + 2,
+                i + 1,
+                filename
+            ));
+        }
+    } else {
+        // Add chapter starts to nav points
+        for (i, chapter) in chapters.iter().enumerate() {
+            let filename = html_files[chapter.page]
+                .file_name()
+                .unwrap()
+                .to_string_lossy();
+            nav_points.push_str(&format!(
+                r#"    <navPoint id="navpoint-{}" playOrder="{}">
+      <navLabel><text>{}</text></navLabel>
+      <content src="{}"/>
+    </navPoint>
+"#,
+                i + 2, // +2 because cover is 1
+                i + 2,
+                escape_xml(&chapter.title),
+                filename
+            ));
+        }
     }
 
     let toc_content = format!(
@@ -205,7 +451,7 @@ fn create_toc_ncx(
   <navMap>
 {nav_points}  </navMap>
 </ncx>"#,
-        title = &c.title
+        title = escape_xml(&c.title)
     );
 
     let mut file = File::create(&toc_path)?;
@@ -217,13 +463,14 @@ fn create_toc_ncx(
 /// Creates the content.opf file (package document)
 fn create_content_opf(
     c: &Comic,
+    uuid: &str,
     oebps_dir: &Path,
     cover_html_path: &Path,
     html_files: &[PathBuf],
     images: &[(ProcessedImage, String)],
+    has_nav: bool,
 ) -> Result<()> {
     let opf_path = oebps_dir.join("content.opf");
-    let uuid = Uuid::new_v4().to_string();
 
     // Build manifest items
     let mut manifest = String::new();
@@ -233,6 +480,14 @@ fn create_content_opf(
         .push_str(r#"    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>"#);
     manifest.push('\n');
 
+    // Add chapter navigation
+    if has_nav {
+        manifest.push_str(
+            r#"    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>"#,
+        );
+        manifest.push('\n');
+    }
+
     // Add cover HTML
     let cover_filename = cover_html_path.file_name().unwrap().to_string_lossy();
     manifest.push_str(&format!(
@@ -261,10 +516,10 @@ fn create_content_opf(
             .to_string_lossy()
             .to_lowercase();
 
-        let media_type = if extension == "png" {
-            "image/png"
-        } else {
-            "image/jpeg"
+        let media_type = match extension.as_str() {
+            "png" => "image/png",
+            "webp" => "image/webp",
+            _ => "image/jpeg",
         };
 
         // Special handling for the first image (cover)
@@ -310,7 +565,32 @@ fn create_content_opf(
         right_to_left = !right_to_left;
     }
 
+    // KOReader shows one page at a time, pairing them up leaves half the screen blank
+    let spread = match c.config.reader_profile {
+        ReaderProfile::KoReader => "none",
+        _ => "landscape",
+    };
     let (width, height) = c.config.device_dimensions();
+    let encoding = if c.config.record_encoding {
+        format!(
+            r#"
+            <meta name="comically:encoding" content="{}"/>"#,
+            c.config.image_format.encoding()
+        )
+    } else {
+        String::new()
+    };
+    let contributor = c
+        .config
+        .producer()
+        .map(|producer| {
+            format!(
+                r#"
+            <dc:contributor>{}</dc:contributor>"#,
+                escape_xml(&producer)
+            )
+        })
+        .unwrap_or_default();
 
     // Create the OPF content with page-progression-direction
     let opf_content = format!(
@@ -320,7 +600,7 @@ fn create_content_opf(
             <dc:title>{title}</dc:title>
             <dc:language>en-US</dc:language>
             <dc:identifier id="BookID">urn:uuid:{uuid}</dc:identifier>
-            <dc:creator>comically</dc:creator>
+            <dc:creator>comically</dc:creator>{contributor}
             <meta name="cover" content="cover-image"/>
             <meta name="fixed-layout" content="true"/>
             <meta name="original-resolution" content="{width}x{height}"/>
@@ -332,13 +612,13 @@ fn create_content_opf(
             <meta name="ke-border-width" content="0"/>
             <meta name="orientation-lock" content="none"/>
             <meta name="region-mag" content="true"/>
-            <meta property="rendition:spread">landscape</meta>
-            <meta property="rendition:layout">pre-paginated</meta>
+            <meta property="rendition:spread">{spread}</meta>
+            <meta property="rendition:layout">pre-paginated</meta>{encoding}
           </metadata>
           <manifest>{manifest}</manifest>
           <spine toc="ncx" page-progression-direction="{progression_direction}">{spine}</spine>
         </package>"###,
-        title = &c.title,
+        title = escape_xml(&c.title),
         writing_mode = if c.config.right_to_left {
             "horizontal-rl"
         } else {
@@ -354,6 +634,7 @@ fn create_content_opf(
 
 /// Creates the EPUB file by zipping the directory structure
 fn create_epub_file(
+    comic: &Comic,
     epub_dir: &Path,
     output_path: &Path,
     image_map: &[(ProcessedImage, String)],
@@ -362,9 +643,12 @@ fn create_epub_file(
     let writer = BufWriter::new(file);
     let mut zip = ZipWriter::new(writer);
 
-    let options_stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
-    let options_deflated =
-        SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut options = SimpleFileOptions::default();
+    if comic.config.deterministic {
+        options = options.last_modified_time(zip::DateTime::default());
+    }
+    let options_stored = options.compression_method(CompressionMethod::Stored);
+    let options_deflated = options.compression_method(CompressionMethod::Deflated);
 
     // Add mimetype first (must not be compressed)
     let mimetype_path = epub_dir.join("mimetype");
@@ -373,7 +657,11 @@ fn create_epub_file(
     zip.write_all(&mimetype_content)?;
 
     // add the rest of the files
-    for entry in WalkDir::new(epub_dir).into_iter().filter_map(|e| e.ok()) {
+    for entry in WalkDir::new(epub_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
         let path = entry.path();
 
         // Skip the mimetype file (already added) and the output file
@@ -392,7 +680,8 @@ fn create_epub_file(
         }
     }
 
-    // include all images
+    // include all images, stored since they're compressed already and deflating
+    // them again only slows packaging down
     for (image, rel_path) in image_map {
         let path = &image.path;
         let rel_path = format!("OEBPS/{}", rel_path);
@@ -406,3 +695,11 @@ fn create_epub_file(
     zip.finish()?;
     Ok(())
 }
+
+#[test]
+fn page_groups_keep_the_cover_alone() {
+    assert_eq!(page_groups(4, 1), vec![0..1, 1..2, 2..3, 3..4]);
+    assert_eq!(page_groups(6, 2), vec![0..1, 1..3, 3..5, 5..6]);
+    assert_eq!(page_groups(1, 3), vec![0..1]);
+    assert!(page_groups(0, 0).is_empty());
+}
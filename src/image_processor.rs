@@ -1,93 +1,642 @@
+use ab_glyph::{Font, FontVec, PxScale, ScaleFont};
 use anyhow::{Context, Result};
+use imageproc::drawing::{draw_text_mut, text_size};
 use imageproc::image::{
     imageops::{self, FilterType},
-    load_from_memory, ColorType, DynamicImage, GenericImageView, GrayImage, ImageBuffer, Luma,
-    Pixel, SubImage,
+    load_from_memory, ColorType, DynamicImage, GenericImageView, GrayImage, ImageBuffer,
+    ImageDecoder, ImageReader, Luma, Pixel, Rgb, RgbImage, Rgba, RgbaImage, SubImage,
 };
 use imageproc::stats::histogram;
-use rayon::iter::{ParallelBridge, ParallelIterator};
-use std::path::Path;
-use std::sync::mpsc;
+use rayon::iter::{
+    IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelBridge, ParallelIterator,
+};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::time::Duration;
 use webp::WebPMemory;
+use zune_jpeg::zune_core::{colorspace::ColorSpace, options::DecoderOptions};
+use zune_jpeg::JpegDecoder;
 
-use crate::comic::{ComicConfig, ImageFormat, PngCompression, ProcessedImage, SplitStrategy};
-use crate::comic_archive::ArchiveFile;
+use crate::comic::{
+    is_disk_full, CmykMode, ComicConfig, ComicStatus, ContactSheetConfig, ImageFormat,
+    JpegSubsampling, PageCanvas, PngCompression, ProcessedImage, ProgressEvent, ResizeFilter,
+    RotateMode, ScaleMode, SplitStrategy, WatermarkConfig, WatermarkPosition,
+};
+use crate::comic_archive::{natural_cmp, ArchiveFile, PageOrder, SortKey};
 use crate::Event;
 
+#[allow(clippy::too_many_arguments)]
 pub fn process_archive_images(
     archive: impl Iterator<Item = anyhow::Result<ArchiveFile>> + Send,
+    page_names: &[PathBuf],
     config: ComicConfig,
     output_dir: &Path,
     comic_id: usize,
     event_tx: &mpsc::Sender<Event>,
-) -> Result<Vec<ProcessedImage>> {
+    progress_step: usize,
+    workers: &Workers,
+) -> Result<(Vec<ProcessedImage>, Vec<String>)> {
     log::info!("Processing archive images");
 
-    let mut images = archive
-        .par_bridge()
-        .filter_map(|load| {
-            if let Err(e) = &load {
-                log::warn!("Failed to load image: {}", e);
+    // pages that couldn't be loaded and other issues, reported back as warnings
+    let warnings = Mutex::new(Vec::new());
+    let order = config.page_order.as_deref().map(PageOrder::new);
+    let sort_key = config
+        .sort_key_regex
+        .as_deref()
+        .map(SortKey::new)
+        .transpose()?;
+    // every entry name, for checking the page order against
+    let entries = Mutex::new(Vec::new());
+    // the first page write that ran out of space
+    let disk_full = Mutex::new(None);
+    // pages processed since the last progress update
+    let unreported = AtomicUsize::new(0);
+    let report = |count| {
+        if count > 0 {
+            let _ = event_tx.send(
+                ProgressEvent::ComicUpdate {
+                    id: comic_id,
+                    status: ComicStatus::ImageProcessed { count },
+                }
+                .into_event(),
+            );
+        }
+    };
+    // the entry whose pages sort first, reprocessed in color once every page is in
+    let cover = Mutex::new(None::<(PathBuf, ArchiveFile)>);
+    let watermark = config
+        .watermark
+        .as_ref()
+        .filter(|_| !config.debug_identity)
+        .map(Watermark::new)
+        .transpose()?;
+
+    let page_path = |archive_file: &ArchiveFile, ii: usize| {
+        let file = archive_file.parent().display();
+        let stem = archive_file.file_stem().to_string_lossy();
+        let extension = config.image_format.extension();
+        // pages are put in order by their path, so the rank goes first
+        let rank = order
+            .as_ref()
+            .map(|order| format!("{:05}_", order.rank(&archive_file.file_name)))
+            .unwrap_or_default();
+        let key = sort_key
+            .as_ref()
+            .map(|key| key.prefix(&archive_file.file_name))
+            .unwrap_or_default();
+        output_dir.join(format!("{rank}{key}{file}_{stem}_{ii}.{extension}",))
+    };
+
+    // pages outside split_ranges are processed as if split were None
+    let unsplit = ComicConfig {
+        split: SplitStrategy::None,
+        ..config.clone()
+    };
+    let page_config = |number: Option<usize>| match number {
+        Some(number)
+            if !config
+                .split_ranges
+                .iter()
+                .any(|range| range.contains(&number)) =>
+        {
+            &unsplit
+        }
+        _ => &config,
+    };
+
+    // loads, decodes, processes and saves a single page, `number` is its place in reading
+    // order when split_ranges needs it
+    let process_page = |mut archive_file: ArchiveFile,
+                        number: Option<usize>|
+     -> Vec<ProcessedImage> {
+        let page_config = page_config(number);
+        if order.is_some() {
+            entries.lock().unwrap().push(archive_file.file_name.clone());
+        }
+        if config.color_cover && !config.debug_identity {
+            let path = page_path(&archive_file, 0);
+            let mut cover = cover.lock().unwrap();
+            if cover.as_ref().is_none_or(|(cover_path, _)| {
+                natural_cmp(&path.to_string_lossy(), &cover_path.to_string_lossy()).is_lt()
+            }) {
+                *cover = Some((path, archive_file.clone()));
             }
-            load.ok()
-        })
-        .filter_map(|archive_file| {
-            let Ok(img) = load_from_memory(&archive_file.data) else {
-                log::warn!("Failed to load image: {}", archive_file.file_name.display());
-                return None;
-            };
+        }
 
-            Some((archive_file, process_image(img, &config)))
-        })
-        .flat_map(|(archive_file, images)| {
-            let result = images
-                .into_iter()
-                .enumerate()
-                .filter_map(|(ii, img)| {
-                    let path = {
-                        let file = archive_file.parent().display();
-                        let stem = archive_file.file_stem().to_string_lossy();
-                        let extension = config.image_format.extension();
-                        output_dir.join(format!("{file}_{stem}_{ii}.{extension}",))
-                    };
-                    let dimensions = img.dimensions();
-                    match save_image(&img, &path, &config.image_format) {
-                        Ok(_) => {
-                            log::trace!("Saved image: {}", path.display());
-                            Some(ProcessedImage { path, dimensions })
-                        }
-                        Err(e) => {
-                            log::warn!("Failed to save {}: {}", path.display(), e);
-                            None
-                        }
+        let exif = config
+            .preserve_metadata
+            .then(|| read_exif(&archive_file.data))
+            .flatten();
+
+        // only decoding is timed, processing hands work to the rayon pool this thread is part of
+        let data = if config.passthrough {
+            archive_file.data.clone()
+        } else {
+            std::mem::take(&mut archive_file.data)
+        };
+        let (cmyk_mode, color_profiles) = (config.cmyk_mode, config.color_profiles);
+        let loaded = with_timeout(config.page_timeout_secs, move || {
+            load_page(&data, cmyk_mode, color_profiles)
+        });
+
+        let img = match loaded {
+            Ok(img) => img,
+            Err(e) => {
+                log::warn!(
+                    "Failed to load image {}: {}",
+                    archive_file.file_name.display(),
+                    e
+                );
+                warnings.lock().unwrap().push(format!(
+                    "Skipped page {}: {}",
+                    archive_file.file_name.display(),
+                    e
+                ));
+                return Vec::new();
+            }
+        };
+
+        let original = page_config.rotate.dimensions(img.dimensions());
+        let passthrough = config.passthrough
+            && needs_no_changes(&img, &archive_file.data, &config, &archive_file.file_name);
+        let images = if passthrough {
+            vec![img]
+        } else {
+            archive_file.data = Vec::new();
+            let mut pages = process_image(img, page_config, &archive_file.file_name);
+            if let Some(watermark) = &watermark {
+                pages.iter_mut().for_each(|page| watermark.apply(page));
+            }
+            pages
+        };
+        let parts = images.len();
+        let result = images
+            .into_iter()
+            .enumerate()
+            .filter_map(|(ii, img)| {
+                let path = page_path(&archive_file, ii);
+                let mut transforms = if passthrough {
+                    vec!["passthrough".to_string()]
+                } else {
+                    rotate_transform(page_config.rotate)
+                        .into_iter()
+                        .chain(split_transforms(page_config, original, ii, parts))
+                        .collect()
+                };
+                if watermark.is_some() && !passthrough {
+                    transforms.push("watermarked".to_string());
+                }
+                let dimensions = img.dimensions();
+                let chapter = Some(archive_file.parent().display().to_string())
+                    .filter(|folder| !folder.is_empty());
+                let saved = if passthrough {
+                    write_page(&archive_file.data, &path, workers)
+                } else {
+                    save_image(&img, &path, &config, exif.as_deref(), workers)
+                };
+                let saved = saved.and_then(|_| {
+                    save_extra_codecs(&img, &path, output_dir, &config, exif.as_deref(), workers)
+                });
+                match saved {
+                    Ok(_) => {
+                        log::trace!("Saved image: {}", path.display());
+                        Some(ProcessedImage {
+                            path,
+                            dimensions,
+                            chapter,
+                            source: archive_file.file_name.clone(),
+                            transforms,
+                        })
                     }
-                })
-                .collect::<Vec<_>>();
+                    // every page after this one would fail too, the comic fails instead
+                    Err(e) if is_disk_full(&e) => {
+                        disk_full.lock().unwrap().get_or_insert(e);
+                        None
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to save {}: {}", path.display(), e);
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
 
-            // Send progress update for each successfully processed image
-            if !result.is_empty() {
-                use crate::comic::{ComicStatus, ProgressEvent};
-                let _ = event_tx.send(Event::Progress(ProgressEvent::ComicUpdate {
-                    id: comic_id,
-                    status: ComicStatus::ImageProcessed,
-                }));
+        // Send progress update for each successfully processed image, or each `progress_step`
+        if !result.is_empty() {
+            if unreported.fetch_add(1, Ordering::AcqRel) + 1 >= progress_step {
+                report(unreported.swap(0, Ordering::AcqRel));
+            }
+            if config.page_events {
+                let _ = event_tx.send(
+                    ProgressEvent::PageProcessed {
+                        id: comic_id,
+                        name: archive_file.file_name.display().to_string(),
+                        original,
+                        processed: result.iter().map(|image| image.dimensions).collect(),
+                    }
+                    .into_event(),
+                );
             }
+        }
 
-            result
-        })
-        .collect::<Vec<_>>();
+        result
+    };
+
+    // Pages are queued on the pool one task each, so the pages of every comic in the
+    // batch share its workers. Reading stays on this thread and waits, helping out with
+    // queued pages, while enough are queued to keep every worker busy
+    let images = Mutex::new(Vec::new());
+    let queued = AtomicUsize::new(0);
+    let out_of_space = || disk_full.lock().unwrap().is_some();
+    // split_ranges numbers pages in the order the processed pages are sorted into at the end,
+    // ranked from the entry names so pages are still read one at a time
+    let numbers: HashMap<PathBuf, usize> = if config.split_ranges.is_empty() {
+        HashMap::new()
+    } else {
+        let mut names: Vec<_> = page_names
+            .iter()
+            .map(|name| {
+                let file = ArchiveFile {
+                    file_name: name.clone(),
+                    data: Vec::new(),
+                };
+                (page_path(&file, 0).to_string_lossy().into_owned(), name)
+            })
+            .collect();
+        names.sort_by(|(a, _), (b, _)| natural_cmp(a, b));
+        names
+            .into_iter()
+            .enumerate()
+            .map(|(i, (_, name))| (name.clone(), i + 1))
+            .collect()
+    };
+    let archive = archive.map(|load| {
+        let number = load
+            .as_ref()
+            .ok()
+            .and_then(|file| numbers.get(&file.file_name).copied());
+        (number, load)
+    });
+    workers.install(|| {
+        let max_queued = rayon::current_num_threads() * 2;
+        rayon::scope(|scope| {
+            for (number, load) in archive.take_while(|_| !out_of_space()) {
+                let archive_file = match load {
+                    Ok(archive_file) => archive_file,
+                    Err(e) => {
+                        log::warn!("Failed to load image: {}", e);
+                        continue;
+                    }
+                };
+                while queued.load(Ordering::Acquire) >= max_queued {
+                    if rayon::yield_now() != Some(rayon::Yield::Executed) {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                }
+                queued.fetch_add(1, Ordering::AcqRel);
+                let (images, queued, process_page) = (&images, &queued, &process_page);
+                scope.spawn(move |_| {
+                    let pages = process_page(archive_file, number);
+                    images.lock().unwrap().extend(pages);
+                    queued.fetch_sub(1, Ordering::AcqRel);
+                });
+            }
+        });
+    });
+    report(unreported.into_inner());
+    if let Some(e) = disk_full.into_inner().unwrap() {
+        return Err(e);
+    }
+    let mut images = images.into_inner().unwrap();
 
-    images.sort_by(|a, b| a.path.as_os_str().cmp(b.path.as_os_str()));
+    images.sort_by(|a, b| natural_cmp(&a.path.to_string_lossy(), &b.path.to_string_lossy()));
     images.dedup_by_key(|i| i.path.as_os_str().to_owned());
 
-    Ok(images)
+    if let Some((_, cover)) = cover.into_inner().unwrap() {
+        let paths = (0..).map(|ii| page_path(&cover, ii));
+        // the cover sorts first, so it's page 1
+        let number = (!config.split_ranges.is_empty()).then_some(1);
+        color_cover(
+            &mut images,
+            &cover,
+            paths,
+            output_dir,
+            page_config(number),
+            watermark.as_ref(),
+            workers,
+        )?;
+    }
+
+    if config.page_canvas == PageCanvas::Largest && !config.debug_identity {
+        pad_to_largest(&mut images, output_dir, &config, workers)?;
+    }
+
+    let mut warnings = warnings.into_inner().unwrap();
+    warnings.sort();
+    if let Some(order) = &order {
+        let entries = entries.into_inner().unwrap();
+        for name in order.missing(&entries) {
+            warnings.push(format!(
+                "Page order lists {name}, which isn't in the archive"
+            ));
+        }
+    }
+
+    Ok((images, warnings))
+}
+
+/// Replace the cover's grayscale pages with color ones, the rest of the comic stays grayscale.
+///
+/// Pages are processed in parallel, so which one is the cover is only known once they're all in.
+fn color_cover(
+    images: &mut [ProcessedImage],
+    cover: &ArchiveFile,
+    paths: impl Iterator<Item = PathBuf>,
+    processed_dir: &Path,
+    config: &ComicConfig,
+    watermark: Option<&Watermark>,
+    workers: &Workers,
+) -> Result<()> {
+    // a cover that failed to load was already reported as skipped
+    let Ok(img) = load_page(&cover.data, config.cmyk_mode, config.color_profiles) else {
+        return Ok(());
+    };
+    let exif = config
+        .preserve_metadata
+        .then(|| read_exif(&cover.data))
+        .flatten();
+
+    let pages = process_image_with(img, config, |img| {
+        process_color_image(img.into_rgb8(), config)
+    });
+    for (mut page, path) in pages.into_iter().zip(paths) {
+        if let Some(image) = images.iter_mut().find(|image| image.path == path) {
+            if let Some(watermark) = watermark {
+                watermark.apply(&mut page);
+            }
+            save_image(&page, &path, config, exif.as_deref(), workers)?;
+            save_extra_codecs(
+                &page,
+                &path,
+                processed_dir,
+                config,
+                exif.as_deref(),
+                workers,
+            )?;
+            image.dimensions = page.dimensions();
+            image.transforms.push("color cover".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Pick the highest JPEG quality whose pages should add up to about `target_bytes`.
+///
+/// Encodes an evenly spaced sample of pages at each candidate quality and scales the
+/// sample up to the whole comic, so the real pass only has to encode every page once.
+/// Never goes below quality 10, a smaller target just gets as close as it can.
+pub fn search_jpeg_quality(
+    archive: impl Iterator<Item = anyhow::Result<ArchiveFile>> + Send,
+    config: &ComicConfig,
+    num_images: usize,
+    target_bytes: u64,
+) -> Result<u8> {
+    const SAMPLE_PAGES: usize = 8;
+    const MIN_QUALITY: u8 = 10;
+
+    let samples: Vec<Vec<DynamicImage>> = archive
+        .step_by(num_images.div_ceil(SAMPLE_PAGES).max(1))
+        .filter_map(|load| load.ok())
+        .par_bridge()
+        .filter_map(|file| {
+            let img = load_page(&file.data, config.cmyk_mode, config.color_profiles).ok()?;
+            Some(process_image(img, config, &file.file_name))
+        })
+        .collect();
+    if samples.is_empty() {
+        anyhow::bail!("No readable pages to estimate the output size from");
+    }
+
+    let projected_size = |quality| -> Result<u64> {
+        let config = ComicConfig {
+            image_format: ImageFormat::Jpeg { quality },
+            ..config.clone()
+        };
+        let sample_bytes = samples
+            .par_iter()
+            .flatten()
+            .map(|img| encode_image(img, &config, None).map(|data| data.len() as u64))
+            .sum::<Result<u64>>()?;
+        Ok(sample_bytes * num_images as u64 / samples.len() as u64)
+    };
+
+    // size grows with quality, find the highest one that still fits
+    let (mut low, mut high) = (MIN_QUALITY, 100);
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        if projected_size(mid)? <= target_bytes {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    log::debug!(
+        "Quality {} projects to {} bytes, target is {} bytes",
+        low,
+        projected_size(low)?,
+        target_bytes
+    );
+    Ok(low)
+}
+
+/// Decodes given up on by `with_timeout` that are still running in the background
+static ABANDONED_DECODES: AtomicUsize = AtomicUsize::new(0);
+
+/// Run `f` on its own thread and give up on it after `timeout_secs`.
+///
+/// A thread can't be stopped from the outside, so an abandoned one keeps running in the background.
+/// Once as many are abandoned as there are cores, pages are decoded here without a timeout
+/// rather than piling up more of them.
+fn with_timeout<T, F>(timeout_secs: Option<u64>, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let Some(timeout_secs) = timeout_secs else {
+        return f();
+    };
+    let max_abandoned = std::thread::available_parallelism().map_or(1, |n| n.get());
+    if ABANDONED_DECODES.load(Ordering::Acquire) >= max_abandoned {
+        return f();
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let abandoned = std::sync::Arc::new(Mutex::new(false));
+    let flag = abandoned.clone();
+    std::thread::spawn(move || {
+        let result = f();
+        let abandoned = flag.lock().unwrap();
+        if *abandoned {
+            ABANDONED_DECODES.fetch_sub(1, Ordering::AcqRel);
+        } else {
+            let _ = tx.send(result);
+        }
+    });
+    match rx.recv_timeout(Duration::from_secs(timeout_secs)) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            let mut abandoned = abandoned.lock().unwrap();
+            // it may have finished while the lock was being taken
+            if let Ok(result) = rx.try_recv() {
+                return result;
+            }
+            *abandoned = true;
+            ABANDONED_DECODES.fetch_add(1, Ordering::AcqRel);
+            anyhow::bail!("Decoding took longer than {}s", timeout_secs)
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("Decoding panicked"),
+    }
+}
+
+/// A page already sized for the device in the output format, with no other
+/// transform that would change it. `passthrough` writes these bytes as they are,
+/// so pages with EXIF are only kept when `preserve_metadata` would keep it anyway
+fn needs_no_changes(img: &DynamicImage, data: &[u8], config: &ComicConfig, name: &Path) -> bool {
+    use imageproc::image::ImageFormat as Codec;
+
+    let same_format = matches!(
+        (imageproc::image::guess_format(data), config.image_format),
+        (Ok(Codec::Jpeg), ImageFormat::Jpeg { .. })
+            | (Ok(Codec::Png), ImageFormat::Png { .. })
+            | (Ok(Codec::WebP), ImageFormat::WebP { .. })
+    );
+    // color pages are only kept with auto_grayscale, everything else is converted
+    let keeps_color = match img {
+        DynamicImage::ImageLuma8(_) => true,
+        _ => config.auto_grayscale && is_color_page(img, name),
+    };
+
+    same_format
+        && !config.debug_identity
+        && img.dimensions() == config.device_dimensions()
+        && config.posterize_levels.is_none()
+        && config.rotate == RotateMode::None
+        && config.dimension_multiple.is_none()
+        && !needs_inverting(img, config)
+        && config.brightness == 0
+        && !applies_gamma(config.gamma)
+        && config.channel_gamma.is_none()
+        && keeps_contrast(img)
+        && (config.preserve_metadata || read_exif(data).is_none())
+        && config.dpi.is_none()
+        && config.margin_color.is_none()
+        // every page is padded to the comic's largest one
+        && config.page_canvas != PageCanvas::Largest
+        && !config.color_profiles
+        && config.watermark.is_none()
+        && keeps_color
+        && config.crop_margins.is_none()
+        && !(config.auto_crop && auto_crop(&img.to_luma8()).is_some())
+}
+
+/// How `rotate` turned the page, for the page manifest
+fn rotate_transform(rotate: RotateMode) -> Option<String> {
+    let transform = match rotate {
+        RotateMode::None => return None,
+        RotateMode::Cw90 => "turned clockwise",
+        RotateMode::Ccw90 => "turned counterclockwise",
+        RotateMode::Rotate180 => "turned upside down",
+    };
+    Some(transform.to_string())
+}
+
+/// How `process_image_view` got part `ii` of `parts` out of a page, for the page manifest
+fn split_transforms(
+    config: &ComicConfig,
+    (width, height): (u32, u32),
+    ii: usize,
+    parts: usize,
+) -> Vec<String> {
+    if width <= height || config.debug_identity {
+        return Vec::new();
+    }
+    // the panel in reading order, and how many the page was split into
+    let (panel, panels) = match (config.split, ii, parts) {
+        (SplitStrategy::Rotate, _, 1) => return vec!["rotated".to_string()],
+        (SplitStrategy::RotateAndSplit, 0, 3..) => return vec!["rotated".to_string()],
+        (SplitStrategy::Split, _, 2..) => (ii, parts),
+        (SplitStrategy::RotateAndSplit, _, 3..) => (ii - 1, parts - 1),
+        _ => return Vec::new(),
+    };
+    let from_left = if config.right_to_left {
+        panels - 1 - panel
+    } else {
+        panel
+    };
+    let transform = match (panels, from_left) {
+        (2, 0) => "left half".to_string(),
+        (2, _) => "right half".to_string(),
+        _ => format!("panel {} of {} from the left", from_left + 1, panels),
+    };
+    vec![transform]
 }
 
 /// Process a single image file with Kindle-optimized transformations
-pub fn process_image(img: DynamicImage, config: &ComicConfig) -> Vec<DynamicImage> {
-    let img = transform(img.into_luma8(), config.brightness, config.gamma);
+pub fn process_image(img: DynamicImage, config: &ComicConfig, name: &Path) -> Vec<DynamicImage> {
+    process_image_with(img, config, |img| {
+        if config.auto_grayscale && is_color_page(&img, name) {
+            process_color_image(img.into_rgb8(), config)
+        } else if let Some(gammas) = config.channel_gamma.filter(|_| img.color().has_color()) {
+            // balance the channels while there still are some, the single gamma is replaced
+            let balanced = channel_gamma(img.into_rgb8(), gammas);
+            process_gray_image(imageops::grayscale(&balanced), config, 1.0)
+        } else {
+            process_gray_image(img.into_luma8(), config, config.gamma)
+        }
+    })
+}
+
+/// The steps every page goes through around `convert`, which picks between color and
+/// grayscale and makes the device-sized pages. The color cover only swaps `convert`
+fn process_image_with(
+    mut img: DynamicImage,
+    config: &ComicConfig,
+    convert: impl FnOnce(DynamicImage) -> Vec<DynamicImage>,
+) -> Vec<DynamicImage> {
+    if config.debug_identity {
+        return vec![img];
+    }
+    img = rotate_page(img, config.rotate);
+    if needs_inverting(&img, config) {
+        img.invert();
+    }
+
+    let mut pages = convert(img);
+
+    // last, so resizing can't bring back in-between values
+    if let Some(levels) = config.posterize_levels {
+        for page in &mut pages {
+            posterize(page, levels);
+        }
+    }
+    pages
+}
+
+fn process_gray_image(img: GrayImage, config: &ComicConfig, gamma: f32) -> Vec<DynamicImage> {
+    let img = transform(img, config.brightness, gamma);
 
-    let gray_images = if config.auto_crop {
+    let gray_images = if let Some(margins) = config.crop_margins {
+        match crop_to_margins(img.dimensions(), margins) {
+            Some((x, y, width, height)) => {
+                process_image_view(&*imageops::crop_imm(&img, x, y, width, height), config)
+            }
+            None => process_image_view(&img, config),
+        }
+    } else if config.auto_crop {
         if let Some(cropped) = auto_crop(&img) {
             process_image_view(&*cropped, config)
         } else {
@@ -104,95 +653,125 @@ pub fn process_image(img: DynamicImage, config: &ComicConfig) -> Vec<DynamicImag
         .collect()
 }
 
-fn process_image_view<I>(img: &I, c: &ComicConfig) -> Vec<GrayImage>
+fn process_color_image(img: RgbImage, config: &ComicConfig) -> Vec<DynamicImage> {
+    let gammas = config.channel_gamma.unwrap_or([config.gamma; 3]);
+    let img = transform_color(img, config.brightness, gammas);
+
+    // crop bounds come from the grayscale version so both paths crop the same way
+    let crop = match config.crop_margins {
+        Some(margins) => crop_to_margins(img.dimensions(), margins),
+        None => config
+            .auto_crop
+            .then(|| imageops::grayscale(&img))
+            .and_then(|gray| {
+                auto_crop(&gray).map(|cropped| {
+                    let (x, y) = cropped.offsets();
+                    let (width, height) = cropped.dimensions();
+                    (x, y, width, height)
+                })
+            }),
+    };
+
+    let color_images = match crop {
+        Some((x, y, width, height)) => {
+            process_image_view(&*imageops::crop_imm(&img, x, y, width, height), config)
+        }
+        None => process_image_view(&img, config),
+    };
+
+    color_images
+        .into_iter()
+        .map(DynamicImage::ImageRgb8)
+        .collect()
+}
+
+fn process_image_view<I>(img: &I, c: &ComicConfig) -> Vec<ImageBuffer<I::Pixel, Vec<u8>>>
 where
-    I: GenericImageView<Pixel = Luma<u8>> + Send + Sync,
+    I: GenericImageView + Send + Sync,
+    I::Pixel: Pixel<Subpixel = u8> + Send + Sync + 'static,
 {
-    let target = c.device_dimensions();
     let (width, height) = img.dimensions();
     let is_double_page = width > height;
 
-    let margin = c.margin_color.map(|color| Luma([color]));
+    // padding to the device needs a color even without margins, white blends with the page
+    let margin_color = match c.page_canvas {
+        PageCanvas::Device => Some(c.margin_color.unwrap_or(255)),
+        PageCanvas::Natural | PageCanvas::Largest => c.margin_color,
+    };
+    // the same gray level in every channel
+    let margin = margin_color.map(|color| {
+        *<I::Pixel as Pixel>::from_slice(&[color; 4][..I::Pixel::CHANNEL_COUNT as usize])
+    });
+
+    // the panels of a wide page resized, in reading order
+    let panels = || {
+        let mut panels: Vec<_> = split_panels(img, split_parts(c, width, height))
+            .par_iter()
+            .map(|panel| resize_image(&**panel, c, margin))
+            .collect();
+        if c.right_to_left {
+            panels.reverse();
+        }
+        panels
+    };
 
     match c.split {
         SplitStrategy::None => {
             // Just resize, no splitting or rotation
-            vec![resize_image(img, target, margin)]
+            vec![resize_image(img, c, margin)]
         }
         SplitStrategy::Split => {
             if is_double_page {
-                // Split double pages
-                let (left, right) = split_double_pages(img);
-
-                let (left_resized, right_resized) = rayon::join(
-                    || resize_image(&*left, target, margin),
-                    || resize_image(&*right, target, margin),
-                );
-
-                // Determine order based on right_to_left setting
-                let (first, second) = if c.right_to_left {
-                    (right_resized, left_resized)
-                } else {
-                    (left_resized, right_resized)
-                };
-
-                vec![first, second]
+                panels()
             } else {
-                vec![resize_image(img, target, margin)]
+                vec![resize_image(img, c, margin)]
             }
         }
         SplitStrategy::Rotate => {
             if is_double_page {
                 let rotated = rotate_image_90(img, c.right_to_left);
-                vec![resize_image(&rotated, target, margin)]
+                vec![resize_image(&rotated, c, margin)]
             } else {
-                vec![resize_image(img, target, margin)]
+                vec![resize_image(img, c, margin)]
             }
         }
         SplitStrategy::RotateAndSplit => {
             if is_double_page {
-                let (left, right) = split_double_pages(img);
-
-                let mut rotated_resized = None;
-                let mut left_resized = None;
-                let mut right_resized = None;
-
-                rayon::scope(|s| {
-                    s.spawn(|_| {
-                        let rotated = rotate_image_90(img, c.right_to_left);
-                        rotated_resized = Some(resize_image(&rotated, target, margin));
-                    });
-                    s.spawn(|_| {
-                        left_resized = Some(resize_image(&*left, target, margin));
-                    });
-                    s.spawn(|_| {
-                        right_resized = Some(resize_image(&*right, target, margin));
-                    });
-                });
-
-                let rotated_resized = rotated_resized.unwrap();
-                let left_resized = left_resized.unwrap();
-                let right_resized = right_resized.unwrap();
-
-                let (first, second) = if c.right_to_left {
-                    (right_resized, left_resized)
-                } else {
-                    (left_resized, right_resized)
-                };
-
-                vec![rotated_resized, first, second]
+                let (rotated, panels) = rayon::join(
+                    || resize_image(&rotate_image_90(img, c.right_to_left), c, margin),
+                    panels,
+                );
+                std::iter::once(rotated).chain(panels).collect()
             } else {
-                vec![resize_image(img, target, margin)]
+                vec![resize_image(img, c, margin)]
             }
         }
     }
 }
 
+/// Whether `gamma` changes a page, values this close to 1.0 are left out
+fn applies_gamma(gamma: f32) -> bool {
+    (gamma.clamp(0.1, 3.0) - 1.0).abs() > 0.01
+}
+
+/// Whether autocontrast leaves the page as it is, its values already span black to white
+fn keeps_contrast(img: &DynamicImage) -> bool {
+    let values = match img {
+        DynamicImage::ImageLuma8(gray) => gray.as_raw(),
+        DynamicImage::ImageRgb8(rgb) => rgb.as_raw(),
+        _ => return false,
+    };
+    let (min, max) = values.iter().fold((u8::MAX, u8::MIN), |(min, max), &v| {
+        (min.min(v), max.max(v))
+    });
+    max <= min || (min == 0 && max == 255)
+}
+
 /// gamma - 0.1 to 3.0, where 1.0 = no change, <1 = brighter, >1 = more contrast
 fn transform(mut img: GrayImage, brightness: i32, gamma: f32) -> GrayImage {
-    let gamma = gamma.clamp(0.1, 3.0);
     // only apply gamma if it's not 1.0
-    if (gamma - 1.0).abs() > 0.01 {
+    if applies_gamma(gamma) {
+        let gamma = gamma.clamp(0.1, 3.0);
         imageproc::map::map_colors_mut(&mut img, |pixel| {
             let normalized = pixel[0] as f32 / 255.0;
             let corrected = normalized.powf(gamma);
@@ -228,21 +807,227 @@ fn transform(mut img: GrayImage, brightness: i32, gamma: f32) -> GrayImage {
     img
 }
 
-fn split_double_pages<I: GenericImageView>(img: &I) -> (SubImage<&I>, SubImage<&I>) {
+/// Round every channel to `levels` evenly spaced values, black and white included.
+/// Unlike dithering the rounding error is dropped, so flat areas compress very well
+fn posterize(img: &mut DynamicImage, levels: u8) {
+    let steps = levels.max(2) as u32 - 1;
+    let table: [u8; 256] = std::array::from_fn(|value| {
+        let level = (value as u32 * steps + 127) / 255;
+        ((level * 255 + steps / 2) / steps) as u8
+    });
+
+    match img {
+        DynamicImage::ImageLuma8(buffer) => buffer.iter_mut().for_each(|v| *v = table[*v as usize]),
+        DynamicImage::ImageRgb8(buffer) => buffer.iter_mut().for_each(|v| *v = table[*v as usize]),
+        _ => log::warn!("Posterize skipped, unexpected color type {:?}", img.color()),
+    }
+}
+
+/// Gamma for each of R, G and B, same range as `transform`.
+/// Lifting or deepening one channel evens out yellowed or blue-tinted scans
+fn channel_gamma(mut img: RgbImage, gammas: [f32; 3]) -> RgbImage {
+    if !gammas.into_iter().any(applies_gamma) {
+        return img;
+    }
+    let gammas = gammas.map(|gamma| gamma.clamp(0.1, 3.0));
+
+    let curves = gammas.map(|gamma| -> [u8; 256] {
+        std::array::from_fn(|value| {
+            let corrected = (value as f32 / 255.0).powf(gamma);
+            (corrected * 255.0).round().clamp(0.0, 255.0) as u8
+        })
+    });
+    for pixel in img.pixels_mut() {
+        for (value, curve) in pixel.0.iter_mut().zip(&curves) {
+            *value = curve[*value as usize];
+        }
+    }
+    img
+}
+
+/// Color counterpart of `transform`, with a gamma per channel
+fn transform_color(img: RgbImage, brightness: i32, gammas: [f32; 3]) -> RgbImage {
+    let mut img = channel_gamma(img, gammas);
+
+    // Autocontrast over all channels together so hues don't shift
+    let (min, max) = img.iter().fold((u8::MAX, u8::MIN), |(min, max), &v| {
+        (min.min(v), max.max(v))
+    });
+
+    if max > min {
+        let range = (max - min) as u32;
+        img.iter_mut()
+            .for_each(|value| *value = ((*value - min) as u32 * 255 / range) as u8);
+    }
+
+    if brightness != 0 {
+        imageops::colorops::brighten_in_place(&mut img, brightness);
+    }
+
+    img
+}
+
+fn needs_inverting(img: &DynamicImage, config: &ComicConfig) -> bool {
+    config.invert || (config.auto_invert && looks_inverted(&img.to_luma8()))
+}
+
+// Pages brighter than this on average are taken as normal, whatever their lines look like
+const NEGATIVE_MAX_MEAN: f64 = 100.0;
+// The page's outer strip has to be at least this dark, paper margins are light
+const NEGATIVE_MAX_BORDER: f64 = 64.0;
+
+/// A negative is dark overall with a dark border, and its lines are the light parts.
+/// Lines are thin, so light runs along a row are much shorter than the dark ones
+/// in between, which tells a negative apart from a dark but normal page
+fn looks_inverted(img: &GrayImage) -> bool {
+    let (width, height) = img.dimensions();
+    if width < 8 || height < 8 {
+        return false;
+    }
+
+    let mean = img.iter().map(|&v| v as f64).sum::<f64>() / (width * height) as f64;
+    if mean > NEGATIVE_MAX_MEAN {
+        return false;
+    }
+
+    let band = (width.min(height) / 50).max(1);
+    let (mut border_sum, mut border_count) = (0.0, 0u64);
+    for (x, y, pixel) in img.enumerate_pixels() {
+        if x < band || y < band || x >= width - band || y >= height - band {
+            border_sum += pixel[0] as f64;
+            border_count += 1;
+        }
+    }
+    if border_sum / border_count as f64 > NEGATIVE_MAX_BORDER {
+        return false;
+    }
+
+    // average run length of light and dark pixels, every 4th row is plenty
+    let (mut light, mut dark) = ((0u64, 0u64), (0u64, 0u64));
+    for y in (0..height).step_by(4) {
+        let mut run = 0;
+        let mut is_light = img.get_pixel(0, y)[0] >= 128;
+        for x in 0..width {
+            let pixel_light = img.get_pixel(x, y)[0] >= 128;
+            if pixel_light != is_light {
+                let runs = if is_light { &mut light } else { &mut dark };
+                runs.0 += run;
+                runs.1 += 1;
+                run = 0;
+                is_light = pixel_light;
+            }
+            run += 1;
+        }
+    }
+    if light.1 == 0 {
+        return false;
+    }
+    let average = |(total, count): (u64, u64)| total as f64 / count.max(1) as f64;
+    average(light) * 3.0 < average(dark)
+}
+
+// Chroma further than this from the page's average tint counts as color
+const COLOR_DEVIATION: i32 = 24;
+// Share of pixels that need to be colorful to keep a page in color
+const COLOR_PAGE_RATIO: f32 = 0.05;
+// An average tint stronger than this is the page itself being colored, not the paper
+const COLOR_TINT: i32 = 48;
+
+/// Whether a page has enough color to keep, rather than a tint from paper or scanning
+fn is_color_page(img: &DynamicImage, name: &Path) -> bool {
+    if !img.color().has_color() {
+        return false;
+    }
+
+    // blue and red difference from luma, zero for neutral grays
+    let chroma: Vec<(i32, i32)> = img
+        .to_rgb8()
+        .pixels()
+        .map(|pixel| {
+            let [r, g, b] = pixel.0.map(i32::from);
+            let y = (r * 299 + g * 587 + b * 114) / 1000;
+            (b - y, r - y)
+        })
+        .collect();
+
+    let count = chroma.len().max(1) as i32;
+    let (cb_sum, cr_sum) = chroma.iter().fold((0, 0), |(cb_sum, cr_sum), (cb, cr)| {
+        (cb_sum + cb, cr_sum + cr)
+    });
+    let tint = (cb_sum / count, cr_sum / count);
+
+    let colorful = chroma
+        .iter()
+        .filter(|(cb, cr)| (cb - tint.0).abs().max((cr - tint.1).abs()) > COLOR_DEVIATION)
+        .count();
+    let ratio = colorful as f32 / count as f32;
+
+    let is_color = ratio > COLOR_PAGE_RATIO || tint.0.abs().max(tint.1.abs()) > COLOR_TINT;
+    log::debug!(
+        "{}: {:.1}% colorful pixels, tint {:?}, {}",
+        name.display(),
+        ratio * 100.0,
+        tint,
+        if is_color {
+            "keeping color"
+        } else {
+            "converting to grayscale"
+        }
+    );
+    is_color
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Panels a wide page is split into, 2 unless `panorama_splits` has a larger count for
+/// its aspect ratio
+fn split_parts(c: &ComicConfig, width: u32, height: u32) -> usize {
+    let aspect = width as f32 / height as f32;
+    c.panorama_splits
+        .iter()
+        .filter(|(min_aspect, _)| aspect >= *min_aspect)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map_or(2, |(_, parts)| (*parts).max(2))
+}
+
+/// `parts` side by side panels of equal width, left to right
+fn split_panels<I: GenericImageView>(img: &I, parts: usize) -> Vec<SubImage<&I>> {
     let (width, height) = img.dimensions();
+    // a panel is at least a column wide
+    let parts = parts.min(width as usize);
+    let edge = |i: usize| (width as u64 * i as u64 / parts as u64) as u32;
 
-    let left = imageops::crop_imm(img, 0, 0, width / 2, height);
-    let right = imageops::crop_imm(img, width / 2, 0, width / 2, height);
+    (0..parts)
+        .map(|i| imageops::crop_imm(img, edge(i), 0, edge(i + 1) - edge(i), height))
+        .collect()
+}
 
-    (left, right)
+fn rotate_page(img: DynamicImage, rotate: RotateMode) -> DynamicImage {
+    match rotate {
+        RotateMode::None => img,
+        RotateMode::Cw90 => img.rotate90(),
+        RotateMode::Ccw90 => img.rotate270(),
+        RotateMode::Rotate180 => img.rotate180(),
+    }
 }
 
-fn rotate_image_90<I>(img: &I, clockwise: bool) -> GrayImage
+fn rotate_image_90<I>(img: &I, clockwise: bool) -> ImageBuffer<I::Pixel, Vec<u8>>
 where
-    I: GenericImageView<Pixel = Luma<u8>>,
+    I: GenericImageView,
+    I::Pixel: Pixel<Subpixel = u8>,
 {
     let (width, height) = img.dimensions();
-    let mut rotated = GrayImage::new(height, width);
+    let mut rotated = ImageBuffer::new(height, width);
 
     for y in 0..height {
         for x in 0..width {
@@ -268,64 +1053,17 @@ const SAFETY_MARGIN: u32 = 2;
 /// Auto-crop white margins from all sides of the image
 fn auto_crop(img: &GrayImage) -> Option<SubImage<&GrayImage>> {
     let (width, height) = img.dimensions();
+    let [mut left_margin, mut top_margin, mut right_margin, mut bottom_margin] =
+        content_bounds(img)?;
 
-    // Left margin: scan from left to right
-    let mut left_margin = 0;
-    'left: for x in 0..width {
-        for y in 0..height {
-            if img.get_pixel(x, y)[0] < WHITE_THRESHOLD && is_not_noise(img, x, y) {
-                left_margin = x;
-                break 'left;
-            }
-        }
-    }
-
-    // Right margin: scan from right to left
-    let mut right_margin = width - 1;
-    'right: for x in (0..width).rev() {
-        for y in 0..height {
-            if img.get_pixel(x, y)[0] < WHITE_THRESHOLD && is_not_noise(img, x, y) {
-                right_margin = x;
-                break 'right;
-            }
-        }
-    }
+    // Apply safety margin
+    left_margin = left_margin.saturating_sub(SAFETY_MARGIN);
+    right_margin = (right_margin + SAFETY_MARGIN).min(width - 1);
+    top_margin = top_margin.saturating_sub(SAFETY_MARGIN);
+    bottom_margin = (bottom_margin + SAFETY_MARGIN).min(height - 1);
 
-    // Top margin: scan from top to bottom
-    let mut top_margin = 0;
-    'top: for y in 0..height {
-        for x in 0..width {
-            if img.get_pixel(x, y)[0] < WHITE_THRESHOLD && is_not_noise(img, x, y) {
-                top_margin = y;
-                break 'top;
-            }
-        }
-    }
-
-    // Bottom margin: scan from bottom to top
-    let mut bottom_margin = height - 1;
-    'bottom: for y in (0..height).rev() {
-        for x in 0..width {
-            if img.get_pixel(x, y)[0] < WHITE_THRESHOLD && is_not_noise(img, x, y) {
-                bottom_margin = y;
-                break 'bottom;
-            }
-        }
-    }
-
-    // If we didn't find any content, return None
-    if left_margin >= right_margin || top_margin >= bottom_margin {
-        return None;
-    }
-
-    // Apply safety margin
-    left_margin = left_margin.saturating_sub(SAFETY_MARGIN);
-    right_margin = (right_margin + SAFETY_MARGIN).min(width - 1);
-    top_margin = top_margin.saturating_sub(SAFETY_MARGIN);
-    bottom_margin = (bottom_margin + SAFETY_MARGIN).min(height - 1);
-
-    let crop_width = right_margin.saturating_sub(left_margin).saturating_add(1);
-    let crop_height = bottom_margin.saturating_sub(top_margin).saturating_add(1);
+    let crop_width = right_margin.saturating_sub(left_margin).saturating_add(1);
+    let crop_height = bottom_margin.saturating_sub(top_margin).saturating_add(1);
 
     let left_margin_size = left_margin;
     let right_margin_size = width.saturating_sub(right_margin).saturating_sub(1);
@@ -355,6 +1093,118 @@ fn auto_crop(img: &GrayImage) -> Option<SubImage<&GrayImage>> {
     None
 }
 
+/// The region left once `margins` are cut from a page, `None` when they'd leave nothing
+fn crop_to_margins(
+    (width, height): (u32, u32),
+    [left, top, right, bottom]: [u32; 4],
+) -> Option<(u32, u32, u32, u32)> {
+    let crop_width = width.checked_sub(left)?.checked_sub(right)?;
+    let crop_height = height.checked_sub(top)?.checked_sub(bottom)?;
+    (crop_width > 0 && crop_height > 0 && (crop_width, crop_height) != (width, height)).then_some((
+        left,
+        top,
+        crop_width,
+        crop_height,
+    ))
+}
+
+/// The margins auto_crop would cut from a page, as left, top, right and bottom.
+/// `None` for a blank page, which has no content to keep in view
+fn page_margins(img: &GrayImage) -> Option<[u32; 4]> {
+    content_bounds(img)?;
+    let Some(cropped) = auto_crop(img) else {
+        return Some([0; 4]);
+    };
+    let (width, height) = img.dimensions();
+    let (x, y) = cropped.offsets();
+    let (crop_width, crop_height) = cropped.dimensions();
+    Some([x, y, width - x - crop_width, height - y - crop_height])
+}
+
+/// Margins every page can lose without cutting into its content, for `shared_crop`.
+/// Each side is the smallest one found on the pages, `None` when all of them are blank
+pub fn shared_crop_margins(
+    archive: impl Iterator<Item = anyhow::Result<ArchiveFile>> + Send,
+    config: &ComicConfig,
+) -> Option<[u32; 4]> {
+    archive
+        .filter_map(|load| load.ok())
+        .par_bridge()
+        .filter_map(|file| {
+            let img = load_page(&file.data, config.cmyk_mode, config.color_profiles).ok()?;
+            let mut img = rotate_page(img, config.rotate);
+            if needs_inverting(&img, config) {
+                img.invert();
+            }
+            page_margins(&transform(
+                img.into_luma8(),
+                config.brightness,
+                config.gamma,
+            ))
+        })
+        .reduce_with(|a, b| std::array::from_fn(|i| a[i].min(b[i])))
+}
+
+/// The first and last columns and rows with content, as left, top, right and bottom.
+/// `None` for a blank page
+fn content_bounds(img: &GrayImage) -> Option<[u32; 4]> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    // Left margin: scan from left to right, a page without any is blank
+    let mut left_margin = None;
+    'left: for x in 0..width {
+        for y in 0..height {
+            if img.get_pixel(x, y)[0] < WHITE_THRESHOLD && is_not_noise(img, x, y) {
+                left_margin = Some(x);
+                break 'left;
+            }
+        }
+    }
+    let left_margin = left_margin?;
+
+    // Right margin: scan from right to left
+    let mut right_margin = width - 1;
+    'right: for x in (0..width).rev() {
+        for y in 0..height {
+            if img.get_pixel(x, y)[0] < WHITE_THRESHOLD && is_not_noise(img, x, y) {
+                right_margin = x;
+                break 'right;
+            }
+        }
+    }
+
+    // Top margin: scan from top to bottom
+    let mut top_margin = 0;
+    'top: for y in 0..height {
+        for x in 0..width {
+            if img.get_pixel(x, y)[0] < WHITE_THRESHOLD && is_not_noise(img, x, y) {
+                top_margin = y;
+                break 'top;
+            }
+        }
+    }
+
+    // Bottom margin: scan from bottom to top
+    let mut bottom_margin = height - 1;
+    'bottom: for y in (0..height).rev() {
+        for x in 0..width {
+            if img.get_pixel(x, y)[0] < WHITE_THRESHOLD && is_not_noise(img, x, y) {
+                bottom_margin = y;
+                break 'bottom;
+            }
+        }
+    }
+
+    // If we didn't find any content, return None
+    if left_margin >= right_margin || top_margin >= bottom_margin {
+        return None;
+    }
+    Some([left_margin, top_margin, right_margin, bottom_margin])
+}
+
 /// Check if a pixel is likely to be content rather than noise
 #[inline]
 fn is_not_noise(img: &GrayImage, x: u32, y: u32) -> bool {
@@ -398,121 +1248,855 @@ fn is_not_noise(img: &GrayImage, x: u32, y: u32) -> bool {
 
 fn resize_image<I>(
     img: &I,
-    device_dimensions: (u32, u32),
+    c: &ComicConfig,
     margin_color: Option<I::Pixel>,
 ) -> ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>>
 where
     I: GenericImageView,
     <I as GenericImageView>::Pixel: 'static,
 {
-    let (target_width, target_height) = device_dimensions;
+    let round = |value| round_down(value, c.dimension_multiple);
+    let (target_width, target_height) = c.device_dimensions();
+    let (target_width, target_height) = (round(target_width), round(target_height));
     let (width, height) = img.dimensions();
+    // an empty image has nothing to scale, and Fill couldn't crop it
+    if width == 0 || height == 0 {
+        return ImageBuffer::new(width, height);
+    }
 
     let filter = if width <= target_width && height <= target_height {
-        // For upscaling, Bicubic gives smoother results for manga
-        FilterType::CatmullRom
+        c.upscale_filter
     } else {
-        // For downscaling, Lanczos3 preserves more detail
-        FilterType::Lanczos3
+        c.downscale_filter
     };
+    let filter = FilterType::from(filter);
+
+    let width_ratio = target_width as f32 / width as f32;
+    let height_ratio = target_height as f32 / height as f32;
+    match c.scale_mode {
+        ScaleMode::Fit => {}
+        ScaleMode::Stretch => {
+            return imageops::resize(img, target_width, target_height, filter);
+        }
+        ScaleMode::Fill => {
+            // cropped before resizing, a sliver of a page would otherwise blow up to a huge image
+            let ratio = width_ratio.max(height_ratio);
+            let crop_width = ((target_width as f32 / ratio).round() as u32).clamp(1, width);
+            let crop_height = ((target_height as f32 / ratio).round() as u32).clamp(1, height);
+            let x = (width - crop_width) / 2;
+            let y = (height - crop_height) / 2;
+            let cropped = img.view(x, y, crop_width, crop_height);
+            return imageops::resize(&*cropped, target_width, target_height, filter);
+        }
+    }
+    let ratio = width_ratio.min(height_ratio);
+
+    // a sliver of a page can't scale down to nothing
+    let new_width = round(((width as f32 * ratio) as u32).max(1));
+    let new_height = round(((height as f32 * ratio) as u32).max(1));
+
+    let resized = imageops::resize(img, new_width, new_height, filter);
+
+    if new_width == target_width && new_height == target_height {
+        return resized;
+    }
+
+    let margin_color = match margin_color {
+        Some(color) => color,
+        None => return resized,
+    };
+
+    let mut img = ImageBuffer::from_pixel(target_width, target_height, margin_color);
+
+    // Calculate centering offsets
+    let x_offset = (target_width - new_width) / 2;
+    let y_offset = (target_height - new_height) / 2;
+
+    // Copy the resized image to the center of the final image
+    imageops::overlay(&mut img, &resized, x_offset.into(), y_offset.into());
+
+    img
+}
+
+/// A grid of thumbnails of the processed pages in reading order, saved as a JPEG at `path`
+pub fn contact_sheet(
+    images: &[ProcessedImage],
+    sheet: &ContactSheetConfig,
+    path: &Path,
+) -> Result<()> {
+    const GAP: u32 = 4;
+
+    let columns = sheet.columns.max(1);
+    let thumb_width = sheet.thumb_width.max(1);
+    // rows are as tall as the tallest page scaled to the thumbnail width
+    let cell_height = images
+        .iter()
+        .map(|image| {
+            let (width, height) = image.dimensions;
+            (height as u64 * thumb_width as u64 / width.max(1) as u64) as u32
+        })
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    let thumbs = images
+        .par_iter()
+        .map(|image| {
+            let page = imageproc::image::open(&image.path)
+                .with_context(|| format!("Failed to reopen page: {}", image.path.display()))?;
+            Ok(page.thumbnail(thumb_width, cell_height).to_rgb8())
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let rows = (thumbs.len() as u32).div_ceil(columns);
+    let mut canvas = RgbImage::from_pixel(
+        GAP + columns * (thumb_width + GAP),
+        GAP + rows.max(1) * (cell_height + GAP),
+        Rgb([255; 3]),
+    );
+    for (i, thumb) in thumbs.iter().enumerate() {
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        // centered in its cell
+        let x = GAP + column * (thumb_width + GAP) + (thumb_width - thumb.width()) / 2;
+        let y = GAP + row * (cell_height + GAP) + (cell_height - thumb.height()) / 2;
+        imageops::overlay(&mut canvas, thumb, x.into(), y.into());
+    }
+
+    let mut file = std::io::BufWriter::new(
+        std::fs::File::create(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?,
+    );
+    compress_to_jpeg(
+        &DynamicImage::ImageRgb8(canvas),
+        &mut file,
+        85,
+        None,
+        JpegSubsampling::Chroma420,
+        false,
+    )
+}
+
+/// Rounds down to a multiple, sizes below a single multiple are left alone
+fn round_down(value: u32, multiple: Option<u32>) -> u32 {
+    match multiple {
+        Some(multiple) if multiple > 1 && value >= multiple => value - value % multiple,
+        _ => value,
+    }
+}
+
+/// A watermark rendered once per comic, then stamped onto every page
+pub struct Watermark {
+    stamp: RgbaImage,
+    position: WatermarkPosition,
+    opacity: f32,
+    scale: f32,
+}
+
+impl Watermark {
+    pub fn new(config: &WatermarkConfig) -> Result<Self> {
+        let stamp = match (&config.image, &config.text) {
+            (Some(image), _) => imageproc::image::open(image)
+                .with_context(|| format!("Failed to open watermark: {}", image.display()))?
+                .into_rgba8(),
+            (None, Some(text)) => {
+                let font = config
+                    .font
+                    .as_ref()
+                    .context("A text watermark needs a font")?;
+                let data = std::fs::read(font)
+                    .with_context(|| format!("Failed to read font: {}", font.display()))?;
+                let font = FontVec::try_from_vec(data)
+                    .with_context(|| format!("Failed to parse font: {}", font.display()))?;
+                text_stamp(text, &font)
+            }
+            (None, None) => anyhow::bail!("A watermark needs either text or an image"),
+        };
+
+        Ok(Self {
+            stamp,
+            position: config.position,
+            opacity: config.opacity.clamp(0.0, 1.0),
+            scale: config.scale.clamp(0.0, 1.0),
+        })
+    }
+
+    /// Stamp onto `page`, sized against its final width
+    pub fn apply(&self, page: &mut DynamicImage) {
+        let (width, height) = page.dimensions();
+        let stamp_width = ((width as f32 * self.scale).round() as u32).clamp(1, width.max(1));
+        let stamp_height = (self.stamp.height() as u64 * stamp_width as u64
+            / self.stamp.width().max(1) as u64) as u32;
+        let stamp_height = stamp_height.clamp(1, height.max(1));
+        let stamp = imageops::resize(&self.stamp, stamp_width, stamp_height, FilterType::Triangle);
+
+        // kept a little off the edges
+        let (free_x, free_y) = (width - stamp_width, height - stamp_height);
+        let margin = width.min(height) / 40;
+        let (left, top) = (margin.min(free_x), margin.min(free_y));
+        let (right, bottom) = (free_x.saturating_sub(margin), free_y.saturating_sub(margin));
+        let (x, y) = match self.position {
+            WatermarkPosition::TopLeft => (left, top),
+            WatermarkPosition::TopRight => (right, top),
+            WatermarkPosition::Center => (free_x / 2, free_y / 2),
+            WatermarkPosition::BottomLeft => (left, bottom),
+            WatermarkPosition::BottomRight => (right, bottom),
+        };
+
+        match page {
+            DynamicImage::ImageLuma8(page) => {
+                blend_stamp(page, &stamp, (x, y), self.opacity, |pixel| pixel.to_luma())
+            }
+            DynamicImage::ImageRgb8(page) => {
+                blend_stamp(page, &stamp, (x, y), self.opacity, |pixel| pixel.to_rgb())
+            }
+            other => {
+                let mut rgb = other.to_rgb8();
+                blend_stamp(&mut rgb, &stamp, (x, y), self.opacity, |pixel| {
+                    pixel.to_rgb()
+                });
+                *other = DynamicImage::ImageRgb8(rgb);
+            }
+        }
+    }
+}
+
+/// `text` in black on a transparent background, rendered large so pages only scale it down
+fn text_stamp(text: &str, font: &FontVec) -> RgbaImage {
+    let scale = PxScale::from(128.0);
+    let (width, _) = text_size(scale, font, text);
+    let height = font.as_scaled(scale).height().ceil() as u32;
+    let mut coverage = GrayImage::new(width.max(1), height.max(1));
+    draw_text_mut(&mut coverage, Luma([255]), 0, 0, scale, font, text);
+
+    RgbaImage::from_fn(coverage.width(), coverage.height(), |x, y| {
+        Rgba([0, 0, 0, coverage.get_pixel(x, y)[0]])
+    })
+}
+
+fn blend_stamp<P>(
+    page: &mut ImageBuffer<P, Vec<u8>>,
+    stamp: &RgbaImage,
+    (x, y): (u32, u32),
+    opacity: f32,
+    color: impl Fn(&Rgba<u8>) -> P,
+) where
+    P: Pixel<Subpixel = u8>,
+{
+    for (stamp_x, stamp_y, pixel) in stamp.enumerate_pixels() {
+        let alpha = pixel[3] as f32 / 255.0 * opacity;
+        if alpha == 0.0 {
+            continue;
+        }
+        let color = color(pixel);
+        let target = page.get_pixel_mut(x + stamp_x, y + stamp_y);
+        for (channel, value) in target.channels_mut().iter_mut().zip(color.channels()) {
+            *channel = (*channel as f32 * (1.0 - alpha) + *value as f32 * alpha).round() as u8;
+        }
+    }
+}
+
+/// Pad every page to the size of the largest one, centered.
+///
+/// Pages are re-encoded from disk, so any preserved EXIF is dropped from the padded ones.
+fn pad_to_largest(
+    images: &mut [ProcessedImage],
+    processed_dir: &Path,
+    config: &ComicConfig,
+    workers: &Workers,
+) -> Result<()> {
+    let width = images.iter().map(|image| image.dimensions.0).max();
+    let height = images.iter().map(|image| image.dimensions.1).max();
+    let (Some(width), Some(height)) = (width, height) else {
+        return Ok(());
+    };
+    let color = config.margin_color.unwrap_or(255);
+
+    images
+        .par_iter_mut()
+        .filter(|image| image.dimensions != (width, height))
+        .try_for_each(|image| {
+            let x = (width - image.dimensions.0) / 2;
+            let y = (height - image.dimensions.1) / 2;
+            // every codec's copy is padded from itself, like the image_format one
+            let copies = std::iter::once((image.path.clone(), config.clone())).chain(
+                extra_codec_configs(config).into_iter().map(|codec| {
+                    let path = codec_path(processed_dir, &image.path, codec.image_format);
+                    (path, codec)
+                }),
+            );
+            for (path, config) in copies {
+                let page = imageproc::image::open(&path)
+                    .with_context(|| format!("Failed to reopen page: {}", path.display()))?;
+                let padded = match page {
+                    DynamicImage::ImageLuma8(page) => {
+                        let mut canvas = GrayImage::from_pixel(width, height, Luma([color]));
+                        imageops::overlay(&mut canvas, &page, x.into(), y.into());
+                        DynamicImage::ImageLuma8(canvas)
+                    }
+                    page => {
+                        let mut canvas = RgbImage::from_pixel(width, height, Rgb([color; 3]));
+                        imageops::overlay(&mut canvas, &page.to_rgb8(), x.into(), y.into());
+                        DynamicImage::ImageRgb8(canvas)
+                    }
+                };
+                save_image(&padded, &path, &config, None, workers)?;
+            }
+            image.dimensions = (width, height);
+            Ok(())
+        })
+}
+
+/// `config` with each codec other outputs are packaged with as its image_format
+fn extra_codec_configs(config: &ComicConfig) -> Vec<ComicConfig> {
+    config
+        .extra_codecs()
+        .into_iter()
+        .map(|codec| ComicConfig {
+            image_format: codec,
+            ..config.clone()
+        })
+        .collect()
+}
+
+/// Save `img` again at its `codec_path` for each codec other outputs are packaged with,
+/// so none of them is re-encoded from a lossy page
+fn save_extra_codecs(
+    img: &DynamicImage,
+    path: &Path,
+    processed_dir: &Path,
+    config: &ComicConfig,
+    exif: Option<&[u8]>,
+    workers: &Workers,
+) -> Result<()> {
+    for codec in extra_codec_configs(config) {
+        save_image(
+            img,
+            &codec_path(processed_dir, path, codec.image_format),
+            &codec,
+            exif,
+            workers,
+        )?;
+    }
+    Ok(())
+}
+
+/// Where the page saved at `path` is also encoded as `codec`, in a directory next to
+/// `processed_dir` of its own for each codec and its settings. Pages keep their folders
+/// below it, pages from elsewhere like the page cache go straight in
+pub fn codec_path(processed_dir: &Path, path: &Path, codec: ImageFormat) -> PathBuf {
+    let settings = match codec {
+        ImageFormat::Jpeg { quality } | ImageFormat::WebP { quality } => quality.to_string(),
+        ImageFormat::Png { compression } => format!("{compression:?}").to_lowercase(),
+    };
+    let dir = processed_dir.with_file_name(format!("Processed-{}-{settings}", codec.extension()));
+    let page = path
+        .strip_prefix(processed_dir)
+        .unwrap_or(Path::new(path.file_name().unwrap_or_default()));
+    dir.join(page).with_extension(codec.extension())
+}
+
+/// Re-encode the processed pages as `codec`, for pages that weren't encoded with it while
+/// processing, like those restored from the page cache.
+///
+/// Like padding, this starts from the pages on disk and drops any preserved EXIF.
+pub fn transcode(
+    images: &[ProcessedImage],
+    processed_dir: &Path,
+    codec: ImageFormat,
+    config: &ComicConfig,
+    workers: &Workers,
+) -> Result<Vec<ProcessedImage>> {
+    let config = ComicConfig {
+        image_format: codec,
+        ..config.clone()
+    };
+    workers.install(|| {
+        images
+            .par_iter()
+            .map(|image| {
+                let page = imageproc::image::open(&image.path)
+                    .with_context(|| format!("Failed to reopen page: {}", image.path.display()))?;
+                let path = codec_path(processed_dir, &image.path, codec);
+                save_image(&page, &path, &config, None, workers)?;
+                Ok(ProcessedImage {
+                    path,
+                    ..image.clone()
+                })
+            })
+            .collect()
+    })
+}
+
+/// Compress an image to JPEG format with the specified quality
+pub fn compress_to_jpeg<W>(
+    img: &DynamicImage,
+    writer: &mut W,
+    quality: u8,
+    dpi: Option<u16>,
+    subsampling: JpegSubsampling,
+    progressive: bool,
+) -> Result<()>
+where
+    W: std::io::Write,
+{
+    use imageproc::image::codecs::jpeg::{JpegEncoder, PixelDensity};
+
+    // the image encoder only does baseline 4:4:4, grayscale pages have no chroma to subsample
+    let sampling = match (subsampling, img) {
+        (JpegSubsampling::Chroma422, DynamicImage::ImageRgb8(_)) => {
+            Some(jpeg_encoder::SamplingFactor::R_4_2_2)
+        }
+        (JpegSubsampling::Chroma420, DynamicImage::ImageRgb8(_)) => {
+            Some(jpeg_encoder::SamplingFactor::R_4_2_0)
+        }
+        _ => None,
+    };
+    if sampling.is_some() || progressive {
+        let converted;
+        let (pixels, color) = match img {
+            DynamicImage::ImageLuma8(gray) => (gray.as_raw(), jpeg_encoder::ColorType::Luma),
+            DynamicImage::ImageRgb8(rgb) => (rgb.as_raw(), jpeg_encoder::ColorType::Rgb),
+            _ => {
+                converted = img.to_rgb8();
+                (converted.as_raw(), jpeg_encoder::ColorType::Rgb)
+            }
+        };
+        let (width, height) = img.dimensions();
+        let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+            anyhow::bail!("Image too large for JPEG: {}x{}", width, height);
+        };
+        let mut encoder = jpeg_encoder::Encoder::new(writer, quality);
+        if let Some(sampling) = sampling {
+            encoder.set_sampling_factor(sampling);
+        }
+        encoder.set_progressive(progressive);
+        if let Some(dpi) = dpi {
+            encoder.set_density(jpeg_encoder::PixelDensity::dpi(dpi));
+        }
+        encoder
+            .encode(pixels, width, height, color)
+            .map_err(|e| anyhow::anyhow!("Failed to compress image to JPEG: {}", e))?;
+        return Ok(());
+    }
+
+    let mut encoder = JpegEncoder::new_with_quality(writer, quality);
+    if let Some(dpi) = dpi {
+        encoder.set_pixel_density(PixelDensity::dpi(dpi));
+    }
+
+    encoder
+        .encode_image(img)
+        .with_context(|| "Failed to compress image to JPEG")?;
+
+    Ok(())
+}
+
+/// Compress an image to PNG format with the specified compression level
+pub fn compress_to_png<W>(
+    img: &DynamicImage,
+    writer: &mut W,
+    compression: PngCompression,
+) -> Result<()>
+where
+    W: std::io::Write,
+{
+    use imageproc::image::codecs::png::{CompressionType, FilterType, PngEncoder};
+    use imageproc::image::ImageEncoder;
+
+    let compression_type = match compression {
+        PngCompression::Fast => CompressionType::Fast,
+        PngCompression::Default => CompressionType::Default,
+        PngCompression::Best => CompressionType::Best,
+    };
+
+    let is_grayscale = img.color() == ColorType::L8 || img.color() == ColorType::La8;
+
+    let encoder = PngEncoder::new_with_quality(
+        writer,
+        compression_type,
+        if is_grayscale {
+            FilterType::NoFilter
+        } else {
+            FilterType::Adaptive
+        },
+    );
+
+    encoder
+        .write_image(
+            img.as_bytes(),
+            img.width(),
+            img.height(),
+            img.color().into(),
+        )
+        .with_context(|| "Failed to compress image to PNG")?;
+
+    Ok(())
+}
+
+/// Compress an image to WebP format with the specified quality
+pub fn compress_to_webp(img: &DynamicImage, quality: u8) -> Result<WebPMemory> {
+    let img = DynamicImage::from(img.to_rgb8());
+    let encoder = webp::Encoder::from_image(&img)
+        .map_err(|e| anyhow::anyhow!("Failed to create WebP encoder: {}", e))?;
+    let webp_data = encoder.encode(quality as f32);
+    Ok(webp_data)
+}
+
+/// Decode an image, handling CMYK JPEGs ourselves
+pub fn load_image(data: &[u8], cmyk_mode: CmykMode) -> Result<DynamicImage> {
+    if is_heif(data) {
+        anyhow::bail!("HEIF images aren't supported, convert the page to JPEG or PNG");
+    }
+    if is_truncated(data) {
+        anyhow::bail!("Image data is truncated");
+    }
+    let img = match decode_cmyk_jpeg(data, cmyk_mode)? {
+        Some(img) => img,
+        None => load_from_memory(data)?,
+    };
+    // nothing to scale, the resize math would divide by zero
+    if img.width() == 0 || img.height() == 0 {
+        anyhow::bail!("Image has no pixels ({}x{})", img.width(), img.height());
+    }
+    Ok(img)
+}
+
+/// `load_image` for a page, converted to sRGB from its embedded ICC profile with `color_profiles`
+fn load_page(data: &[u8], cmyk_mode: CmykMode, color_profiles: bool) -> Result<DynamicImage> {
+    let img = load_image(data, cmyk_mode)?;
+    Ok(if color_profiles {
+        to_srgb(img, data)
+    } else {
+        img
+    })
+}
+
+/// Convert a color page to sRGB from the ICC profile embedded in `data`, so wide-gamut
+/// scans don't come out oversaturated. Pages without an RGB profile are left as they are
+#[cfg(feature = "icc")]
+fn to_srgb(img: DynamicImage, data: &[u8]) -> DynamicImage {
+    use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+    if !img.color().has_color() {
+        return img;
+    }
+    let icc = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .and_then(|mut decoder| decoder.icc_profile().ok().flatten());
+    let Some(profile) = icc.and_then(|icc| ColorProfile::new_from_slice(&icc).ok()) else {
+        return img;
+    };
+    if profile.color_space != DataColorSpace::Rgb {
+        return img;
+    }
+    let transform = match profile.create_transform_8bit(
+        Layout::Rgb,
+        &ColorProfile::new_srgb(),
+        Layout::Rgb,
+        TransformOptions::default(),
+    ) {
+        Ok(transform) => transform,
+        Err(e) => {
+            log::warn!("Ignoring unusable ICC profile: {}", e);
+            return img;
+        }
+    };
+
+    let src = img.into_rgb8();
+    let mut srgb = RgbImage::new(src.width(), src.height());
+    if let Err(e) = transform.transform(&src, &mut srgb) {
+        log::warn!("Failed to apply ICC profile: {}", e);
+        return DynamicImage::ImageRgb8(src);
+    }
+    DynamicImage::ImageRgb8(srgb)
+}
+
+#[cfg(not(feature = "icc"))]
+fn to_srgb(img: DynamicImage, _data: &[u8]) -> DynamicImage {
+    img
+}
+
+/// Whether the data ends before the format's end marker, as with interrupted downloads.
+///
+/// The JPEG decoder fills missing rows with gray rather than failing.
+fn is_truncated(data: &[u8]) -> bool {
+    // encoders sometimes pad after the end marker
+    let tail = &data[data.len().saturating_sub(1024)..];
+    if data.starts_with(&[0xFF, 0xD8]) {
+        // motion photos append a video after the still, so the EOI isn't always at the end.
+        // 0xFF is always escaped in entropy-coded data, so EOI can't show up by accident
+        let scan = jpeg_scan_start(data).unwrap_or(data.len());
+        !data[scan..].windows(2).any(|w| w == [0xFF, 0xD9])
+    } else if data.starts_with(b"\x89PNG") {
+        !tail.windows(4).any(|w| w == b"IEND")
+    } else if data.starts_with(b"RIFF") && data.len() >= 8 {
+        let size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        data.len() < size + 8
+    } else {
+        false
+    }
+}
+
+/// Convert a CMYK JPEG to RGB, `None` for anything that isn't one.
+///
+/// The `image` decoder always assumes Adobe-inverted values,
+/// so plain CMYK comes out with inverted colors.
+fn decode_cmyk_jpeg(data: &[u8], mode: CmykMode) -> Result<Option<DynamicImage>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Ok(None);
+    }
+
+    let options = DecoderOptions::default()
+        .set_strict_mode(false)
+        .set_max_width(usize::MAX)
+        .set_max_height(usize::MAX)
+        .jpeg_set_out_colorspace(ColorSpace::CMYK);
+    let mut decoder = JpegDecoder::new_with_options(data, options);
+    if decoder.decode_headers().is_err() || decoder.get_input_colorspace() != Some(ColorSpace::CMYK)
+    {
+        return Ok(None);
+    }
+
+    let inverted = match mode {
+        CmykMode::Auto => has_adobe_segment(data),
+        CmykMode::Inverted => true,
+        CmykMode::Normal => false,
+    };
+
+    let (width, height) = decoder.dimensions().context("Missing JPEG dimensions")?;
+    let cmyk = decoder
+        .decode()
+        .map_err(|e| anyhow::anyhow!("Failed to decode CMYK JPEG: {:?}", e))?;
+
+    let rgb = cmyk
+        .chunks_exact(4)
+        .flat_map(|px| {
+            let [c, m, y, k] = if inverted {
+                [px[0], px[1], px[2], px[3]]
+            } else {
+                [255 - px[0], 255 - px[1], 255 - px[2], 255 - px[3]]
+            };
+            // (1 - ink) * (1 - black), with inverted values already being 1 - ink
+            [c, m, y].map(|v| ((v as u32 * k as u32 + 127) / 255) as u8)
+        })
+        .collect();
+
+    let img = RgbImage::from_raw(width as u32, height as u32, rgb)
+        .context("CMYK JPEG decoded to an unexpected size")?;
+    Ok(Some(DynamicImage::ImageRgb8(img)))
+}
+
+/// Whether the JPEG has an Adobe APP14 segment, which marks CMYK values as inverted
+fn has_adobe_segment(jpeg: &[u8]) -> bool {
+    let mut pos = 2;
+    while pos + 4 <= jpeg.len() && jpeg[pos] == 0xFF {
+        let marker = jpeg[pos + 1];
+        // metadata segments all come before the start of scan
+        if marker == 0xDA {
+            break;
+        }
+        if marker == 0xEE && jpeg[pos + 4..].starts_with(b"Adobe") {
+            return true;
+        }
+        let length = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        pos += 2 + length;
+    }
+    false
+}
+
+/// Offset of the first start of scan, skipping the metadata segments (and any embedded thumbnail)
+fn jpeg_scan_start(jpeg: &[u8]) -> Option<usize> {
+    let mut pos = 2;
+    while pos + 4 <= jpeg.len() && jpeg[pos] == 0xFF {
+        if jpeg[pos + 1] == 0xDA {
+            return Some(pos);
+        }
+        let length = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        pos += 2 + length;
+    }
+    None
+}
 
-    let width_ratio = target_width as f32 / width as f32;
-    let height_ratio = target_height as f32 / height as f32;
-    let ratio = width_ratio.min(height_ratio);
+/// Whether the data is a HEIF/HEIC container, as written by phone cameras
+fn is_heif(data: &[u8]) -> bool {
+    const BRANDS: &[&[u8]] = &[
+        b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1",
+    ];
+    data.len() >= 12 && &data[4..8] == b"ftyp" && BRANDS.contains(&&data[8..12])
+}
 
-    let new_width = (width as f32 * ratio) as u32;
-    let new_height = (height as f32 * ratio) as u32;
+/// Raw EXIF payload of an encoded image, if it carries one
+fn read_exif(data: &[u8]) -> Option<Vec<u8>> {
+    let reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?;
+    let mut decoder = reader.into_decoder().ok()?;
+    decoder.exif_metadata().ok().flatten()
+}
 
-    let resized = imageops::resize(img, new_width, new_height, filter);
+/// Insert an APP1 EXIF segment directly after the JPEG start-of-image marker
+fn insert_exif_segment(jpeg: Vec<u8>, exif: &[u8]) -> Result<Vec<u8>> {
+    const EXIF_HEADER: &[u8] = b"Exif\0\0";
 
-    if new_width == target_width && new_height == target_height {
-        return resized;
+    // segment length includes the two length bytes themselves
+    let length = u16::try_from(2 + EXIF_HEADER.len() + exif.len())
+        .context("EXIF metadata too large for a JPEG segment")?;
+
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        anyhow::bail!("Not a JPEG stream");
     }
 
-    let margin_color = match margin_color {
-        Some(color) => color,
-        None => return resized,
-    };
+    let mut output = Vec::with_capacity(jpeg.len() + length as usize + 2);
+    output.extend_from_slice(&jpeg[..2]);
+    output.extend_from_slice(&[0xFF, 0xE1]);
+    output.extend_from_slice(&length.to_be_bytes());
+    output.extend_from_slice(EXIF_HEADER);
+    output.extend_from_slice(exif);
+    output.extend_from_slice(&jpeg[2..]);
+    Ok(output)
+}
 
-    let mut img = ImageBuffer::from_pixel(target_width, target_height, margin_color);
+/// Insert a pHYs chunk with the given density directly after the PNG header chunk
+fn insert_png_density(png: Vec<u8>, dpi: u16) -> Result<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    // signature, then IHDR: length, type, 13 bytes of data, crc
+    const IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
 
-    // Calculate centering offsets
-    let x_offset = (target_width - new_width) / 2;
-    let y_offset = (target_height - new_height) / 2;
+    if !png.starts_with(SIGNATURE) || png.len() < IHDR_END {
+        anyhow::bail!("Not a PNG stream");
+    }
 
-    // Copy the resized image to the center of the final image
-    imageops::overlay(&mut img, &resized, x_offset.into(), y_offset.into());
+    // PNG density is stored per meter
+    let ppm = (dpi as f64 / 0.0254).round() as u32;
+    let mut chunk = b"pHYs".to_vec();
+    chunk.extend_from_slice(&ppm.to_be_bytes());
+    chunk.extend_from_slice(&ppm.to_be_bytes());
+    chunk.push(1); // unit is the meter
+
+    let mut output = Vec::with_capacity(png.len() + chunk.len() + 8);
+    output.extend_from_slice(&png[..IHDR_END]);
+    output.extend_from_slice(&(chunk.len() as u32 - 4).to_be_bytes());
+    output.extend_from_slice(&chunk);
+    output.extend_from_slice(&crc32(&chunk).to_be_bytes());
+    output.extend_from_slice(&png[IHDR_END..]);
+    Ok(output)
+}
 
-    img
+/// CRC-32 as used by PNG chunks
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
 }
 
-/// Compress an image to JPEG format with the specified quality
-pub fn compress_to_jpeg<W>(img: &DynamicImage, writer: &mut W, quality: u8) -> Result<()>
-where
-    W: std::io::Write,
-{
-    let mut encoder =
-        imageproc::image::codecs::jpeg::JpegEncoder::new_with_quality(writer, quality);
+/// Shared by every comic in a batch, so the caps hold across the whole batch
+#[derive(Default)]
+pub struct Workers {
+    // reads, decodes and processes pages, the caller's pool when None
+    decode: Option<rayon::ThreadPool>,
+    // caps page encodes, which run on the decode workers
+    encodes: Option<Limiter>,
+    // caps disk writes, encoding still runs in parallel
+    writes: Option<Limiter>,
+}
 
-    encoder
-        .encode_image(img)
-        .with_context(|| "Failed to compress image to JPEG")?;
+impl Workers {
+    pub fn new(config: &ComicConfig) -> Result<Self> {
+        let decode = config
+            .decode_threads
+            .map(|threads| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads.max(1))
+                    .thread_name(|i| format!("comically-decode-{i}"))
+                    .build()
+            })
+            .transpose()
+            .context("Failed to start the decode workers")?;
+
+        Ok(Self {
+            decode,
+            encodes: config.encode_threads.map(Limiter::new),
+            writes: config.max_concurrent_writes.map(Limiter::new),
+        })
+    }
 
-    Ok(())
+    fn install<R, F>(&self, f: F) -> R
+    where
+        R: Send,
+        F: FnOnce() -> R + Send,
+    {
+        match &self.decode {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
 }
 
-/// Compress an image to PNG format with the specified compression level
-pub fn compress_to_png<W>(
-    img: &DynamicImage,
-    writer: &mut W,
-    compression: PngCompression,
-) -> Result<()>
-where
-    W: std::io::Write,
-{
-    use imageproc::image::codecs::png::{CompressionType, FilterType, PngEncoder};
-    use imageproc::image::ImageEncoder;
-
-    let compression_type = match compression {
-        PngCompression::Fast => CompressionType::Fast,
-        PngCompression::Default => CompressionType::Default,
-        PngCompression::Best => CompressionType::Best,
-    };
+/// Caps how many threads are inside a section at once
+struct Limiter {
+    available: Mutex<usize>,
+    released: Condvar,
+}
 
-    let is_grayscale = img.color() == ColorType::L8 || img.color() == ColorType::La8;
+impl Limiter {
+    fn new(max_concurrent: usize) -> Self {
+        Self {
+            available: Mutex::new(max_concurrent.max(1)),
+            released: Condvar::new(),
+        }
+    }
 
-    let encoder = PngEncoder::new_with_quality(
-        writer,
-        compression_type,
-        if is_grayscale {
-            FilterType::NoFilter
-        } else {
-            FilterType::Adaptive
-        },
-    );
+    fn acquire(&self) -> Permit<'_> {
+        let available = self.available.lock().unwrap();
+        let mut available = self
+            .released
+            .wait_while(available, |available| *available == 0)
+            .unwrap();
+        *available -= 1;
+        Permit { limiter: self }
+    }
+}
 
-    encoder
-        .write_image(
-            img.as_bytes(),
-            img.width(),
-            img.height(),
-            img.color().into(),
-        )
-        .with_context(|| "Failed to compress image to PNG")?;
+struct Permit<'a> {
+    limiter: &'a Limiter,
+}
 
-    Ok(())
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.limiter.available.lock().unwrap() += 1;
+        self.limiter.released.notify_one();
+    }
 }
 
-/// Compress an image to WebP format with the specified quality
-pub fn compress_to_webp(img: &DynamicImage, quality: u8) -> Result<WebPMemory> {
-    let img = DynamicImage::from(img.to_rgb8());
-    let encoder = webp::Encoder::from_image(&img)
-        .map_err(|e| anyhow::anyhow!("Failed to create WebP encoder: {}", e))?;
-    let webp_data = encoder.encode(quality as f32);
-    Ok(webp_data)
+fn save_image(
+    img: &DynamicImage,
+    path: &Path,
+    config: &ComicConfig,
+    exif: Option<&[u8]>,
+    workers: &Workers,
+) -> Result<()> {
+    // encode up front so only the write itself is limited
+    let data = {
+        let _permit = workers.encodes.as_ref().map(Limiter::acquire);
+        encode_image(img, config, exif)
+            .with_context(|| format!("Failed to encode image: {}", path.display()))?
+    };
+
+    write_page(&data, path, workers)
 }
 
-fn save_image(img: &DynamicImage, path: &Path, format: &ImageFormat) -> Result<()> {
+fn write_page(data: &[u8], path: &Path, workers: &Workers) -> Result<()> {
     if let Some(parent) = path.parent() {
         std::fs::create_dir_all(parent).with_context(|| {
             format!(
@@ -522,32 +2106,52 @@ fn save_image(img: &DynamicImage, path: &Path, format: &ImageFormat) -> Result<(
         })?;
     }
 
-    match format {
+    let _permit = workers.writes.as_ref().map(Limiter::acquire);
+    std::fs::write(path, data)
+        .with_context(|| format!("Failed to save image: {}", path.display()))?;
+
+    Ok(())
+}
+
+// metadata is only carried over for JPEG output, other encoders always strip it
+// WebP has no density field, so `dpi` only applies to JPEG and PNG
+fn encode_image(img: &DynamicImage, config: &ComicConfig, exif: Option<&[u8]>) -> Result<Vec<u8>> {
+    let dpi = config.dpi;
+    let data = match config.image_format {
         ImageFormat::Jpeg { quality } => {
-            let mut output_buffer = std::io::BufWriter::new(std::fs::File::create(path)?);
-            compress_to_jpeg(img, &mut output_buffer, *quality)
-                .with_context(|| format!("Failed to save JPEG image: {}", path.display()))?;
+            let mut jpeg = Vec::new();
+            compress_to_jpeg(
+                img,
+                &mut jpeg,
+                quality,
+                dpi,
+                config.jpeg_subsampling,
+                config.jpeg_progressive,
+            )?;
+            match exif {
+                Some(exif) => insert_exif_segment(jpeg, exif)?,
+                None => jpeg,
+            }
         }
         ImageFormat::Png { compression } => {
-            let mut output_buffer = std::io::BufWriter::new(std::fs::File::create(path)?);
-            compress_to_png(img, &mut output_buffer, *compression)
-                .with_context(|| format!("Failed to save PNG image: {}", path.display()))?;
-        }
-        ImageFormat::WebP { quality } => {
-            let webp_data = compress_to_webp(img, *quality)
-                .with_context(|| format!("Failed to encode WebP image: {}", path.display()))?;
-            std::fs::write(path, &*webp_data)
-                .with_context(|| format!("Failed to save WebP image: {}", path.display()))?;
+            let mut png = Vec::new();
+            compress_to_png(img, &mut png, compression)?;
+            match dpi {
+                Some(dpi) => insert_png_density(png, dpi)?,
+                None => png,
+            }
         }
-    }
+        ImageFormat::WebP { quality } => compress_to_webp(img, quality)?.to_vec(),
+    };
 
-    Ok(())
+    Ok(data)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use imageproc::image::{GrayImage, Luma};
+    use crate::comic::{DevicePreset, ProgressGranularity};
+    use imageproc::image::{GrayImage, Luma, Rgb};
 
     #[test]
     fn test_basic_cropping() {
@@ -596,6 +2200,46 @@ mod tests {
         assert_eq!(cropped_width, 100 - 40 + 2 * SAFETY_MARGIN,);
     }
 
+    #[test]
+    fn test_shared_crop_keeps_every_page_content() {
+        let page = |name: &str, img: GrayImage| {
+            let mut data = Vec::new();
+            compress_to_png(
+                &DynamicImage::ImageLuma8(img),
+                &mut data,
+                PngCompression::Fast,
+            )
+            .unwrap();
+            Ok(ArchiveFile {
+                file_name: name.into(),
+                data,
+            })
+        };
+        let archive = [
+            page(
+                "001.png",
+                create_test_image_with_vertical(100, 80, 30, 20, 15, 40, &[]),
+            ),
+            page(
+                "002.png",
+                create_test_image_with_vertical(100, 80, 20, 35, 25, 15, &[]),
+            ),
+            page("003.png", GrayImage::from_pixel(100, 80, Luma([255]))),
+        ];
+        let config = ComicConfig {
+            brightness: 0,
+            gamma: 1.0,
+            ..ComicConfig::default()
+        };
+
+        let margins = shared_crop_margins(archive.into_iter(), &config).unwrap();
+        // the smallest margin on each side, less the safety margin, the blank page doesn't count
+        let safe = |margin: u32| margin - SAFETY_MARGIN;
+        assert_eq!(margins, [safe(20), safe(15), safe(20), safe(15)]);
+        assert_eq!(crop_to_margins((100, 80), margins), Some((18, 13, 64, 54)));
+        assert_eq!(crop_to_margins((30, 20), margins), None);
+    }
+
     #[test]
     fn test_no_margins() {
         let test_img = create_test_image(100, 50, 0, 0, &[]);
@@ -703,6 +2347,1023 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_exif_stripped_by_default() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_pixel(16, 16, Luma([128])));
+        let mut jpeg = Vec::new();
+        compress_to_jpeg(&img, &mut jpeg, 90, None, JpegSubsampling::Chroma444, false).unwrap();
+
+        // big-endian TIFF header with a single GPS IFD pointer entry
+        let exif = [
+            b'M', b'M', 0x00, 0x2A, 0x00, 0x00, 0x00, 0x08, // header
+            0x00, 0x01, // one entry
+            0x88, 0x25, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x1A, // GPS IFD
+            0x00, 0x00, 0x00, 0x00, // no next IFD
+        ];
+        let source = insert_exif_segment(jpeg, &exif).unwrap();
+        assert_eq!(read_exif(&source).as_deref(), Some(&exif[..]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let decoded = load_from_memory(&source).unwrap();
+        let config = ComicConfig {
+            image_format: ImageFormat::Jpeg { quality: 85 },
+            ..ComicConfig::default()
+        };
+
+        let stripped = dir.path().join("stripped.jpg");
+        save_image(&decoded, &stripped, &config, None, &Workers::default()).unwrap();
+        assert!(
+            read_exif(&std::fs::read(&stripped).unwrap()).is_none(),
+            "output should not carry GPS EXIF"
+        );
+
+        let preserved = dir.path().join("preserved.jpg");
+        save_image(
+            &decoded,
+            &preserved,
+            &config,
+            Some(&exif),
+            &Workers::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            read_exif(&std::fs::read(&preserved).unwrap()).as_deref(),
+            Some(&exif[..])
+        );
+    }
+
+    #[test]
+    fn test_truncated_images_rejected() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_pixel(64, 64, Luma([40])));
+        let mut jpeg = Vec::new();
+        compress_to_jpeg(&img, &mut jpeg, 90, None, JpegSubsampling::Chroma444, false).unwrap();
+        let mut png = Vec::new();
+        img.write_to(
+            &mut std::io::Cursor::new(&mut png),
+            imageproc::image::ImageFormat::Png,
+        )
+        .unwrap();
+
+        for data in [jpeg, png] {
+            assert!(load_image(&data, CmykMode::Auto).is_ok());
+            let truncated = &data[..data.len() / 2];
+            assert!(load_image(truncated, CmykMode::Auto).is_err());
+        }
+    }
+
+    #[test]
+    fn test_motion_photo_and_heif() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_pixel(64, 64, Luma([40])));
+        let mut jpeg = Vec::new();
+        compress_to_jpeg(&img, &mut jpeg, 90, None, JpegSubsampling::Chroma444, false).unwrap();
+
+        // the still comes first, followed by an MP4 that the decoder should ignore
+        let mut motion = jpeg.clone();
+        motion.extend_from_slice(b"\0\0\0\x18ftypmp42");
+        motion.extend_from_slice(&[0; 4096]);
+        let decoded = load_image(&motion, CmykMode::Auto).unwrap();
+        assert_eq!(decoded.dimensions(), (64, 64));
+
+        let mut heic = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic".to_vec();
+        heic.extend_from_slice(&[0; 64]);
+        let err = load_image(&heic, CmykMode::Auto).unwrap_err();
+        assert!(err.to_string().contains("HEIF"));
+    }
+
+    #[test]
+    fn test_pad_to_largest_canvas() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ComicConfig {
+            page_canvas: PageCanvas::Largest,
+            image_format: ImageFormat::Png {
+                compression: PngCompression::Fast,
+            },
+            ..ComicConfig::default()
+        };
+
+        let mut images: Vec<_> = [(40, 60), (60, 40)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, (width, height))| {
+                let path = dir.path().join(format!("{i}.png"));
+                let page =
+                    DynamicImage::ImageLuma8(GrayImage::from_pixel(width, height, Luma([0])));
+                save_image(&page, &path, &config, None, &Workers::default()).unwrap();
+                ProcessedImage {
+                    path,
+                    dimensions: (width, height),
+                    ..ProcessedImage::default()
+                }
+            })
+            .collect();
+
+        pad_to_largest(&mut images, dir.path(), &config, &Workers::default()).unwrap();
+
+        for image in &images {
+            assert_eq!(image.dimensions, (60, 60));
+            let page = imageproc::image::open(&image.path).unwrap().into_luma8();
+            assert_eq!(page.dimensions(), (60, 60));
+            // padding in the corner, the page itself in the middle
+            assert_eq!(page.get_pixel(0, 0).0, [255]);
+            assert_eq!(page.get_pixel(30, 30).0, [0]);
+        }
+    }
+
+    #[test]
+    fn test_color_cover() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ComicConfig {
+            color_cover: true,
+            auto_crop: false,
+            device: DevicePreset {
+                name: "test".into(),
+                dimensions: (40, 60),
+            },
+            image_format: ImageFormat::Png {
+                compression: PngCompression::Fast,
+            },
+            ..ComicConfig::default()
+        };
+
+        let page = |name: &str| {
+            let img = RgbImage::from_pixel(40, 60, Rgb([200, 40, 40]));
+            let mut data = Vec::new();
+            compress_to_png(
+                &DynamicImage::ImageRgb8(img),
+                &mut data,
+                PngCompression::Fast,
+            )
+            .unwrap();
+            Ok(ArchiveFile {
+                file_name: name.into(),
+                data,
+            })
+        };
+        let run = |config: ComicConfig| {
+            // stored out of order, the cover is the page that sorts first
+            let archive = vec![page("002.png"), page("001.png"), page("003.png")];
+            let (tx, _rx) = mpsc::channel();
+            let (images, _) = process_archive_images(
+                archive.into_iter(),
+                &[],
+                config,
+                dir.path(),
+                0,
+                &tx,
+                1,
+                &Workers::default(),
+            )
+            .unwrap();
+            images
+        };
+
+        let images = run(config.clone());
+        let colors: Vec<_> = images
+            .iter()
+            .map(|image| imageproc::image::open(&image.path).unwrap().color())
+            .collect();
+        assert_eq!(colors, [ColorType::Rgb8, ColorType::L8, ColorType::L8]);
+
+        // the cover turns with the rest of the pages
+        let rotated = run(ComicConfig {
+            rotate: RotateMode::Cw90,
+            split: SplitStrategy::None,
+            ..config.clone()
+        });
+        let dimensions: Vec<_> = rotated.iter().map(|image| image.dimensions).collect();
+        assert_eq!(dimensions, [(40, 26); 3]);
+        let cover = imageproc::image::open(&rotated[0].path).unwrap();
+        assert_eq!(cover.dimensions(), (40, 26));
+
+        // a negative cover is turned positive like the rest
+        let inverted = run(ComicConfig {
+            invert: true,
+            ..config.clone()
+        });
+        let cover = imageproc::image::open(&inverted[0].path)
+            .unwrap()
+            .into_rgb8();
+        let [red, green, _] = cover.get_pixel(20, 30).0;
+        assert!(red < green, "{:?}", cover.get_pixel(20, 30));
+
+        // posterized to black and white like the rest
+        let posterized = run(ComicConfig {
+            posterize_levels: Some(2),
+            ..config
+        });
+        let cover = imageproc::image::open(&posterized[0].path)
+            .unwrap()
+            .into_rgb8();
+        assert!(cover.pixels().flat_map(|p| p.0).all(|v| v == 0 || v == 255));
+    }
+
+    #[test]
+    fn test_progress_updates_are_coalesced() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ComicConfig {
+            device: DevicePreset {
+                name: "test".into(),
+                dimensions: (40, 60),
+            },
+            ..ComicConfig::default()
+        };
+        let page = |name: String| {
+            let mut data = Vec::new();
+            compress_to_png(
+                &DynamicImage::ImageLuma8(GrayImage::from_pixel(40, 60, Luma([90]))),
+                &mut data,
+                PngCompression::Fast,
+            )
+            .unwrap();
+            Ok(ArchiveFile {
+                file_name: name.into(),
+                data,
+            })
+        };
+        let archive = (1..=7).map(|i| page(format!("{i:03}.png")));
+
+        let (tx, rx) = mpsc::channel();
+        let (images, _) = process_archive_images(
+            archive,
+            &[],
+            config,
+            dir.path(),
+            0,
+            &tx,
+            ProgressGranularity::Percent(30.0).step(7),
+            &Workers::default(),
+        )
+        .unwrap();
+        assert_eq!(images.len(), 7);
+
+        let counts: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                Event::Progress {
+                    event:
+                        ProgressEvent::ComicUpdate {
+                            status: ComicStatus::ImageProcessed { count },
+                            ..
+                        },
+                    ..
+                } => Some(count),
+                _ => None,
+            })
+            .collect();
+        // every 3 pages or a few more when pages finish together, the rest flushed at the end
+        assert_eq!(counts.iter().sum::<usize>(), 7);
+        assert!(counts.len() <= 3);
+        assert!(counts[..counts.len() - 1].iter().all(|&count| count >= 3));
+    }
+
+    #[test]
+    fn test_limiter_caps_concurrency() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let limiter = Limiter::new(2);
+        let (inside, most) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let _permit = limiter.acquire();
+                    let now = inside.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(10));
+                    inside.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(most.into_inner(), 2);
+    }
+
+    #[test]
+    fn test_passthrough_presized_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ComicConfig {
+            passthrough: true,
+            brightness: 0,
+            gamma: 1.0,
+            device: DevicePreset {
+                name: "test".into(),
+                dimensions: (40, 60),
+            },
+            image_format: ImageFormat::Png {
+                compression: PngCompression::Best,
+            },
+            ..ComicConfig::default()
+        };
+
+        let page = |name: &str, (width, height), contrast: u32| {
+            let img = GrayImage::from_fn(width, height, |x, y| {
+                Luma([((x * 5 + y * 3) % 256 * contrast / 255) as u8])
+            });
+            let mut data = Vec::new();
+            compress_to_png(
+                &DynamicImage::ImageLuma8(img),
+                &mut data,
+                PngCompression::Fast,
+            )
+            .unwrap();
+            ArchiveFile {
+                file_name: name.into(),
+                data,
+            }
+        };
+        let sized = page("001.png", (40, 60), 255);
+        let larger = page("002.png", (80, 120), 255);
+        // autocontrast would stretch it
+        let faded = page("003.png", (40, 60), 128);
+        let archive = vec![Ok(sized.clone()), Ok(larger.clone()), Ok(faded.clone())];
+
+        let (tx, _rx) = mpsc::channel();
+        let (images, _) = process_archive_images(
+            archive.into_iter(),
+            &[],
+            config,
+            dir.path(),
+            0,
+            &tx,
+            1,
+            &Workers::default(),
+        )
+        .unwrap();
+
+        assert_eq!(std::fs::read(&images[0].path).unwrap(), sized.data);
+        assert_ne!(std::fs::read(&images[1].path).unwrap(), larger.data);
+        assert_eq!(images[1].dimensions, (40, 60));
+        assert_ne!(std::fs::read(&images[2].path).unwrap(), faded.data);
+    }
+
+    #[test]
+    fn test_posterize_levels() {
+        let mut img = DynamicImage::ImageLuma8(GrayImage::from_fn(256, 1, |x, _| Luma([x as u8])));
+        posterize(&mut img, 4);
+
+        let mut values: Vec<u8> = img.to_luma8().into_raw();
+        values.dedup();
+        assert_eq!(values, [0, 85, 170, 255]);
+
+        // below two levels still keeps black and white
+        let mut img = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([20, 130, 240])));
+        posterize(&mut img, 1);
+        assert_eq!(img.to_rgb8().get_pixel(0, 0).0, [0, 255, 255]);
+    }
+
+    #[test]
+    fn test_one_pixel_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let page = |name: &str, (width, height)| {
+            let mut data = Vec::new();
+            compress_to_png(
+                &DynamicImage::ImageLuma8(GrayImage::from_pixel(width, height, Luma([90]))),
+                &mut data,
+                PngCompression::Fast,
+            )
+            .unwrap();
+            Ok(ArchiveFile {
+                file_name: name.into(),
+                data,
+            })
+        };
+        let archive = || {
+            [
+                page("001.png", (1, 1)),
+                page("002.png", (1, 120)),
+                page("003.png", (2, 1)),
+                Ok(ArchiveFile {
+                    file_name: "004.png".into(),
+                    data: Vec::new(),
+                }),
+            ]
+        };
+
+        for scale_mode in [ScaleMode::Fit, ScaleMode::Fill, ScaleMode::Stretch] {
+            let config = ComicConfig {
+                device: DevicePreset {
+                    name: "test".into(),
+                    dimensions: (40, 60),
+                },
+                split: SplitStrategy::RotateAndSplit,
+                scale_mode,
+                dimension_multiple: Some(16),
+                ..ComicConfig::default()
+            };
+            let (tx, _rx) = mpsc::channel();
+            let (images, warnings) = process_archive_images(
+                archive().into_iter(),
+                &[],
+                config,
+                dir.path(),
+                0,
+                &tx,
+                1,
+                &Workers::default(),
+            )
+            .unwrap();
+            // the broken page is skipped, the slivers come out as real pages
+            assert_eq!(images.len(), 5);
+            assert!(images
+                .iter()
+                .all(|image| image.dimensions.0 > 0 && image.dimensions.1 > 0));
+            assert_eq!(warnings.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_rotate_every_page() {
+        // a portrait page, dark at the top
+        let page = GrayImage::from_fn(60, 100, |_, y| Luma([if y < 50 { 0 } else { 255 }]));
+        let turned = |rotate| {
+            let config = ComicConfig {
+                device: DevicePreset {
+                    name: "test".into(),
+                    dimensions: (100, 60),
+                },
+                split: SplitStrategy::None,
+                auto_crop: false,
+                rotate,
+                ..ComicConfig::default()
+            };
+            let pages = process_image(
+                DynamicImage::ImageLuma8(page.clone()),
+                &config,
+                Path::new("001.png"),
+            );
+            assert_eq!(pages.len(), 1);
+            pages[0].to_luma8()
+        };
+
+        // clockwise, the top ends up on the right
+        let cw = turned(RotateMode::Cw90);
+        assert!(cw.width() > cw.height());
+        assert!(cw.get_pixel(cw.width() * 3 / 4, cw.height() / 2)[0] < 128);
+        assert!(cw.get_pixel(cw.width() / 4, cw.height() / 2)[0] > 128);
+
+        let ccw = turned(RotateMode::Ccw90);
+        assert!(ccw.get_pixel(ccw.width() / 4, ccw.height() / 2)[0] < 128);
+
+        let flipped = turned(RotateMode::Rotate180);
+        assert!(flipped.height() > flipped.width());
+        assert!(flipped.get_pixel(flipped.width() / 2, flipped.height() * 3 / 4)[0] < 128);
+        assert_eq!(RotateMode::Cw90.dimensions((60, 100)), (100, 60));
+    }
+
+    #[test]
+    fn test_panorama_splits() {
+        // three bands, dark to light from the left
+        let foldout =
+            |width: u32| GrayImage::from_fn(width, 100, |x, _| Luma([(x * 3 / width * 120) as u8]));
+        let config = ComicConfig {
+            device: DevicePreset {
+                name: "test".into(),
+                dimensions: (40, 60),
+            },
+            split: SplitStrategy::Split,
+            right_to_left: true,
+            auto_crop: false,
+            panorama_splits: vec![(2.2, 3), (4.0, 4)],
+            ..ComicConfig::default()
+        };
+        let centers = |img: GrayImage| {
+            process_image_view(&img, &config)
+                .iter()
+                .map(|page| page.get_pixel(page.width() / 2, page.height() / 2)[0])
+                .collect::<Vec<_>>()
+        };
+
+        // right to left, so the lightest panel comes first
+        assert_eq!(centers(foldout(300)), [240, 120, 0]);
+        assert_eq!(centers(foldout(200)).len(), 2);
+        assert_eq!(centers(foldout(450)).len(), 4);
+
+        let transforms: Vec<_> = (0..3)
+            .flat_map(|ii| split_transforms(&config, (300, 100), ii, 3))
+            .collect();
+        assert_eq!(
+            transforms,
+            [
+                "panel 3 of 3 from the left",
+                "panel 2 of 3 from the left",
+                "panel 1 of 3 from the left"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let spread = |name: &str| {
+            let mut data = Vec::new();
+            compress_to_png(
+                &DynamicImage::ImageLuma8(GrayImage::from_pixel(80, 40, Luma([90]))),
+                &mut data,
+                PngCompression::Fast,
+            )
+            .unwrap();
+            Ok(ArchiveFile {
+                file_name: name.into(),
+                data,
+            })
+        };
+        // out of order, the numbers follow the sorted names
+        let archive = [spread("003.png"), spread("001.png"), spread("002.png")];
+        // a range past the last page is fine
+        let config = ComicConfig {
+            device: DevicePreset {
+                name: "test".into(),
+                dimensions: (40, 60),
+            },
+            split: SplitStrategy::Split,
+            auto_crop: false,
+            split_ranges: vec![2..3, 9..12],
+            ..ComicConfig::default()
+        };
+        let (tx, _rx) = mpsc::channel();
+        let names = ["003.png".into(), "001.png".into(), "002.png".into()];
+        let (images, _) = process_archive_images(
+            archive.into_iter(),
+            &names,
+            config,
+            dir.path(),
+            0,
+            &tx,
+            1,
+            &Workers::default(),
+        )
+        .unwrap();
+
+        let sources: Vec<_> = images.iter().map(|image| image.source.clone()).collect();
+        assert_eq!(
+            sources,
+            [
+                PathBuf::from("001.png"),
+                PathBuf::from("002.png"),
+                PathBuf::from("002.png"),
+                PathBuf::from("003.png"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scale_modes() {
+        let mut config = ComicConfig {
+            device: DevicePreset {
+                name: "test".into(),
+                dimensions: (100, 150),
+            },
+            ..ComicConfig::default()
+        };
+        // a square page with a dark left half
+        let img = GrayImage::from_fn(200, 200, |x, _| Luma([if x < 100 { 0 } else { 255 }]));
+
+        let fit = resize_image(&img, &config, None);
+        assert_eq!(fit.dimensions(), (100, 100));
+
+        config.scale_mode = ScaleMode::Fill;
+        let fill = resize_image(&img, &config, Some(Luma([255])));
+        assert_eq!(fill.dimensions(), (100, 150));
+        // scaled to 150x150 and cropped to the middle, both halves still show
+        assert_eq!(fill.get_pixel(0, 75)[0], 0);
+        assert_eq!(fill.get_pixel(99, 75)[0], 255);
+
+        config.scale_mode = ScaleMode::Stretch;
+        let stretch = resize_image(&img, &config, None);
+        assert_eq!(stretch.dimensions(), (100, 150));
+        assert_eq!(stretch.get_pixel(10, 0)[0], 0);
+
+        // nothing to crop out of an empty page
+        config.scale_mode = ScaleMode::Fill;
+        let empty = resize_image(&GrayImage::new(0, 40), &config, None);
+        assert_eq!(empty.dimensions(), (0, 40));
+        // a page narrower than the panels asked for gets one per column
+        let sliver = GrayImage::new(2, 40);
+        assert_eq!(split_panels(&sliver, 3).len(), 2);
+    }
+
+    #[test]
+    fn test_dimension_multiple() {
+        let config = ComicConfig {
+            device: DevicePreset {
+                name: "test".into(),
+                dimensions: (100, 150),
+            },
+            dimension_multiple: Some(16),
+            ..ComicConfig::default()
+        };
+        let img = GrayImage::from_pixel(333, 411, Luma([128]));
+
+        let resized = resize_image(&img, &config, None);
+        assert_eq!(resized.dimensions(), (96, 112));
+        let padded = resize_image(&img, &config, Some(Luma([255])));
+        assert_eq!(padded.dimensions(), (96, 144));
+
+        assert_eq!(round_down(10, Some(16)), 10);
+        assert_eq!(round_down(47, None), 47);
+    }
+
+    #[test]
+    fn test_contact_sheet_grid() {
+        let dir = tempfile::tempdir().unwrap();
+        let images: Vec<_> = (0..3)
+            .map(|i| {
+                let path = dir.path().join(format!("{i}.png"));
+                GrayImage::from_pixel(100, 150, Luma([40 * i as u8]))
+                    .save(&path)
+                    .unwrap();
+                ProcessedImage {
+                    path,
+                    dimensions: (100, 150),
+                    ..ProcessedImage::default()
+                }
+            })
+            .collect();
+
+        let sheet = ContactSheetConfig {
+            columns: 2,
+            thumb_width: 50,
+        };
+        let path = dir.path().join("sheet.jpg");
+        contact_sheet(&images, &sheet, &path).unwrap();
+
+        // 2 columns and 2 rows of 50x75 thumbnails with 4px gaps
+        let sheet = imageproc::image::open(&path).unwrap();
+        assert_eq!(sheet.dimensions(), (112, 162));
+    }
+
+    #[test]
+    fn test_auto_invert() {
+        // thin black lines on white, like lettering and line art
+        let page = GrayImage::from_fn(400, 600, |x, y| {
+            let margin = x < 30 || y < 30 || x >= 370 || y >= 570;
+            let line = (x % 24 < 2 && y % 90 < 70) || (y % 40 < 2 && x % 70 < 50);
+            Luma([if line && !margin { 0 } else { 255 }])
+        });
+        let mut negative = page.clone();
+        imageops::invert(&mut negative);
+
+        assert!(!looks_inverted(&page));
+        assert!(looks_inverted(&negative));
+
+        // dark, but the light areas are wide panels rather than lines
+        let night = GrayImage::from_fn(400, 600, |x, y| {
+            let panel = (60..340).contains(&x) && (y % 200) < 80;
+            Luma([if panel { 230 } else { 20 }])
+        });
+        assert!(!looks_inverted(&night));
+
+        let config = ComicConfig {
+            auto_invert: true,
+            ..ComicConfig::default()
+        };
+        let img = DynamicImage::ImageLuma8(negative);
+        assert!(needs_inverting(&img, &config));
+        let corrected = process_image(img, &config, Path::new("negative.png"));
+        let corrected = corrected[0].to_luma8();
+        let mean = corrected.iter().map(|&v| v as u64).sum::<u64>() / corrected.len() as u64;
+        assert!(mean > 200, "mean {mean}");
+    }
+
+    #[test]
+    fn test_debug_identity() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(300, 200, |x, y| {
+            Rgb([x as u8, y as u8, 90])
+        }));
+        let config = ComicConfig {
+            debug_identity: true,
+            ..ComicConfig::default()
+        };
+
+        let pages = process_image(img.clone(), &config, Path::new("001.jpg"));
+        assert_eq!(pages, vec![img]);
+    }
+
+    #[test]
+    fn test_channel_gamma() {
+        let yellowed = RgbImage::from_pixel(2, 2, Rgb([128, 128, 64]));
+        // lifting only blue brings the paper back to neutral
+        let balanced = channel_gamma(yellowed.clone(), [1.0, 1.0, 0.5]);
+        assert_eq!(balanced.get_pixel(0, 0).0, [128, 128, 128]);
+
+        // no correction leaves the pixels untouched
+        let same = channel_gamma(yellowed.clone(), [1.0; 3]);
+        assert_eq!(same, yellowed);
+    }
+
+    #[test]
+    fn test_page_timeout() {
+        let slow = with_timeout(Some(0), || {
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(())
+        });
+        assert!(slow.is_err());
+        assert_eq!(with_timeout(Some(5), || Ok(1)).unwrap(), 1);
+        assert_eq!(with_timeout(None, || Ok(2)).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_jpeg_subsampling() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(32, 32, Rgb([200, 40, 40])));
+
+        // sampling factors of the luma component in the baseline frame header
+        let luma_sampling = |subsampling| {
+            let mut jpeg = Vec::new();
+            compress_to_jpeg(&img, &mut jpeg, 90, None, subsampling, false).unwrap();
+            load_from_memory(&jpeg).unwrap();
+            let sof = jpeg.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+            jpeg[sof + 11]
+        };
+
+        assert_eq!(luma_sampling(JpegSubsampling::Chroma444), 0x11);
+        assert_eq!(luma_sampling(JpegSubsampling::Chroma422), 0x21);
+        assert_eq!(luma_sampling(JpegSubsampling::Chroma420), 0x22);
+    }
+
+    #[test]
+    fn test_jpeg_progressive() {
+        // progressive JPEGs start their frame with SOF2 instead of the baseline SOF0
+        let markers = |img: &DynamicImage, progressive| {
+            let mut jpeg = Vec::new();
+            compress_to_jpeg(
+                img,
+                &mut jpeg,
+                90,
+                None,
+                JpegSubsampling::Chroma420,
+                progressive,
+            )
+            .unwrap();
+            let decoded = load_from_memory(&jpeg).unwrap();
+            assert_eq!(decoded.dimensions(), img.dimensions());
+            let has = |marker| jpeg.windows(2).any(|w| w == [0xFF, marker]);
+            (has(0xC0), has(0xC2))
+        };
+
+        let gray =
+            DynamicImage::ImageLuma8(GrayImage::from_fn(32, 48, |x, y| Luma([(x * y) as u8])));
+        let color = DynamicImage::ImageRgb8(RgbImage::from_pixel(32, 32, Rgb([200, 40, 40])));
+        for img in [&gray, &color] {
+            assert_eq!(markers(img, false), (true, false));
+            assert_eq!(markers(img, true), (false, true));
+        }
+    }
+
+    #[test]
+    fn test_search_jpeg_quality() {
+        // noisy pages so the encoded size depends on quality
+        let pages: Vec<ArchiveFile> = (0..4u32)
+            .map(|page| {
+                let img = GrayImage::from_fn(200, 300, |x, y| {
+                    let hash = (x * 31 + y * 17 + page * 7).wrapping_mul(2654435761);
+                    Luma([(hash >> 24) as u8])
+                });
+                let mut data = Vec::new();
+                compress_to_png(
+                    &DynamicImage::ImageLuma8(img),
+                    &mut data,
+                    PngCompression::Fast,
+                )
+                .unwrap();
+                ArchiveFile {
+                    file_name: format!("pages/{page}.png").into(),
+                    data,
+                }
+            })
+            .collect();
+        let config = ComicConfig {
+            device: DevicePreset {
+                name: "test".into(),
+                dimensions: (200, 300),
+            },
+            ..ComicConfig::default()
+        };
+        let search = |target| {
+            search_jpeg_quality(pages.clone().into_iter().map(Ok), &config, 4, target).unwrap()
+        };
+
+        // every page is sampled, so the projection is exact
+        let at_60 = ComicConfig {
+            image_format: ImageFormat::Jpeg { quality: 60 },
+            ..config.clone()
+        };
+        let size_at_60: usize = pages
+            .iter()
+            .flat_map(|page| {
+                let img = load_image(&page.data, config.cmyk_mode).unwrap();
+                process_image(img, &config, &page.file_name)
+            })
+            .map(|img| encode_image(&img, &at_60, None).unwrap().len())
+            .sum();
+
+        assert_eq!(search(u64::MAX), 100);
+        assert_eq!(search(0), 10);
+        let quality = search(size_at_60 as u64);
+        assert!((60..100).contains(&quality), "quality {quality}");
+    }
+
+    #[cfg(feature = "icc")]
+    #[test]
+    fn test_icc_profile_converted_to_srgb() {
+        use imageproc::image::{codecs::png::PngEncoder, ImageEncoder};
+
+        let green = [60, 180, 60];
+        let img = RgbImage::from_pixel(8, 8, Rgb(green));
+        let mut data = Vec::new();
+        let mut encoder = PngEncoder::new(&mut data);
+        let adobe_rgb = moxcms::ColorProfile::new_adobe_rgb().encode().unwrap();
+        encoder.set_icc_profile(adobe_rgb).unwrap();
+        encoder
+            .write_image(img.as_raw(), 8, 8, ColorType::Rgb8.into())
+            .unwrap();
+
+        let ignored = load_page(&data, CmykMode::Auto, false).unwrap();
+        assert_eq!(ignored.to_rgb8().get_pixel(0, 0).0, green);
+
+        // Adobe RGB's green is wider, so the same values are a more saturated sRGB green
+        let [r, g, b] = load_page(&data, CmykMode::Auto, true)
+            .unwrap()
+            .to_rgb8()
+            .get_pixel(0, 0)
+            .0;
+        assert!(r < 60 && g > 180 && b < 60, "got ({r}, {g}, {b})");
+
+        // without a profile there's nothing to convert
+        let mut plain = Vec::new();
+        compress_to_png(
+            &DynamicImage::ImageRgb8(img),
+            &mut plain,
+            PngCompression::Fast,
+        )
+        .unwrap();
+        let untouched = load_page(&plain, CmykMode::Auto, true).unwrap();
+        assert_eq!(untouched.to_rgb8().get_pixel(0, 0).0, green);
+    }
+
+    #[test]
+    fn test_cmyk_jpeg_colors() {
+        // pure red: no cyan or black, full magenta and yellow
+        let ink = [0, 255, 255, 0];
+        let inverted = ink.map(|v| 255 - v);
+
+        let adobe = load_image(&create_cmyk_jpeg(inverted, true), CmykMode::Auto).unwrap();
+        let plain = load_image(&create_cmyk_jpeg(ink, false), CmykMode::Auto).unwrap();
+        let forced = load_image(&create_cmyk_jpeg(inverted, false), CmykMode::Inverted).unwrap();
+
+        for img in [adobe, plain, forced] {
+            let rgb = img.to_rgb8();
+            let [r, g, b] = rgb.get_pixel(4, 4).0;
+            assert!(
+                r >= 250 && g <= 5 && b <= 5,
+                "expected red, got ({r}, {g}, {b})"
+            );
+        }
+    }
+
+    #[test]
+    fn test_dpi_written_to_headers() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_pixel(16, 16, Luma([128])));
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut config = ComicConfig {
+            image_format: ImageFormat::Jpeg { quality: 85 },
+            dpi: Some(300),
+            ..ComicConfig::default()
+        };
+
+        let jpeg = dir.path().join("page.jpg");
+        save_image(&img, &jpeg, &config, None, &Workers::default()).unwrap();
+        let jpeg = std::fs::read(&jpeg).unwrap();
+        // JFIF APP0: units 1 (inches) followed by x and y density
+        let jfif = jpeg.windows(5).position(|w| w == b"JFIF\0").unwrap();
+        assert_eq!(&jpeg[jfif + 7..jfif + 12], &[1, 0x01, 0x2C, 0x01, 0x2C]);
+
+        let png = dir.path().join("page.png");
+        config.image_format = ImageFormat::Png {
+            compression: PngCompression::Fast,
+        };
+        save_image(&img, &png, &config, None, &Workers::default()).unwrap();
+        let png = std::fs::read(&png).unwrap();
+        // 300 dpi is 11811 pixels per meter
+        let phys = png.windows(4).position(|w| w == b"pHYs").unwrap();
+        assert_eq!(&png[phys + 4..phys + 8], &11811u32.to_be_bytes());
+        // the decoder verifies the chunk CRC
+        load_from_memory(&png).unwrap();
+    }
+
+    #[test]
+    fn test_auto_grayscale_mixed_pages() {
+        let mut config = ComicConfig {
+            auto_grayscale: true,
+            auto_crop: false,
+            split: SplitStrategy::None,
+            ..ComicConfig::default()
+        };
+        config.device.dimensions = (32, 48);
+
+        let cover = DynamicImage::ImageRgb8(RgbImage::from_fn(32, 48, |x, _| {
+            if x < 16 {
+                Rgb([220, 30, 40])
+            } else {
+                Rgb([20, 60, 200])
+            }
+        }));
+        // black and white page scanned on yellowed paper
+        let interior = DynamicImage::ImageRgb8(RgbImage::from_fn(32, 48, |_, y| {
+            let v = (y * 5) as u8;
+            Rgb([v, v, v.saturating_sub(12)])
+        }));
+
+        let processed = process_image(cover.clone(), &config, Path::new("cover.jpg"));
+        assert_eq!(processed[0].color(), ColorType::Rgb8);
+        let processed = process_image(interior, &config, Path::new("page.jpg"));
+        assert_eq!(processed[0].color(), ColorType::L8);
+
+        config.auto_grayscale = false;
+        let processed = process_image(cover, &config, Path::new("cover.jpg"));
+        assert_eq!(processed[0].color(), ColorType::L8);
+    }
+
+    #[test]
+    fn test_watermark_sized_to_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let stamp = dir.path().join("stamp.png");
+        RgbaImage::from_pixel(20, 10, Rgba([0, 0, 0, 255]))
+            .save(&stamp)
+            .unwrap();
+        let watermark = Watermark::new(&WatermarkConfig {
+            image: Some(stamp),
+            opacity: 0.5,
+            scale: 0.5,
+            ..WatermarkConfig::default()
+        })
+        .unwrap();
+
+        let mut page = DynamicImage::ImageLuma8(GrayImage::from_pixel(200, 300, Luma([255])));
+        watermark.apply(&mut page);
+        let page = page.into_luma8();
+
+        // 100x50 in the bottom right corner, 5px off the edges, half covering the white
+        assert_eq!(page.get_pixel(150, 270)[0], 128);
+        assert_eq!(page.get_pixel(96, 246)[0], 128);
+        assert_eq!(page.get_pixel(94, 270)[0], 255);
+        assert_eq!(page.get_pixel(196, 296)[0], 255);
+        assert_eq!(page.get_pixel(10, 10)[0], 255);
+
+        assert!(Watermark::new(&WatermarkConfig::default()).is_err());
+    }
+
+    /// Baseline 8x8 CMYK JPEG of a single flat color, optionally with an Adobe APP14 segment
+    fn create_cmyk_jpeg(cmyk: [u8; 4], adobe: bool) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8];
+        let mut segment = |marker: u8, body: &[u8]| {
+            jpeg.extend_from_slice(&[0xFF, marker]);
+            jpeg.extend_from_slice(&(body.len() as u16 + 2).to_be_bytes());
+            jpeg.extend_from_slice(body);
+        };
+
+        if adobe {
+            // version 100, no flags, transform 0 (CMYK)
+            segment(0xEE, b"Adobe\x00\x64\x00\x00\x00\x00\x00");
+        }
+        // quantization table of ones so DC coefficients map directly to sample values
+        segment(0xDB, &[[0u8].as_slice(), &[1; 64]].concat());
+        segment(
+            0xC0,
+            &[
+                8, 0, 8, 0, 8, 4, 1, 0x11, 0, 2, 0x11, 0, 3, 0x11, 0, 4, 0x11, 0,
+            ],
+        );
+        // DC: categories 0-11 as 4 bit codes, AC: end-of-block as the single 1 bit code
+        let mut dc = vec![0x00, 0, 0, 0, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        dc.extend(0..12);
+        segment(0xC4, &dc);
+        let mut ac = vec![0x10, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        ac.push(0x00);
+        segment(0xC4, &ac);
+        segment(0xDA, &[4, 1, 0, 2, 0, 3, 0, 4, 0, 0, 63, 0]);
+
+        let mut bits = Vec::new();
+        for value in cmyk {
+            let dc = (value as i32 - 128) * 8;
+            let category = 32 - dc.unsigned_abs().leading_zeros();
+            let magnitude = if dc < 0 { dc - 1 } else { dc };
+            bits.extend((0..4).rev().map(|i| (category >> i) & 1 == 1));
+            bits.extend((0..category).rev().map(|i| (magnitude >> i) & 1 == 1));
+            bits.push(false);
+        }
+        // pad the final byte with ones
+        bits.resize(bits.len().div_ceil(8) * 8, true);
+        for byte in bits.chunks(8) {
+            let byte = byte.iter().fold(0u8, |acc, &bit| (acc << 1) | bit as u8);
+            jpeg.push(byte);
+            if byte == 0xFF {
+                jpeg.push(0x00);
+            }
+        }
+
+        jpeg.extend_from_slice(&[0xFF, 0xD9]);
+        jpeg
+    }
+
     /// Create a test image with known margins and content
     fn create_test_image(
         width: u32,
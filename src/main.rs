@@ -1,10 +1,14 @@
 mod cbz_builder;
 mod comic;
 mod comic_archive;
+#[cfg(feature = "console-progress")]
+mod console;
 mod epub_builder;
 mod image_processor;
 mod mobi_converter;
+mod page_cache;
 mod pipeline;
+mod progress;
 mod tui;
 
 use anyhow::Context;
@@ -20,7 +24,7 @@ use std::{
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
-use crate::comic::{ComicConfig, ProgressEvent};
+use crate::comic::{ComicConfig, ComicStage, ProgressEvent};
 use crate::tui::config::ConfigEvent;
 
 #[derive(Parser, Debug)]
@@ -30,21 +34,64 @@ use crate::tui::config::ConfigEvent;
     version
 )]
 struct Args {
-    /// Optional directory to scan for manga files (defaults to current directory)
-    directory: Option<PathBuf>,
+    /// Directories to scan for manga files (defaults to current directory).
+    /// With several, each one gets its own folder in the output directory
+    directories: Vec<PathBuf>,
 
-    /// Optional output directory (defaults to input {directory}/comically)
+    /// Optional output directory (defaults to the first input {directory}/comically)
     #[arg(short, long)]
     output: Option<PathBuf>,
 
     /// Enable debug logging to file
     #[arg(long)]
     debug: bool,
+
+    /// Print the supported input and output formats and exit
+    #[arg(long)]
+    list_formats: bool,
+
+    /// Check that every input opens and has pages, list the ones that don't and exit
+    #[arg(long)]
+    check: bool,
+
+    /// Convert one archive with the saved config into a scratch directory, print how long
+    /// each stage took and exit
+    #[arg(long, value_name = "ARCHIVE")]
+    bench: Option<PathBuf>,
+
+    /// Write a small JPEG of an archive's first page, for previewing inputs, and exit
+    #[arg(long, num_args = 2, value_names = ["ARCHIVE", "OUTPUT"])]
+    thumbnail: Option<Vec<PathBuf>>,
+
+    /// Process every input with the saved config, showing a progress bar instead of the TUI
+    #[cfg(feature = "console-progress")]
+    #[arg(long)]
+    no_tui: bool,
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
+    if args.list_formats {
+        print_formats();
+        return Ok(());
+    }
+
+    if args.check {
+        return check_inputs(args.directories);
+    }
+
+    if let Some(path) = args.bench {
+        return bench(&path);
+    }
+
+    if let Some([archive, output]) = args.thumbnail.as_deref() {
+        return comic_archive::first_page_thumbnail(archive, 300)?
+            .into_rgb8()
+            .save(output)
+            .with_context(|| format!("Failed to write {}", output.display()));
+    }
+
     // Only initialize file logging if --debug flag is set
     if args.debug {
         let log_path = "comically.log";
@@ -93,6 +140,11 @@ fn main() -> anyhow::Result<()> {
         env::set_var("PATH", new_path);
     }
 
+    #[cfg(feature = "console-progress")]
+    if args.no_tui {
+        return console::run(args.directories, args.output);
+    }
+
     let theme = tui::Theme::detect();
 
     let mut terminal = ratatui::init_with_options(ratatui::TerminalOptions {
@@ -119,7 +171,7 @@ fn main() -> anyhow::Result<()> {
     });
 
     tui::run(
-        args.directory,
+        args.directories,
         args.output,
         &mut terminal,
         picker,
@@ -137,6 +189,72 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+fn print_formats() {
+    println!("input:");
+    for format in comic_archive::supported_input_formats() {
+        println!("  {:<10} .{}", format.name, format.extension);
+    }
+    println!("output:");
+    for format in comic::supported_output_formats() {
+        let note = match format.extension {
+            "mobi" => match mobi_converter::version() {
+                Some(version) => format!("  (KindleGen {version})"),
+                None => "  (KindleGen not found)".to_string(),
+            },
+            _ => String::new(),
+        };
+        let extension = match format.extension {
+            "" => "(directory)".to_string(),
+            extension => format!(".{extension}"),
+        };
+        println!("  {:<10} {}{}", format.name, extension, note);
+    }
+}
+
+fn bench(path: &std::path::Path) -> anyhow::Result<()> {
+    let config = ComicConfig::load().unwrap_or_default();
+    let timings = pipeline::bench_process(path, config)?;
+    for stage in [
+        ComicStage::Process,
+        ComicStage::Package,
+        ComicStage::Convert,
+    ] {
+        let duration = timings.stage(stage);
+        if !duration.is_zero() {
+            println!("{:<10} {:>8.3}s", stage, duration.as_secs_f64());
+        }
+    }
+    println!("{:<10} {:>8.3}s", "total", timings.total().as_secs_f64());
+    Ok(())
+}
+
+fn check_inputs(directories: Vec<PathBuf>) -> anyhow::Result<()> {
+    let directories = if directories.is_empty() {
+        vec![env::current_dir()?]
+    } else {
+        directories
+    };
+
+    let (mut checked, mut problems) = (0, 0);
+    for dir in &directories {
+        for path in comic_archive::comic_candidates(dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?
+        {
+            checked += 1;
+            if let Err(e) = comic_archive::check_input(&path) {
+                problems += 1;
+                println!("{}: {:#}", path.display(), e);
+            }
+        }
+    }
+
+    if problems > 0 {
+        anyhow::bail!("{} of {} inputs can't be processed", problems, checked);
+    }
+    println!("all {} inputs look good", checked);
+    Ok(())
+}
+
 fn input_handling(tx: mpsc::Sender<Event>, dimensions: Size) {
     const TICK_RATE: Duration = Duration::from_millis(200);
 
@@ -193,11 +311,20 @@ pub enum Event {
     Key(event::KeyEvent),
     Tick,
     Resize(Option<ratatui_image::picker::Picker>),
-    Progress(ProgressEvent),
+    // `seq` increases with every progress event sent, whichever thread sent it
+    Progress {
+        seq: u64,
+        event: ProgressEvent,
+    },
     Config(ConfigEvent),
     StartProcessing {
-        files: Vec<PathBuf>,
+        roots: Vec<pipeline::InputRoot>,
         config: ComicConfig,
         output_dir: PathBuf,
     },
+    // an output is about to be replaced, the answer goes back through `reply`
+    ConfirmOverwrite {
+        path: PathBuf,
+        reply: mpsc::Sender<pipeline::OverwriteDecision>,
+    },
 }
@@ -1,9 +1,11 @@
 use anyhow::{Context, Result};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
-use crate::comic::Comic;
+use crate::comic::{Comic, ComicError, OutputFormat};
+
+const KINDLEGEN: &str = "kindlegen";
 
 /// Converts an EPUB file to MOBI using Amazon's KindleGen
 pub fn create_mobi(comic: &Comic) -> Result<SpawnedKindleGen> {
@@ -13,32 +15,71 @@ pub fn create_mobi(comic: &Comic) -> Result<SpawnedKindleGen> {
         anyhow::bail!("EPUB file does not exist: {}", epub_path.display());
     }
 
-    let child = Command::new("kindlegen")
-        .arg("-dont_append_source")
-        .arg("-c1")
-        .arg("-locale")
-        .arg("en")
-        .arg(&epub_path)
+    let scratch = ScratchDir(comic.epub_dir().join("kindlegen-tmp"));
+    fs::create_dir_all(&scratch.0).context("Failed to create KindleGen temp dir")?;
+
+    let child = kindlegen_command(&epub_path, &scratch.0)
         .stdout(std::process::Stdio::piped())
         .stderr(std::process::Stdio::piped())
         .spawn()
-        .context("Failed to execute KindleGen")?;
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => anyhow::Error::new(ComicError::KindleGenMissing),
+            _ => anyhow::Error::new(e).context("Failed to execute KindleGen"),
+        })?;
 
     let spawned = SpawnedKindleGen {
         child,
         mobi_file: comic.epub_file().with_extension("mobi"),
-        output_mobi: comic.output_path(),
+        output_mobi: comic.output_path(OutputFormat::Mobi),
+        scratch,
     };
 
     Ok(spawned)
 }
 
+/// KindleGen chokes on non-ASCII and space-containing paths, like a temp dir under a
+/// user folder with a Japanese name. It's run from the EPUB's folder and only sees the
+/// EPUB's ASCII file name, the MOBI is moved to the real output path afterwards.
+/// Its temp files go to `scratch` rather than the system temp dir
+fn kindlegen_command(epub_path: &Path, scratch: &Path) -> Command {
+    let mut command = Command::new(KINDLEGEN);
+    for var in ["TMPDIR", "TMP", "TEMP"] {
+        command.env(var, scratch);
+    }
+    command
+        .arg("-dont_append_source")
+        .arg("-c1")
+        .arg("-locale")
+        .arg("en")
+        .arg(epub_path.file_name().unwrap_or(epub_path.as_os_str()));
+    if let Some(dir) = epub_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        command.current_dir(dir);
+    }
+    command
+}
+
 pub struct SpawnedKindleGen {
     child: std::process::Child,
     /// generated by KindleGen
     mobi_file: PathBuf,
     /// where the mobi file will be moved to
     output_mobi: PathBuf,
+    /// KindleGen's temp files, removed once the conversion is over however it ended
+    scratch: ScratchDir,
+}
+
+/// A directory removed when dropped. KindleGen leaves its temp files behind when it
+/// crashes or is killed, and those would otherwise pile up over a batch
+struct ScratchDir(PathBuf);
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        match fs::remove_dir_all(&self.0) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Failed to remove {}: {}", self.0.display(), e),
+        }
+    }
 }
 
 impl SpawnedKindleGen {
@@ -47,8 +88,18 @@ impl SpawnedKindleGen {
         Ok(output)
     }
 
+    pub fn kill(&mut self) {
+        if let Err(e) = self.child.kill() {
+            log::warn!("Failed to kill KindleGen: {}", e);
+        }
+        // reaped so it lets go of its temp files before they're removed
+        let _ = self.child.wait();
+    }
+
     pub fn wait(self) -> Result<()> {
         let output = self.child.wait_with_output()?;
+        // KindleGen has exited, its temp files aren't needed whatever the outcome
+        drop(self.scratch);
         let output_str = String::from_utf8_lossy(&output.stdout);
         let has_error_output = output_str.lines().any(|line| line.starts_with("Error("));
         let kindlegen_status = output.status.code();
@@ -99,7 +150,57 @@ impl SpawnedKindleGen {
     }
 }
 
-/// Checks if KindleGen is available in the PATH
-pub fn is_kindlegen_available() -> bool {
-    Command::new("kindlegen").arg("-version").output().is_ok()
+/// Checks if KindleGen is available in the PATH, so MOBI can be offered up front
+/// rather than failing for every comic
+pub fn is_available() -> bool {
+    Command::new(KINDLEGEN).arg("-version").output().is_ok()
+}
+
+/// KindleGen's version, like "2.9", `None` when it isn't installed
+pub fn version() -> Option<String> {
+    let output = Command::new(KINDLEGEN).arg("-version").output().ok()?;
+    parse_version(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// From the banner KindleGen prints first, "Amazon kindlegen(Linux) V2.9 build 1028-0897292"
+fn parse_version(banner: &str) -> Option<String> {
+    let line = banner.lines().find(|line| line.contains("kindlegen"))?;
+    line.split_whitespace()
+        .find_map(|word| word.strip_prefix('V'))
+        .filter(|version| version.starts_with(|c: char| c.is_ascii_digit()))
+        .map(str::to_string)
+}
+
+#[test]
+fn kindlegen_only_sees_ascii_paths() {
+    let epub = Path::new("/tmp/ワンピース 第1巻/EPUB/book.epub");
+    let command = kindlegen_command(epub, Path::new("/tmp/kindlegen-tmp"));
+
+    assert!(command.get_args().all(|arg| arg
+        .to_str()
+        .is_some_and(|arg| arg.is_ascii() && !arg.contains(' '))));
+    assert_eq!(command.get_current_dir(), epub.parent());
+}
+
+#[test]
+fn kindlegen_temp_files_are_removed() {
+    let dir = tempfile::tempdir().unwrap();
+    let scratch = dir.path().join("kindlegen-tmp");
+    fs::create_dir_all(scratch.join("mdp_1234")).unwrap();
+    fs::write(scratch.join("mdp_1234").join("book.tmp"), b"left over").unwrap();
+
+    let command = kindlegen_command(&dir.path().join("book.epub"), &scratch);
+    assert!(command
+        .get_envs()
+        .any(|(var, value)| var == "TMPDIR" && value == Some(scratch.as_os_str())));
+
+    drop(ScratchDir(scratch.clone()));
+    assert!(!scratch.exists());
+}
+
+#[test]
+fn kindlegen_version_is_read_from_its_banner() {
+    let banner = "*************************************************************\n Amazon kindlegen(Linux) V2.9 build 1028-0897292 \n A command line e-book compiler \n";
+    assert_eq!(parse_version(banner).as_deref(), Some("2.9"));
+    assert_eq!(parse_version("command not found"), None);
 }
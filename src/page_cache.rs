@@ -0,0 +1,207 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::comic::{ComicConfig, ImageFormat, ProcessedImage};
+
+/// Settings that only change how pages are packaged, so they're left out of the key.
+/// Anything else, including settings added later, gets a new cache entry
+const OUTPUT_ONLY: &[&str] = &[
+    "output_format",
+    "additional_formats",
+    "format_codecs",
+    "format_dirs",
+    "cover_only",
+    "reader_profile",
+    "images_per_page",
+    "record_encoding",
+    "producer_tag",
+    "comic_logs",
+    "progress_granularity",
+    "page_events",
+    "decode_threads",
+    "encode_threads",
+    "max_concurrent_writes",
+    "batch_size_budget",
+    "create_output_dir",
+    "series_folders",
+    "error_sidecar",
+    "bundle",
+    "contact_sheet",
+    "metadata_command",
+    "page_cache",
+    "overwrite",
+    "seed",
+    "page_manifest",
+    "batch_plan",
+    "duplicate_check",
+    // its settings are already copied into the other fields
+    "profile",
+];
+
+const MANIFEST: &str = "pages.json";
+
+/// Processed pages kept on disk between runs, keyed by the input's contents and the
+/// settings that shape its pages, so another output format can reuse them
+pub struct PageCache {
+    dir: PathBuf,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    // `target_size` picks the quality while processing, the pages were written with this one
+    image_format: ImageFormat,
+    pages: Vec<ProcessedImage>,
+    warnings: Vec<String>,
+}
+
+pub struct CachedPages {
+    pub image_format: ImageFormat,
+    pub pages: Vec<ProcessedImage>,
+    pub warnings: Vec<String>,
+}
+
+impl PageCache {
+    pub fn new(root: &Path, input: &Path, config: &ComicConfig) -> Result<Self> {
+        let key = format!(
+            "{:016x}{:016x}",
+            hash_file(input)?,
+            fnv1a(FNV_OFFSET, transform_settings(config)?.as_bytes())
+        );
+        Ok(Self {
+            dir: root.join(key),
+        })
+    }
+
+    /// The cached pages, `None` when there are none or they're incomplete
+    pub fn load(&self) -> Option<CachedPages> {
+        let manifest = fs::read_to_string(self.dir.join(MANIFEST)).ok()?;
+        let manifest: Manifest = serde_json::from_str(&manifest).ok()?;
+        let pages: Vec<_> = manifest
+            .pages
+            .into_iter()
+            .map(|page| ProcessedImage {
+                path: self.dir.join(&page.path),
+                ..page
+            })
+            .collect();
+        if !pages.iter().all(|page| page.path.is_file()) {
+            return None;
+        }
+        Some(CachedPages {
+            image_format: manifest.image_format,
+            pages,
+            warnings: manifest.warnings,
+        })
+    }
+
+    /// Copies the pages into the cache. They're written next to it first and moved in
+    /// once complete, so an interrupted run never leaves a partial entry
+    pub fn store(
+        &self,
+        image_format: ImageFormat,
+        pages: &[ProcessedImage],
+        warnings: &[String],
+    ) -> Result<()> {
+        let root = self.dir.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(root).context("Failed to create page cache")?;
+        let staging = tempfile::tempdir_in(root)?;
+
+        let mut cached = Vec::with_capacity(pages.len());
+        for (i, page) in pages.iter().enumerate() {
+            // pages from different archive folders can share a file name, so they're
+            // numbered in their order instead
+            let extension = page.path.extension().unwrap_or_default();
+            let name = PathBuf::from(format!("{i:05}.{}", extension.to_string_lossy()));
+            fs::copy(&page.path, staging.path().join(&name))
+                .with_context(|| format!("Failed to cache {}", page.path.display()))?;
+            cached.push(ProcessedImage {
+                path: name,
+                ..page.clone()
+            });
+        }
+        let manifest = Manifest {
+            image_format,
+            pages: cached,
+            warnings: warnings.to_vec(),
+        };
+        fs::write(
+            staging.path().join(MANIFEST),
+            serde_json::to_string(&manifest)?,
+        )?;
+
+        // an entry from a concurrent run with the same key holds the same pages
+        if let Err(e) = fs::rename(staging.path(), &self.dir) {
+            if !self.dir.join(MANIFEST).is_file() {
+                return Err(e).context("Failed to add pages to the cache");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The settings that change the processed pages, as JSON
+fn transform_settings(config: &ComicConfig) -> Result<String> {
+    let mut value = serde_json::to_value(config)?;
+    if let Some(settings) = value.as_object_mut() {
+        for key in OUTPUT_ONLY {
+            settings.remove(*key);
+        }
+        // the processing code itself changes between versions
+        settings.insert("version".into(), env!("CARGO_PKG_VERSION").into());
+    }
+    Ok(value.to_string())
+}
+
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a, stable across builds unlike the std hasher, so keys survive upgrading Rust
+pub fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn hash_file(path: &Path) -> Result<u64> {
+    let mut file = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    let mut hash = FNV_OFFSET;
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            return Ok(hash);
+        }
+        hash = fnv1a(hash, &buf[..read]);
+    }
+}
+
+#[test]
+fn cache_key_ignores_output_settings() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("Book.cbz");
+    fs::write(&input, b"archive").unwrap();
+    let config = ComicConfig::default();
+    let key = |config: &ComicConfig| PageCache::new(dir.path(), &input, config).unwrap().dir;
+
+    let epub = ComicConfig {
+        output_format: crate::comic::OutputFormat::Epub,
+        images_per_page: 2,
+        ..config.clone()
+    };
+    assert_eq!(key(&config), key(&epub));
+
+    let brighter = ComicConfig {
+        brightness: 10,
+        ..config.clone()
+    };
+    assert_ne!(key(&config), key(&brighter));
+
+    let before = key(&config);
+    fs::write(&input, b"another archive").unwrap();
+    assert_ne!(before, key(&config));
+}
@@ -1,71 +1,695 @@
 use crate::{
     cbz_builder,
-    comic::{Comic, ComicConfig, ComicStage, ComicStatus, OutputFormat, ProgressEvent},
-    comic_archive, epub_builder, image_processor, mobi_converter, Event,
+    comic::{
+        is_disk_full, ByteSize, Comic, ComicConfig, ComicError, ComicStage, ComicStatus,
+        DuplicateCheck, ImageFormat, OutputFormat, OverwritePolicy, ProcessedImage, ProgressEvent,
+    },
+    comic_archive, epub_builder, image_processor, mobi_converter,
+    page_cache::PageCache,
+    progress::StageTimings,
+    Event,
 };
 use anyhow::Context;
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
+use regex::Regex;
 use std::{
-    path::PathBuf,
-    sync::mpsc,
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex, OnceLock,
+    },
     thread,
     time::{Duration, Instant},
 };
+use zip::{
+    write::{SimpleFileOptions, ZipWriter},
+    CompressionMethod,
+};
+
+/// Comics to stop while the rest of the batch goes on, by id. Clones share the same set
+#[derive(Clone, Default)]
+pub struct CancelComics(Arc<Mutex<HashSet<usize>>>);
+
+impl CancelComics {
+    /// Stops the comic at its next check, killing its KindleGen if it's converting
+    pub fn cancel(&self, id: usize) {
+        self.0.lock().unwrap().insert(id);
+    }
+
+    fn is_cancelled(&self, id: usize) -> bool {
+        self.0.lock().unwrap().contains(&id)
+    }
+}
+
+/// Holds the batch between comics and between pages while set, pages and KindleGen
+/// conversions already started still finish. Clones share the same switch
+#[derive(Clone, Default)]
+pub struct PauseBatch(Arc<(Mutex<bool>, Condvar)>);
+
+impl PauseBatch {
+    pub fn pause(&self) {
+        *self.0 .0.lock().unwrap() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.0 .0.lock().unwrap() = false;
+        self.0 .1.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.0 .0.lock().unwrap()
+    }
+
+    /// Blocks until the batch is resumed, returns right away when it isn't paused
+    fn wait(&self) {
+        let (paused, resumed) = &*self.0;
+        let _paused = resumed
+            .wait_while(paused.lock().unwrap(), |paused| *paused)
+            .unwrap();
+    }
+}
+
+/// Set when a comic fails with `fail_fast` enabled or runs out of disk space,
+/// stops the rest of the batch. Single comics are stopped through `cancelled`
+#[derive(Clone)]
+pub struct AbortSignal {
+    aborted: Arc<AtomicBool>,
+    fail_fast: bool,
+    cancelled: CancelComics,
+}
+
+impl AbortSignal {
+    fn new(fail_fast: bool, cancelled: CancelComics) -> Self {
+        Self {
+            aborted: Arc::default(),
+            fail_fast,
+            cancelled,
+        }
+    }
+
+    /// Record a failed comic, aborting the batch if `fail_fast` is set. A full disk always
+    /// aborts, the comics left would only fail the same way and can be rerun once there's room.
+    /// A cancelled comic was stopped on purpose, so it never aborts the others
+    fn failed(&self, id: usize, disk_full: bool) {
+        if (self.fail_fast && !self.cancelled.is_cancelled(id)) || disk_full {
+            self.aborted.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Relaxed)
+    }
+
+    /// Whether comic `id` should stop, with the batch or on its own
+    fn is_stopped(&self, id: usize) -> bool {
+        self.is_aborted() || self.cancelled.is_cancelled(id)
+    }
+
+    fn check(&self, id: usize) -> anyhow::Result<()> {
+        if self.is_aborted() {
+            return Err(ComicError::Cancelled.into());
+        }
+        if self.cancelled.is_cancelled(id) {
+            return Err(ComicError::CancelledByUser.into());
+        }
+        Ok(())
+    }
+}
+
+/// Convert the comic at `path` with `config` into a scratch directory and time each stage,
+/// for measuring performance changes on a fixture archive
+pub fn bench_process(path: &Path, config: ComicConfig) -> anyhow::Result<StageTimings> {
+    let output_dir = tempfile::tempdir().context("Failed to create a scratch output")?;
+    let roots = vec![InputRoot {
+        root: path.parent().unwrap_or(Path::new("")).to_path_buf(),
+        files: vec![path.to_path_buf()],
+        ..InputRoot::default()
+    }];
+    let (event_tx, event_rx) = mpsc::channel();
+    process_files(
+        roots,
+        config,
+        output_dir.path().to_path_buf(),
+        event_tx,
+        None,
+    )?;
+
+    let mut timings = StageTimings::new();
+    for event in event_rx.try_iter() {
+        let Event::Progress {
+            event: ProgressEvent::ComicUpdate { status, .. },
+            ..
+        } = event
+        else {
+            continue;
+        };
+        match status {
+            ComicStatus::ImageProcessingComplete { duration } => {
+                timings.add_stage(ComicStage::Process, duration)
+            }
+            ComicStatus::StageCompleted { stage, duration } => timings.add_stage(stage, duration),
+            ComicStatus::Failed { error } => return Err(error.into()),
+            _ => {}
+        }
+    }
+    Ok(timings)
+}
+
+/// Output bytes written by successful comics, against `batch_size_budget`
+#[derive(Clone)]
+pub struct SizeBudget {
+    limit: Option<u64>,
+    used: Arc<AtomicU64>,
+    // titles of the comics skipped once the budget was used up
+    skipped: Arc<Mutex<Vec<String>>>,
+}
+
+impl SizeBudget {
+    fn new(limit: Option<ByteSize>) -> Self {
+        Self {
+            limit: limit.map(|limit| limit.0),
+            used: Arc::default(),
+            skipped: Arc::default(),
+        }
+    }
+
+    fn add(&self, bytes: u64) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Fails `comic` when the budget is used up, before any work is done on it
+    fn admit(&self, comic: &Comic) -> bool {
+        let spent = self
+            .limit
+            .is_some_and(|limit| self.used.load(Ordering::Relaxed) >= limit);
+        if spent {
+            self.skipped.lock().unwrap().push(comic.title.clone());
+            comic.failed(ComicError::OverBudget.into());
+        }
+        !spent
+    }
+}
+
+/// Comics found in one input directory
+#[derive(Debug, Clone, Default)]
+pub struct InputRoot {
+    pub root: PathBuf,
+    pub files: Vec<PathBuf>,
+    // output directory for single files, used as is instead of mirroring their folder
+    pub output_overrides: HashMap<PathBuf, PathBuf>,
+    // title for single files, named in the metadata and the outputs instead of the file name
+    pub titles: HashMap<PathBuf, String>,
+}
+
+/// Series a comic belongs to, its file name up to the volume or chapter number
+fn series_name(file: &Path) -> Option<String> {
+    static SERIES: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?i)^(.+?)[\s_-]+(?:v|vol\.?|volume|c|ch\.?|chapter|#)?\s*\d+").unwrap()
+    });
+
+    let stem = file.file_stem()?.to_string_lossy();
+    let series = SERIES.captures(&stem)?.get(1)?.as_str().trim();
+    (!series.is_empty()).then(|| series.to_string())
+}
+
+/// Pairs every comic with its output directory, mirroring its folder below its root.
+/// With several roots each one gets a folder named after it, keeping their structures apart.
+/// `series_folders` adds a folder per series, and overrides win over both
+fn output_dirs(
+    roots: Vec<InputRoot>,
+    output_dir: &Path,
+    series_folders: bool,
+) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+    let separate = roots.len() > 1;
+    let mut names = HashMap::new();
+    let mut inputs = Vec::new();
+
+    for InputRoot {
+        root,
+        files,
+        mut output_overrides,
+        ..
+    } in roots
+    {
+        let mut dir = output_dir.to_path_buf();
+        if separate {
+            let name = root
+                .file_name()
+                .with_context(|| format!("Can't name an output folder after {}", root.display()))?;
+            if let Some(other) = names.insert(name.to_os_string(), root.clone()) {
+                anyhow::bail!(
+                    "Inputs {} and {} would share the output folder {}",
+                    other.display(),
+                    root.display(),
+                    name.to_string_lossy()
+                );
+            }
+            dir.push(name);
+        }
+
+        for file in files {
+            if let Some(output) = output_overrides.remove(&file) {
+                inputs.push((file, output));
+                continue;
+            }
+
+            let relative = file
+                .parent()
+                .and_then(|parent| parent.strip_prefix(&root).ok())
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            let mut output = dir.join(relative);
+            if let Some(series) = series_folders.then(|| series_name(&file)).flatten() {
+                output.push(series);
+            }
+            inputs.push((file, output));
+        }
+    }
+
+    Ok(inputs)
+}
+
+/// Writes extra metadata for a packaged comic, `output` is one of its output files.
+/// The built-in ComicInfo and OPF metadata are written by the builders either way
+pub trait MetadataWriter: Send + Sync {
+    fn write(&self, comic: &Comic, output: &Path) -> anyhow::Result<()>;
+}
 
+impl<F> MetadataWriter for F
+where
+    F: Fn(&Comic, &Path) -> anyhow::Result<()> + Send + Sync,
+{
+    fn write(&self, comic: &Comic, output: &Path) -> anyhow::Result<()> {
+        self(comic, output)
+    }
+}
+
+pub type MetadataWriters = Arc<Vec<Box<dyn MetadataWriter>>>;
+
+/// What to do with an output that already exists
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverwriteDecision {
+    Overwrite,
+    Skip,
+    // this one and every later output, without asking again
+    OverwriteAll,
+    SkipAll,
+}
+
+/// Asked before a comic replaces one of its existing outputs, like a UI prompting yes/no/all.
+/// Calls come one at a time, even from comics processed in parallel
+pub trait OverwriteHook: Send + Sync {
+    fn decide(&self, output: &Path) -> OverwriteDecision;
+}
+
+impl<F> OverwriteHook for F
+where
+    F: Fn(&Path) -> OverwriteDecision + Send + Sync,
+{
+    fn decide(&self, output: &Path) -> OverwriteDecision {
+        self(output)
+    }
+}
+
+/// Extension points for callers embedding the pipeline
+#[derive(Default)]
+pub struct Hooks {
+    // run after the ones from the config once each comic is packaged
+    pub writers: Vec<Box<dyn MetadataWriter>>,
+    // the config's `overwrite` policy decides without one
+    pub on_overwrite: Option<Box<dyn OverwriteHook>>,
+    // comics to stop partway through, keep a clone to cancel them while the batch runs
+    pub cancel: CancelComics,
+    // keep a clone to hold the batch and let it go on later
+    pub pause: PauseBatch,
+}
+
+/// How each comic in a batch ended, for callers that need more than the events
+#[derive(Debug, Default)]
+pub struct BatchSummary {
+    pub succeeded: usize,
+    // titles of the comics not started, their output existed or the size budget ran out
+    pub skipped: Vec<String>,
+    // title and error of every comic that failed, cancelled ones included
+    pub failed: Vec<(String, String)>,
+}
+
+impl BatchSummary {
+    pub fn total(&self) -> usize {
+        self.succeeded + self.skipped.len() + self.failed.len()
+    }
+
+    /// Passes the events on to `tx` while recording each comic's outcome,
+    /// until `ProcessingComplete`
+    fn forward(events: mpsc::Receiver<Event>, tx: mpsc::Sender<Event>) -> Self {
+        let mut summary = Self::default();
+        let mut titles = HashMap::new();
+        for event in events {
+            let mut complete = false;
+            if let Event::Progress { event, .. } = &event {
+                match event {
+                    ProgressEvent::RegisterComic { id, file_name } => {
+                        titles.insert(*id, file_name.clone());
+                    }
+                    ProgressEvent::ComicUpdate { id, status } => {
+                        let title = titles.get(id).cloned().unwrap_or_default();
+                        match status {
+                            ComicStatus::Success { .. }
+                            | ComicStatus::CompletedWithWarnings { .. } => summary.succeeded += 1,
+                            ComicStatus::Failed {
+                                error: ComicError::OverBudget | ComicError::OutputExists(_),
+                            } => summary.skipped.push(title),
+                            ComicStatus::Failed { error } => {
+                                summary.failed.push((title, error.to_string()))
+                            }
+                            _ => {}
+                        }
+                    }
+                    ProgressEvent::ProcessingComplete => complete = true,
+                    _ => {}
+                }
+            }
+            // the caller may have stopped listening, the batch goes on regardless
+            let _ = tx.send(event);
+            if complete {
+                break;
+            }
+        }
+        summary
+    }
+}
+
+/// Checks a comic's existing outputs against the hook, or the policy without one
+struct OverwriteGuard {
+    hook: Option<Box<dyn OverwriteHook>>,
+    // the answer for the rest of the batch once an "all" was picked, also serializes the hook
+    all: Mutex<Option<bool>>,
+}
+
+impl OverwriteGuard {
+    fn new(hook: Option<Box<dyn OverwriteHook>>, policy: OverwritePolicy) -> Self {
+        let all = match (&hook, policy) {
+            (Some(_), _) => None,
+            (None, OverwritePolicy::Overwrite) => Some(true),
+            (None, OverwritePolicy::Skip) => Some(false),
+        };
+        Self {
+            hook,
+            all: Mutex::new(all),
+        }
+    }
+
+    /// Fails `comic` when one of its outputs exists and mustn't be replaced,
+    /// before any work is done on it
+    fn admit(&self, comic: &Comic) -> bool {
+        for output in comic.outputs().into_iter().filter(|output| output.exists()) {
+            let mut all = self.all.lock().unwrap();
+            let overwrite = match (*all, &self.hook) {
+                (Some(overwrite), _) => overwrite,
+                (None, Some(hook)) => match hook.decide(&output) {
+                    OverwriteDecision::Overwrite => true,
+                    OverwriteDecision::Skip => false,
+                    OverwriteDecision::OverwriteAll => *all.insert(true),
+                    OverwriteDecision::SkipAll => *all.insert(false),
+                },
+                (None, None) => true,
+            };
+            if !overwrite {
+                comic.failed(ComicError::OutputExists(output).into());
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Runs `metadata_command` for an output
+struct CommandWriter(Vec<String>);
+
+impl MetadataWriter for CommandWriter {
+    fn write(&self, comic: &Comic, output: &Path) -> anyhow::Result<()> {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        let Some((program, args)) = self.0.split_first() else {
+            anyhow::bail!("metadata_command is empty");
+        };
+        let description = serde_json::json!({
+            "title": comic.title,
+            "input": comic.input,
+            "output": output,
+            "pages": comic.processed_files.len(),
+            "image_format": comic.config.image_format,
+        });
+
+        let mut child = Command::new(program)
+            .args(args)
+            .arg(output)
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {}", program))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(description.to_string().as_bytes())?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            anyhow::bail!("{} exited with {}", program, status);
+        }
+        Ok(())
+    }
+}
+
+/// The writers enabled in the config
+fn metadata_writers(config: &ComicConfig) -> Vec<Box<dyn MetadataWriter>> {
+    let mut writers: Vec<Box<dyn MetadataWriter>> = Vec::new();
+    if let Some(command) = &config.metadata_command {
+        writers.push(Box::new(CommandWriter(command.clone())));
+    }
+    writers
+}
+
+/// Package `format` with its own codec from `format_codecs`, using the pages encoded with it
+/// while processing or re-encoding the processed ones when there are none
+fn with_codec(
+    comic: &mut Comic,
+    format: OutputFormat,
+    workers: &image_processor::Workers,
+    build: impl FnOnce(&Comic) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let codec = comic.config.codec(format);
+    if codec == comic.config.image_format {
+        return build(comic);
+    }
+
+    let encoded: Vec<_> = comic
+        .processed_files
+        .iter()
+        .map(|image| ProcessedImage {
+            path: image_processor::codec_path(&comic.processed_dir, &image.path, codec),
+            ..image.clone()
+        })
+        .collect();
+    let images = if encoded.iter().all(|image| image.path.exists()) {
+        encoded
+    } else {
+        if !comic.config.image_format.is_lossless() {
+            comic.warn(format!(
+                "Re-encoding lossy {} pages as {} for {:?}, quality is lost twice",
+                comic.config.image_format.extension(),
+                codec.extension(),
+                format
+            ));
+        }
+        image_processor::transcode(
+            &comic.processed_files,
+            &comic.processed_dir,
+            codec,
+            &comic.config,
+            workers,
+        )?
+    };
+    let primary = std::mem::replace(&mut comic.processed_files, images);
+    let primary_codec = std::mem::replace(&mut comic.config.image_format, codec);
+    let built = build(comic);
+    comic.processed_files = primary;
+    comic.config.image_format = primary_codec;
+    built
+}
+
+/// Lists every page with the archive entry it came from, in output order
+fn write_page_manifest(comic: &Comic) -> anyhow::Result<()> {
+    let pages: Vec<_> = comic
+        .processed_files
+        .iter()
+        .enumerate()
+        .map(|(i, page)| {
+            serde_json::json!({
+                "page": i + 1,
+                "source": page.source,
+                "format": page.path.extension().map(|ext| ext.to_string_lossy().to_lowercase()),
+                "transforms": page.transforms,
+            })
+        })
+        .collect();
+    let path = comic.sidecar_path("pages.json");
+    std::fs::write(&path, serde_json::to_string_pretty(&pages)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// A failing writer leaves the outputs in place, so it's a warning rather than a failure
+fn write_metadata(comic: &mut Comic, writers: &[Box<dyn MetadataWriter>]) {
+    for output in comic.outputs() {
+        for writer in writers {
+            if let Err(e) = writer.write(comic, &output) {
+                comic.warn(format!(
+                    "Failed to write metadata for {}: {}",
+                    output.display(),
+                    e
+                ));
+            }
+        }
+    }
+}
+
+/// Runs on `pool` when given, otherwise on rayon's global pool.
+/// Blocks until every comic is done, MOBI conversions included, and
+/// sends `ProcessingComplete` right before returning.
+/// Returns how each comic ended, or an error if the batch was aborted by `fail_fast`
 pub fn process_files(
-    files: Vec<PathBuf>,
+    roots: Vec<InputRoot>,
     config: ComicConfig,
     output_dir: PathBuf,
     event_tx: mpsc::Sender<Event>,
-) {
-    log::info!("processing with config: {:?}", config);
-    log::info!("processing {} files", files.len());
+    pool: Option<&rayon::ThreadPool>,
+) -> anyhow::Result<BatchSummary> {
+    process_files_with_hooks(roots, config, output_dir, event_tx, pool, Hooks::default())
+}
 
-    let (kindlegen_tx, kindlegen_rx) = mpsc::channel::<Comic>();
+/// `process_files` with extra metadata writers and an overwrite prompt
+pub fn process_files_with_hooks(
+    mut roots: Vec<InputRoot>,
+    config: ComicConfig,
+    output_dir: PathBuf,
+    event_tx: mpsc::Sender<Event>,
+    pool: Option<&rayon::ThreadPool>,
+    hooks: Hooks,
+) -> anyhow::Result<BatchSummary> {
+    let mut all_writers = metadata_writers(&config);
+    all_writers.extend(hooks.writers);
+    let writers: MetadataWriters = Arc::new(all_writers);
+    log::info!("processing with config: {:?}", config);
+    let num_files: usize = roots.iter().map(|root| root.files.len()).sum();
+    log::info!("processing {} files", num_files);
+    let mut titles: HashMap<_, _> = roots
+        .iter_mut()
+        .flat_map(|root| std::mem::take(&mut root.titles))
+        .collect();
 
-    if config.output_format == OutputFormat::Mobi {
-        let event_tx = event_tx.clone();
-        thread::spawn(move || {
-            poll_kindlegen(kindlegen_rx);
-            // after all the comics have finished conversion to mobi, send the complete event
-            event_tx
-                .send(Event::Progress(ProgressEvent::ProcessingComplete))
-                .unwrap();
-        });
+    // a single output file is written as given
+    let single_file = OutputFormat::infer_from_path(&output_dir)?.is_some();
+    let output_root = match output_dir.parent() {
+        Some(parent) if single_file => parent,
+        _ => &output_dir,
+    };
+    if !output_root.as_os_str().is_empty() && !output_root.exists() {
+        if !config.create_output_dir {
+            anyhow::bail!(
+                "Output directory {} doesn't exist, create it or enable create_output_dir",
+                output_root.display()
+            );
+        }
+        std::fs::create_dir_all(output_root)
+            .with_context(|| format!("Failed to create {}", output_root.display()))?;
     }
 
-    let comics: Vec<_> = files
+    let files = if single_file {
+        if num_files > 1 {
+            anyhow::bail!(
+                "Output {} is a single file, but {} comics were given",
+                output_dir.display(),
+                num_files
+            );
+        }
+        roots
+            .into_iter()
+            .flat_map(|root| root.files)
+            .map(|file| (file, output_dir.clone()))
+            .collect()
+    } else {
+        output_dirs(roots, &output_dir, config.series_folders)?
+    };
+
+    let abort = AbortSignal::new(config.fail_fast, hooks.cancel);
+    let pause = hooks.pause;
+    let budget = SizeBudget::new(config.batch_size_budget);
+    let overwrite = OverwriteGuard::new(hooks.on_overwrite, config.overwrite);
+    let workers = image_processor::Workers::new(&config)?;
+
+    // every event goes out through the summary, which sees how each comic ends
+    let (summary_tx, summary_rx) = mpsc::channel();
+    let summary = thread::spawn(move || BatchSummary::forward(summary_rx, event_tx));
+    let event_tx = summary_tx;
+
+    // spawned once the first comic is ready for MOBI conversion, joined before returning
+    let kindlegen = OnceLock::<(mpsc::Sender<Comic>, thread::JoinHandle<Vec<PathBuf>>)>::new();
+    let spawn_kindlegen = || {
+        let (tx, rx) = mpsc::channel();
+        let abort = abort.clone();
+        let pause = pause.clone();
+        let writers = writers.clone();
+        let budget = budget.clone();
+        (
+            tx,
+            thread::spawn(move || poll_kindlegen(rx, abort, pause, writers, budget)),
+        )
+    };
+
+    let mut comics: Vec<_> = files
         .into_iter()
         .enumerate()
-        .filter_map(|(id, file)| {
-            let title = file
-                .file_stem()
-                .unwrap_or_default()
-                .to_string_lossy()
-                .to_string();
+        .filter_map(|(id, (file, output_dir))| {
+            let title = titles.remove(&file).unwrap_or_else(|| {
+                file.file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string()
+            });
 
             event_tx
-                .send(Event::Progress(ProgressEvent::RegisterComic {
-                    id,
-                    file_name: title.clone(),
-                }))
+                .send(
+                    ProgressEvent::RegisterComic {
+                        id,
+                        file_name: title.clone(),
+                    }
+                    .into_event(),
+                )
                 .unwrap();
 
             match Comic::new(
                 id,
                 file.clone(),
-                output_dir.clone(),
+                output_dir,
                 title,
                 config.clone(),
                 event_tx.clone(),
             ) {
                 Ok(comic) => Some(comic),
                 Err(e) => {
+                    abort.failed(id, is_disk_full(&e));
                     event_tx
-                        .send(Event::Progress(ProgressEvent::ComicUpdate {
-                            id,
-                            status: ComicStatus::Failed { error: e },
-                        }))
+                        .send(
+                            ProgressEvent::ComicUpdate {
+                                id,
+                                status: ComicStatus::Failed { error: e.into() },
+                            }
+                            .into_event(),
+                        )
                         .unwrap();
                     None
                 }
@@ -73,81 +697,438 @@ pub fn process_files(
         })
         .collect();
 
-    comics
-        .into_iter()
-        .par_bridge()
-        .filter_map(|mut comic| {
-            let images = comic.with_try(|comic| {
-                let archive_iter = comic_archive::unarchive_comic_iter(&comic.input)?;
-                let num_images = archive_iter.num_images();
-                let start = comic.image_processing_start(num_images);
-                let images = image_processor::process_archive_images(
-                    archive_iter,
-                    config.clone(),
-                    comic.processed_dir(),
-                    comic.id,
-                    &comic.tx,
-                )?;
-                comic.image_processing_complete(start.elapsed());
-                Ok(images)
-            })?;
-
-            log::info!("Processed {} images for {}", images.len(), comic.title);
-
-            comic.processed_files = images;
-
-            match config.output_format {
-                OutputFormat::Cbz => {
-                    // For CBZ, package directly to CBZ
-                    comic.with_try(|comic| {
-                        let start = comic.update_status(ComicStage::Package, 75.0);
-                        cbz_builder::build_cbz(comic)?;
-                        comic.stage_completed(ComicStage::Package, start.elapsed());
-                        comic.success();
-                        Ok(())
-                    })?;
+    // fingerprinted on the batch's workers like the comics themselves
+    match pool {
+        Some(pool) => pool.install(|| warn_duplicates(&mut comics, config.duplicate_check)),
+        None => warn_duplicates(&mut comics, config.duplicate_check),
+    }
+
+    if config.batch_plan {
+        let limit = config.preview_pages.unwrap_or(usize::MAX);
+        let count = || {
+            comics
+                .par_iter()
+                .map(|comic| {
+                    comic_archive::num_pages(
+                        &comic.input,
+                        config.archive_format,
+                        config.nested_archives,
+                    )
+                    .map(|pages| pages.min(limit))
+                    .unwrap_or(0)
+                })
+                .sum()
+        };
+        // counted on the batch's workers like the comics themselves
+        let total_pages = match pool {
+            Some(pool) => pool.install(count),
+            None => count(),
+        };
+        let _ = event_tx.send(
+            ProgressEvent::BatchPlan {
+                total_comics: num_files,
+                total_pages,
+            }
+            .into_event(),
+        );
+    }
+
+    let process = || {
+        // one task per comic, which reads its archive and queues its pages on the same
+        // pool, so a single huge comic and many small ones both keep every core busy
+        comics
+            .into_par_iter()
+            .with_max_len(1)
+            .filter_map(|mut comic| {
+                pause.wait();
+                // comics turned away never start
+                if !overwrite.admit(&comic) || !budget.admit(&comic) {
+                    return None;
                 }
-                OutputFormat::Epub => {
-                    comic.with_try(|comic| {
-                        let start = comic.update_status(ComicStage::Package, 75.0);
-                        epub_builder::build_epub(comic)?;
+                comic.started();
+                let images = comic.with_try(|comic| {
+                    abort.check(comic.id)?;
+                    let cache = config
+                        .page_cache
+                        .as_deref()
+                        .map(|root| PageCache::new(root, &comic.input, &config))
+                        .transpose()?;
+                    if let Some(cached) = cache.as_ref().and_then(PageCache::load) {
+                        let start = comic.image_processing_start(cached.pages.len());
+                        comic.config.image_format = cached.image_format;
+                        for message in cached.warnings {
+                            comic.warn(message);
+                        }
+                        comic.log(log::Level::Info, "Reusing cached pages");
+                        comic.image_processing_complete(start.elapsed());
+                        return Ok(cached.pages);
+                    }
+                    let archive_iter =
+                        comic_archive::unarchive_comic_iter(&comic.input, config.archive_format)?;
+                    let limit = config.preview_pages.unwrap_or(usize::MAX);
+                    let exclude = config
+                        .exclude_pages
+                        .as_deref()
+                        .map(comic_archive::exclude_pattern)
+                        .transpose()?;
+                    let pages = |archive: comic_archive::ArchiveIter| {
+                        let pages =
+                            comic_archive::with_nested_archives(archive, config.nested_archives);
+                        let pages = comic_archive::without_excluded(pages, exclude.clone());
+                        let pages =
+                            comic_archive::without_thumbnails(pages, config.min_page_dimension);
+                        let pages: Box<dyn Iterator<Item = _> + Send> = if config.merge_spreads {
+                            Box::new(comic_archive::merge_spreads(
+                                pages,
+                                config.right_to_left,
+                                config.cmyk_mode,
+                            ))
+                        } else {
+                            Box::new(pages)
+                        };
+                        pages.take(limit)
+                    };
+                    let num_images = if config.nested_archives > 0 {
+                        comic_archive::num_pages(
+                            &comic.input,
+                            config.archive_format,
+                            config.nested_archives,
+                        )?
+                    } else {
+                        archive_iter.num_images()
+                    }
+                    .min(limit);
+                    let start = comic.image_processing_start(num_images);
+                    match (config.target_size, config.image_format) {
+                        (Some(target), ImageFormat::Jpeg { .. }) => {
+                            // first pass over a sample of pages, the second one below writes them
+                            let sample = comic_archive::unarchive_comic_iter(
+                                &comic.input,
+                                config.archive_format,
+                            )?;
+                            let quality = image_processor::search_jpeg_quality(
+                                pages(sample),
+                                &config,
+                                num_images,
+                                target.0,
+                            )?;
+                            comic.config.image_format = ImageFormat::Jpeg { quality };
+                        }
+                        (Some(_), _) => {
+                            comic.warn(
+                                "target_size only applies to JPEG pages, ignoring it".to_string(),
+                            );
+                        }
+                        (None, _) => {}
+                    }
+                    if config.color_profiles && !cfg!(feature = "icc") {
+                        comic.warn(
+                            "color_profiles needs comically built with the icc feature, ignoring it"
+                                .to_string(),
+                        );
+                    }
+                    if config.shared_crop && config.auto_crop && config.crop_margins.is_none() {
+                        let measure = comic_archive::unarchive_comic_iter(
+                            &comic.input,
+                            config.archive_format,
+                        )?;
+                        comic.config.crop_margins =
+                            image_processor::shared_crop_margins(pages(measure), &config);
+                    }
+                    let page_names = if config.split_ranges.is_empty() {
+                        Vec::new()
+                    } else {
+                        archive_iter.page_names()
+                    };
+                    let (images, warnings) = image_processor::process_archive_images(
+                        // hold while paused, stop reading pages once the batch is aborted
+                        pages(archive_iter).take_while(|_| {
+                            pause.wait();
+                            !abort.is_stopped(comic.id)
+                        }),
+                        &page_names,
+                        comic.config.clone(),
+                        comic.processed_dir(),
+                        comic.id,
+                        &comic.tx,
+                        config.progress_granularity.step(num_images),
+                        &workers,
+                    )?;
+                    for message in &warnings {
+                        comic.warn(message.clone());
+                    }
+                    if images.is_empty() {
+                        return Err(ComicError::NoPages.into());
+                    }
+                    abort.check(comic.id)?;
+                    if let Some(cache) = &cache {
+                        if let Err(e) = cache.store(comic.config.image_format, &images, &warnings) {
+                            comic.warn(format!("Failed to cache pages: {:#}", e));
+                        }
+                    }
+                    comic.image_processing_complete(start.elapsed());
+                    Ok(images)
+                });
+                let Some(images) = images else {
+                    abort.failed(comic.id, comic.out_of_space);
+                    return None;
+                };
+
+                comic.log(
+                    log::Level::Info,
+                    format!("Processed {} images", images.len()),
+                );
+
+                comic.processed_files = images;
+
+                if config.page_manifest {
+                    if let Err(e) = write_page_manifest(&comic) {
+                        comic.warn(format!("Failed to write page manifest: {}", e));
+                    }
+                }
+
+                if let Some(sheet) = &config.contact_sheet {
+                    let path = comic.sidecar_path("contact.jpg");
+                    if let Err(e) =
+                        image_processor::contact_sheet(&comic.processed_files, sheet, &path)
+                    {
+                        comic.warn(format!("Failed to write contact sheet: {}", e));
+                    }
+                }
+
+                let packaged = comic.with_try(|comic| {
+                    abort.check(comic.id)?;
+                    if config.has_output_format(OutputFormat::Cbz) {
+                        let start = comic.update_status(ComicStage::Package, 75.0, "building CBZ");
+                        with_codec(comic, OutputFormat::Cbz, &workers, cbz_builder::build_cbz)?;
+                        comic.stage_completed(ComicStage::Package, start.elapsed());
+                    }
+                    if config.has_output_format(OutputFormat::Folder) {
+                        let start = comic.update_status(ComicStage::Package, 75.0, "copying pages");
+                        with_codec(
+                            comic,
+                            OutputFormat::Folder,
+                            &workers,
+                            cbz_builder::build_folder,
+                        )?;
+                        comic.stage_completed(ComicStage::Package, start.elapsed());
+                    }
+
+                    // MOBI is converted from the EPUB, so build it once for both
+                    // unless their pages use different codecs
+                    let is_mobi = config.has_output_format(OutputFormat::Mobi);
+                    let is_epub = config.has_output_format(OutputFormat::Epub);
+                    let shared = is_mobi
+                        && is_epub
+                        && config.codec(OutputFormat::Epub) == config.codec(OutputFormat::Mobi);
+                    let progress = if is_mobi { 50.0 } else { 75.0 };
+
+                    if is_epub {
+                        let step = if shared {
+                            "building EPUB, also for KindleGen"
+                        } else {
+                            "building EPUB"
+                        };
+                        let start = comic.update_status(ComicStage::Package, progress, step);
+                        with_codec(
+                            comic,
+                            OutputFormat::Epub,
+                            &workers,
+                            epub_builder::build_epub,
+                        )?;
                         comic.stage_completed(ComicStage::Package, start.elapsed());
 
-                        // Move EPUB to final destination
-                        let output_path = comic.output_path();
-                        std::fs::rename(comic.epub_file(), &output_path).with_context(|| {
+                        // Move EPUB to final destination, kindlegen still needs it for MOBI
+                        let output_path = comic.output_path(OutputFormat::Epub);
+                        let result = if shared {
+                            std::fs::copy(comic.epub_file(), &output_path).map(|_| ())
+                        } else {
+                            std::fs::rename(comic.epub_file(), &output_path)
+                        };
+                        result.with_context(|| {
                             format!("Failed to move EPUB to output: {:?}", output_path)
                         })?;
+                    }
 
-                        comic.success();
-                        Ok(())
-                    })?;
-                }
-                OutputFormat::Mobi => {
-                    comic.with_try(|comic| {
-                        let start = comic.update_status(ComicStage::Package, 50.0);
-                        epub_builder::build_epub(comic)?;
+                    if is_mobi && !shared {
+                        let start = comic.update_status(
+                            ComicStage::Package,
+                            progress,
+                            "building EPUB for KindleGen",
+                        );
+                        with_codec(
+                            comic,
+                            OutputFormat::Mobi,
+                            &workers,
+                            epub_builder::build_epub,
+                        )?;
                         comic.stage_completed(ComicStage::Package, start.elapsed());
-                        Ok(())
-                    })?;
+                    }
+
+                    Ok(())
+                });
+                if packaged.is_none() {
+                    abort.failed(comic.id, comic.out_of_space);
+                    return None;
+                }
+
+                // MOBI outputs are reported by the kindlegen thread once converted
+                if config.has_output_format(OutputFormat::Mobi) {
+                    let (kindlegen_tx, _) = kindlegen.get_or_init(spawn_kindlegen);
                     kindlegen_tx.send(comic).unwrap();
+                    None
+                } else {
+                    write_metadata(&mut comic, &writers);
+                    comic.success();
+                    budget.add(comic.stats().output_bytes);
+                    Some(comic.outputs())
                 }
+            })
+            .flatten()
+            .collect::<Vec<_>>()
+    };
+    let mut outputs = match pool {
+        Some(pool) => pool.install(process),
+        None => process(),
+    };
+
+    // closing the channel lets the kindlegen thread finish the pending conversions and exit
+    if let Some((kindlegen_tx, handle)) = kindlegen.into_inner() {
+        drop(kindlegen_tx);
+        match handle.join() {
+            Ok(converted) => outputs.extend(converted),
+            Err(_) => log::error!("kindlegen thread panicked"),
+        }
+    }
+
+    let mut bundled = Ok(());
+    if let Some(bundle) = &config.bundle {
+        outputs.sort();
+        match bundle_outputs(&outputs, &output_dir, bundle) {
+            Ok(bytes) => {
+                log::info!(
+                    "Bundled {} outputs into {:?}, {} bytes",
+                    outputs.len(),
+                    bundle,
+                    bytes
+                );
+                event_tx
+                    .send(
+                        ProgressEvent::Bundled {
+                            path: bundle.clone(),
+                            bytes,
+                        }
+                        .into_event(),
+                    )
+                    .unwrap();
             }
-            Some(())
-        })
-        .for_each(|_| {});
+            Err(e) => bundled = Err(e),
+        }
+    }
+    event_tx
+        .send(ProgressEvent::ProcessingComplete.into_event())
+        .unwrap();
+    let summary = summary
+        .join()
+        .map_err(|_| anyhow::anyhow!("Event forwarding panicked"))?;
 
-    match config.output_format {
-        OutputFormat::Epub | OutputFormat::Cbz => {
-            event_tx
-                .send(Event::Progress(ProgressEvent::ProcessingComplete))
-                .unwrap();
+    let skipped = budget.skipped.lock().unwrap();
+    if !skipped.is_empty() {
+        log::warn!(
+            "Batch size budget used up, skipped {} comics: {}",
+            skipped.len(),
+            skipped.join(", ")
+        );
+    }
+
+    if abort.is_aborted() {
+        anyhow::bail!("Batch aborted after a comic failed");
+    }
+    bundled.map(|_| summary)
+}
+
+/// Warns on each comic that looks like an earlier one in the batch, which only
+/// costs time and may write over its outputs. Nothing is skipped
+fn warn_duplicates(comics: &mut [Comic], check: DuplicateCheck) {
+    if check == DuplicateCheck::Off {
+        return;
+    }
+    let fingerprints: Vec<_> = comics
+        .par_iter()
+        .map(|comic| comic_archive::fingerprint(&comic.input, check))
+        .collect();
+
+    let mut seen = HashMap::new();
+    for (comic, fingerprint) in comics.iter_mut().zip(fingerprints) {
+        match fingerprint {
+            Ok(Some(fingerprint)) => match seen.get(&fingerprint) {
+                Some(first) => comic.warn(format!("Looks like a duplicate of {}", first)),
+                None => {
+                    seen.insert(fingerprint, comic.title.clone());
+                }
+            },
+            Ok(None) => {}
+            // unreadable inputs fail once they're processed
+            Err(e) => log::debug!("No fingerprint for {}: {:#}", comic.input.display(), e),
         }
-        _ => {}
     }
 }
 
-pub fn poll_kindlegen(tx: mpsc::Receiver<Comic>) {
+/// Stores the outputs in one zip at `path`, keeping their folders below `output_dir`.
+/// They're compressed already, so they are stored as they are. Returns the zip's size
+fn bundle_outputs(outputs: &[PathBuf], output_dir: &Path, path: &Path) -> anyhow::Result<u64> {
+    let file = File::create(path).with_context(|| format!("Failed to create bundle {:?}", path))?;
+    let mut zip = ZipWriter::new(BufWriter::new(file));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+
+    for output in outputs {
+        let relative = output
+            .strip_prefix(output_dir)
+            .ok()
+            .filter(|relative| !relative.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new(output.file_name().unwrap_or_default()));
+        let name: Vec<_> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+        let name = name.join("/");
+
+        // a folder output goes in with its pages
+        let files = if output.is_dir() {
+            let mut pages: Vec<_> = std::fs::read_dir(output)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<_, _>>()?;
+            pages.sort();
+            pages
+                .into_iter()
+                .map(|page| {
+                    let page_name = page.file_name().unwrap_or_default().to_string_lossy();
+                    (format!("{name}/{page_name}"), page)
+                })
+                .collect()
+        } else {
+            vec![(name, output.clone())]
+        };
+        for (name, path) in files {
+            zip.start_file(name, options)?;
+            let mut file = File::open(&path)
+                .with_context(|| format!("Failed to add {:?} to the bundle", path))?;
+            std::io::copy(&mut file, &mut zip)?;
+        }
+    }
+
+    zip.finish()?;
+    Ok(std::fs::metadata(path)?.len())
+}
+
+/// Converts the EPUBs it is sent to MOBI, returning the outputs of every comic that succeeded
+pub fn poll_kindlegen(
+    tx: mpsc::Receiver<Comic>,
+    abort: AbortSignal,
+    pause: PauseBatch,
+    writers: MetadataWriters,
+    budget: SizeBudget,
+) -> Vec<PathBuf> {
     struct KindleGenStatus {
         comic: Comic,
         spawned: mobi_converter::SpawnedKindleGen,
@@ -155,24 +1136,29 @@ pub fn poll_kindlegen(tx: mpsc::Receiver<Comic>) {
     }
 
     let mut pending = Vec::<Option<KindleGenStatus>>::new();
+    let mut outputs = Vec::new();
 
     'outer: loop {
-        loop {
+        // comics queue up while paused, the running conversions are still polled
+        while !pause.is_paused() {
             let result = tx.try_recv();
 
             match result {
                 Ok(mut comic) => {
                     let result = comic.with_try(|comic| {
-                        let start = comic.update_status(ComicStage::Convert, 75.0);
+                        abort.check(comic.id)?;
+                        let start =
+                            comic.update_status(ComicStage::Convert, 75.0, "running KindleGen");
                         let spawned = mobi_converter::create_mobi(comic)?;
                         Ok((spawned, start))
                     });
-                    if let Some((spawned, start)) = result {
-                        pending.push(Some(KindleGenStatus {
+                    match result {
+                        Some((spawned, start)) => pending.push(Some(KindleGenStatus {
                             comic,
                             spawned,
                             start,
-                        }));
+                        })),
+                        None => abort.failed(comic.id, comic.out_of_space),
                     }
                 }
                 Err(mpsc::TryRecvError::Disconnected) => {
@@ -190,6 +1176,11 @@ pub fn poll_kindlegen(tx: mpsc::Receiver<Comic>) {
 
         for s in pending.iter_mut() {
             let is_done = match s {
+                // no point finishing conversions for an aborted batch or a cancelled comic
+                Some(status) if abort.is_stopped(status.comic.id) => {
+                    status.spawned.kill();
+                    true
+                }
                 Some(status) => match status.spawned.try_wait() {
                     Ok(Some(_)) => true,
                     Ok(None) => false,
@@ -203,14 +1194,21 @@ pub fn poll_kindlegen(tx: mpsc::Receiver<Comic>) {
 
             if is_done {
                 if let Some(mut status) = s.take() {
-                    let _ = status.comic.with_try(|comic| {
-                        log::debug!("KindleGen process completed for: {}", comic.title);
+                    let result = status.comic.with_try(|comic| {
+                        abort.check(comic.id)?;
+                        comic.log(log::Level::Debug, "KindleGen process completed");
                         status.spawned.wait()?;
                         comic.stage_completed(ComicStage::Convert, status.start.elapsed());
+                        write_metadata(comic, &writers);
                         comic.success();
-                        log::debug!("MOBI conversion successful for: {}", comic.title);
+                        budget.add(comic.stats().output_bytes);
+                        comic.log(log::Level::Debug, "MOBI conversion successful");
                         Ok(())
                     });
+                    match result {
+                        Some(()) => outputs.extend(status.comic.outputs()),
+                        None => abort.failed(status.comic.id, status.comic.out_of_space),
+                    }
                 }
             }
         }
@@ -219,4 +1217,863 @@ pub fn poll_kindlegen(tx: mpsc::Receiver<Comic>) {
 
         thread::sleep(Duration::from_millis(100));
     }
+    outputs
+}
+
+#[test]
+fn output_dirs_mirror_each_root() {
+    let root = |root: &str, files: &[&str]| InputRoot {
+        root: root.into(),
+        files: files.iter().map(PathBuf::from).collect(),
+        ..InputRoot::default()
+    };
+    let output = Path::new("/out");
+
+    let single = output_dirs(
+        vec![root("/a/manga", &["/a/manga/x/v1.cbz"])],
+        output,
+        false,
+    )
+    .unwrap();
+    assert_eq!(single[0].1, PathBuf::from("/out/x"));
+
+    let roots = vec![
+        root("/a/manga", &["/a/manga/v1.cbz"]),
+        root("/b/comics", &["/b/comics/y/v2.cbz"]),
+    ];
+    let dirs: Vec<_> = output_dirs(roots, output, false)
+        .unwrap()
+        .into_iter()
+        .map(|(_, dir)| dir)
+        .collect();
+    assert_eq!(
+        dirs,
+        vec![PathBuf::from("/out/manga"), PathBuf::from("/out/comics/y")]
+    );
+
+    let clash = vec![root("/a/manga", &[]), root("/b/manga", &[])];
+    assert!(output_dirs(clash, output, false).is_err());
+}
+
+#[test]
+fn output_dirs_route_by_series_and_overrides() {
+    let mut manga = InputRoot {
+        root: "/a".into(),
+        files: vec!["/a/One Piece v01.cbz".into(), "/a/Dr. STONE v02.cbz".into()],
+        ..InputRoot::default()
+    };
+    manga
+        .output_overrides
+        .insert("/a/Dr. STONE v02.cbz".into(), "/shelf/to-read".into());
+
+    let dirs: Vec<_> = output_dirs(vec![manga], Path::new("/out"), true)
+        .unwrap()
+        .into_iter()
+        .map(|(_, dir)| dir)
+        .collect();
+    assert_eq!(
+        dirs,
+        vec![
+            PathBuf::from("/out/One Piece"),
+            PathBuf::from("/shelf/to-read")
+        ]
+    );
+
+    assert_eq!(
+        series_name(Path::new("Berserk Vol. 12 (Digital).cbz")).as_deref(),
+        Some("Berserk")
+    );
+    assert_eq!(series_name(Path::new("oneshot.cbz")), None);
+}
+
+#[test]
+fn bundle_keeps_output_folders() {
+    let dir = tempfile::tempdir().unwrap();
+    let output_dir = dir.path().join("out");
+    let outputs = [
+        output_dir.join("x").join("v1.cbz"),
+        output_dir.join("v2.epub"),
+    ];
+    for output in &outputs {
+        std::fs::create_dir_all(output.parent().unwrap()).unwrap();
+        std::fs::write(output, b"comic").unwrap();
+    }
+
+    let bundle = dir.path().join("bundle.zip");
+    let bytes = bundle_outputs(&outputs, &output_dir, &bundle).unwrap();
+    assert_eq!(bytes, std::fs::metadata(&bundle).unwrap().len());
+
+    let zip = zip::ZipArchive::new(File::open(&bundle).unwrap()).unwrap();
+    let mut names: Vec<_> = zip.file_names().collect();
+    names.sort();
+    assert_eq!(names, ["v2.epub", "x/v1.cbz"]);
+}
+
+#[test]
+fn missing_output_dir_stops_before_processing() {
+    let dir = tempfile::tempdir().unwrap();
+    let output_dir = dir.path().join("missing").join("out");
+    let (tx, _rx) = mpsc::channel();
+
+    let config = ComicConfig {
+        create_output_dir: false,
+        ..ComicConfig::default()
+    };
+    let error = process_files(vec![], config, output_dir.clone(), tx.clone(), None).unwrap_err();
+    assert!(error.to_string().contains("doesn't exist"));
+    assert!(!output_dir.exists());
+
+    process_files(vec![], ComicConfig::default(), output_dir.clone(), tx, None).unwrap();
+    assert!(output_dir.is_dir());
+}
+
+/// A two page CBZ and a config that converts it to a small CBZ
+#[cfg(test)]
+fn test_comic(path: &Path) -> ComicConfig {
+    use crate::comic::DevicePreset;
+    use std::io::Write;
+
+    let mut zip = ZipWriter::new(File::create(path).unwrap());
+    for page in ["001.png", "002.png"] {
+        let mut png = Vec::new();
+        imageproc::image::GrayImage::from_pixel(100, 150, imageproc::image::Luma([90]))
+            .write_to(
+                &mut std::io::Cursor::new(&mut png),
+                imageproc::image::ImageFormat::Png,
+            )
+            .unwrap();
+        zip.start_file(page, SimpleFileOptions::default()).unwrap();
+        zip.write_all(&png).unwrap();
+    }
+    zip.finish().unwrap();
+
+    ComicConfig {
+        output_format: OutputFormat::Cbz,
+        device: DevicePreset {
+            name: "test".into(),
+            dimensions: (40, 60),
+        },
+        ..ComicConfig::default()
+    }
+}
+
+/// What a batch run by `run_batch` left behind
+#[cfg(test)]
+struct TestBatch {
+    summary: BatchSummary,
+    events: Vec<ProgressEvent>,
+    output_dir: PathBuf,
+}
+
+#[cfg(test)]
+impl TestBatch {
+    /// Warnings containing `text`, with the id of the comic each is about
+    fn warnings(&self, text: &str) -> Vec<(usize, String)> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                ProgressEvent::Warning { id, message } if message.contains(text) => {
+                    Some((*id, message.clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn started(&self) -> usize {
+        self.events
+            .iter()
+            .filter(|event| matches!(event, ProgressEvent::ComicStarted { .. }))
+            .count()
+    }
+
+    /// Ids of the comics that failed with an error `wanted` picks
+    fn failures(&self, wanted: impl Fn(&ComicError) -> bool) -> Vec<usize> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                ProgressEvent::ComicUpdate {
+                    id,
+                    status: ComicStatus::Failed { error },
+                } if wanted(error) => Some(*id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Names of the entries in the zip output `file`
+    fn entries(&self, file: &str) -> Vec<String> {
+        let zip = zip::ZipArchive::new(File::open(self.output_dir.join(file)).unwrap()).unwrap();
+        zip.file_names().map(String::from).collect()
+    }
+
+    fn read(&self, file: &str, entry: &str) -> Vec<u8> {
+        let mut zip =
+            zip::ZipArchive::new(File::open(self.output_dir.join(file)).unwrap()).unwrap();
+        let mut contents = Vec::new();
+        std::io::Read::read_to_end(&mut zip.by_name(entry).unwrap(), &mut contents).unwrap();
+        contents
+    }
+}
+
+/// Converts the inputs of `root` in one batch into `out` next to them
+#[cfg(test)]
+fn run_batch(root: InputRoot, config: ComicConfig, hooks: Hooks) -> TestBatch {
+    let output_dir = root.root.join("out");
+    let (tx, rx) = mpsc::channel();
+    let summary =
+        process_files_with_hooks(vec![root], config, output_dir.clone(), tx, None, hooks).unwrap();
+    let events = rx
+        .try_iter()
+        .filter_map(|event| match event {
+            Event::Progress { event, .. } => Some(event),
+            _ => None,
+        })
+        .collect();
+    TestBatch {
+        summary,
+        events,
+        output_dir,
+    }
+}
+
+/// `names` in `dir` as one input root, each a `test_comic`, with the config for the first
+#[cfg(test)]
+fn test_batch(dir: &Path, names: &[&str]) -> (InputRoot, ComicConfig) {
+    let files: Vec<_> = names.iter().map(|name| dir.join(name)).collect();
+    let config = test_comic(&files[0]);
+    for file in &files[1..] {
+        test_comic(file);
+    }
+    let root = InputRoot {
+        root: dir.to_path_buf(),
+        files,
+        ..InputRoot::default()
+    };
+    (root, config)
+}
+
+#[test]
+fn folder_output_holds_the_pages() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["Book v01.cbz"]);
+    let config = ComicConfig {
+        output_format: OutputFormat::Folder,
+        bundle: Some(dir.path().join("bundle.zip")),
+        ..config
+    };
+    // a page left from an earlier run is replaced along with the folder
+    let folder = dir.path().join("out").join("Book v01");
+    std::fs::create_dir_all(&folder).unwrap();
+    std::fs::write(folder.join("3.jpg"), b"old").unwrap();
+    run_batch(root, config, Hooks::default());
+
+    let mut pages: Vec<_> = std::fs::read_dir(folder)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    pages.sort();
+    assert_eq!(pages, ["1.jpg", "2.jpg"]);
+
+    let bundle = zip::ZipArchive::new(File::open(dir.path().join("bundle.zip")).unwrap()).unwrap();
+    let mut names: Vec<_> = bundle.file_names().collect();
+    names.sort();
+    assert_eq!(names, ["Book v01/1.jpg", "Book v01/2.jpg"]);
+}
+
+#[test]
+fn batch_plan_counts_pages_up_front() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["a.cbz", "b.cbz"]);
+    let plan = |batch: TestBatch| {
+        // sent after registering, before any comic starts
+        batch
+            .events
+            .into_iter()
+            .take_while(|event| !matches!(event, ProgressEvent::ComicStarted { .. }))
+            .find_map(|event| match event {
+                ProgressEvent::BatchPlan {
+                    total_comics,
+                    total_pages,
+                } => Some((total_comics, total_pages)),
+                _ => None,
+            })
+    };
+
+    let planned = ComicConfig {
+        batch_plan: true,
+        ..config.clone()
+    };
+    let batch = run_batch(root.clone(), planned, Hooks::default());
+    assert_eq!(plan(batch), Some((2, 4)));
+    assert_eq!(plan(run_batch(root, config, Hooks::default())), None);
+}
+
+#[test]
+fn packaging_reports_each_step() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["Book.cbz"]);
+    let config = ComicConfig {
+        additional_formats: vec![OutputFormat::Epub],
+        ..config
+    };
+    let batch = run_batch(root, config, Hooks::default());
+
+    let steps: Vec<_> = batch
+        .events
+        .into_iter()
+        .filter_map(|event| match event {
+            ProgressEvent::ComicUpdate {
+                status: ComicStatus::Progress { step, .. },
+                ..
+            } => Some(step),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(steps, ["building CBZ", "building EPUB"]);
+}
+
+#[test]
+fn metadata_writers_see_every_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["Book v01.cbz"]);
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let writer = {
+        let written = written.clone();
+        move |comic: &Comic, output: &Path| {
+            let pages = comic.processed_files.len();
+            written.lock().unwrap().push((output.to_path_buf(), pages));
+            Ok(())
+        }
+    };
+    let hooks = Hooks {
+        writers: vec![Box::new(writer)],
+        ..Hooks::default()
+    };
+    let batch = run_batch(root, config, hooks);
+
+    let written = written.lock().unwrap();
+    assert_eq!(*written, vec![(batch.output_dir.join("Book v01.cbz"), 2)]);
+}
+
+#[test]
+fn existing_outputs_follow_the_overwrite_hook() {
+    let dir = tempfile::tempdir().unwrap();
+    let names = ["a.cbz", "b.cbz", "c.cbz"];
+    let (root, config) = test_batch(dir.path(), &names);
+    let output_dir = dir.path().join("out");
+    std::fs::create_dir_all(&output_dir).unwrap();
+    let run = |config: ComicConfig, hook: Option<Box<dyn OverwriteHook>>| {
+        for file in names {
+            std::fs::write(output_dir.join(file), b"old").unwrap();
+        }
+        let hooks = Hooks {
+            on_overwrite: hook,
+            ..Hooks::default()
+        };
+        run_batch(root.clone(), config, hooks);
+        names
+            .iter()
+            .filter(|file| std::fs::read(output_dir.join(file)).unwrap() != b"old")
+            .count()
+    };
+
+    let skip = ComicConfig {
+        overwrite: crate::comic::OverwritePolicy::Skip,
+        ..config.clone()
+    };
+    assert_eq!(run(skip, None), 0);
+    assert_eq!(run(config.clone(), None), 3);
+
+    // answered once, the rest of the batch follows
+    let asked = Arc::new(AtomicU64::new(0));
+    let hook = {
+        let asked = asked.clone();
+        move |_: &Path| {
+            asked.fetch_add(1, Ordering::Relaxed);
+            OverwriteDecision::SkipAll
+        }
+    };
+    assert_eq!(run(config, Some(Box::new(hook))), 0);
+    assert_eq!(asked.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn cancelling_one_comic_leaves_the_rest() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["a.cbz", "b.cbz", "c.cbz"]);
+    let config = ComicConfig {
+        fail_fast: true,
+        ..config
+    };
+    let cancel = CancelComics::default();
+    cancel.cancel(1);
+    let hooks = Hooks {
+        cancel,
+        ..Hooks::default()
+    };
+    let batch = run_batch(root, config, hooks);
+
+    assert!(batch.output_dir.join("a.cbz").is_file());
+    assert!(!batch.output_dir.join("b.cbz").exists());
+    assert!(batch.output_dir.join("c.cbz").is_file());
+    assert_eq!(
+        batch.failures(|e| matches!(e, ComicError::CancelledByUser)),
+        [1]
+    );
+}
+
+#[test]
+fn duplicate_inputs_are_warned_about() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["a.cbz", "b.cbz", "c.cbz"]);
+    let config = ComicConfig {
+        duplicate_check: DuplicateCheck::Quick,
+        ..config
+    };
+    // a is converted as is, c gets a single blank page
+    let mut page = Vec::new();
+    imageproc::image::GrayImage::new(100, 150)
+        .write_to(
+            &mut std::io::Cursor::new(&mut page),
+            imageproc::image::ImageFormat::Png,
+        )
+        .unwrap();
+    let mut other = ZipWriter::new(File::create(&root.files[2]).unwrap());
+    other
+        .start_file("001.png", SimpleFileOptions::default())
+        .unwrap();
+    std::io::Write::write_all(&mut other, &page).unwrap();
+    other.finish().unwrap();
+
+    let batch = run_batch(root, config, Hooks::default());
+    assert_eq!(batch.summary.succeeded, 3);
+    assert_eq!(
+        batch.warnings("duplicate"),
+        [(1, "Looks like a duplicate of a".to_string())]
+    );
+}
+
+#[test]
+fn given_titles_name_the_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["a.cbz", "b.cbz"]);
+    let config = ComicConfig {
+        output_format: OutputFormat::Epub,
+        ..config
+    };
+    let root = InputRoot {
+        titles: HashMap::from([(root.files[0].clone(), "Real Title: One & Two".to_string())]),
+        ..root
+    };
+    let batch = run_batch(root, config, Hooks::default());
+
+    // the stem is the fallback, and characters file names can't hold are replaced
+    assert!(batch.output_dir.join("b.epub").is_file());
+    let opf = batch.read("Real Title_ One & Two.epub", "OEBPS/content.opf");
+    assert!(String::from_utf8(opf)
+        .unwrap()
+        .contains("<dc:title>Real Title: One &amp; Two</dc:title>"));
+}
+
+#[test]
+fn paused_batch_waits_to_be_resumed() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["a.cbz"]);
+    let pause = PauseBatch::default();
+    pause.pause();
+    let hooks = Hooks {
+        pause: pause.clone(),
+        ..Hooks::default()
+    };
+    let batch = thread::spawn(move || run_batch(root, config, hooks));
+
+    thread::sleep(Duration::from_millis(300));
+    assert!(!batch.is_finished());
+    assert!(!dir.path().join("out").join("a.cbz").exists());
+
+    pause.resume();
+    let batch = batch.join().unwrap();
+    assert_eq!(batch.summary.succeeded, 1);
+    assert!(batch.output_dir.join("a.cbz").is_file());
+}
+
+#[test]
+fn summary_counts_each_outcome() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["a.cbz", "b.cbz", "c.cbz"]);
+    std::fs::write(&root.files[2], b"not an archive").unwrap();
+    let config = ComicConfig {
+        overwrite: OverwritePolicy::Skip,
+        ..config
+    };
+    let output_dir = dir.path().join("out");
+    std::fs::create_dir_all(&output_dir).unwrap();
+    std::fs::write(output_dir.join("a.cbz"), b"old").unwrap();
+
+    let batch = run_batch(root, config, Hooks::default());
+    let summary = &batch.summary;
+    assert_eq!(summary.succeeded, 1);
+    assert_eq!(summary.skipped, vec!["a".to_string()]);
+    assert_eq!(summary.failed.len(), 1);
+    assert_eq!(summary.failed[0].0, "c");
+    assert_eq!(summary.total(), 3);
+    // the events still reach the caller, up to the last one
+    assert!(matches!(
+        batch.events.last(),
+        Some(ProgressEvent::ProcessingComplete)
+    ));
+}
+
+#[test]
+fn size_budget_skips_the_rest() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["a.cbz", "b.cbz", "c.cbz"]);
+    let config = ComicConfig {
+        batch_size_budget: Some(ByteSize(1)),
+        ..config
+    };
+    // one comic at a time, so the first one uses up the budget before the others start
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+    let batch = pool.install(|| run_batch(root, config, Hooks::default()));
+
+    // only the comic that ran was started
+    assert_eq!(batch.started(), 1);
+    assert_eq!(
+        batch
+            .failures(|e| matches!(e, ComicError::OverBudget))
+            .len(),
+        2
+    );
+}
+
+#[test]
+fn epub_pages_use_their_own_codec() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["Book.cbz"]);
+    let config = ComicConfig {
+        additional_formats: vec![OutputFormat::Epub],
+        format_codecs: HashMap::from([(OutputFormat::Epub, ImageFormat::WebP { quality: 80 })]),
+        ..config
+    };
+    let batch = run_batch(root, config, Hooks::default());
+    // the WebP pages are encoded from the decoded pages, not from the JPEG ones
+    assert!(batch.warnings("Re-encoding").is_empty());
+
+    let pages = |file: &str| -> Vec<String> {
+        batch
+            .entries(file)
+            .into_iter()
+            .filter(|name| name.ends_with(".jpg") || name.ends_with(".webp"))
+            .collect()
+    };
+    assert!(pages("Book.cbz").iter().all(|name| name.ends_with(".jpg")));
+    let epub_pages = pages("Book.epub");
+    assert_eq!(epub_pages.len(), 2);
+    assert!(epub_pages.iter().all(|name| name.ends_with(".webp")));
+
+    let opf = String::from_utf8(batch.read("Book.epub", "OEBPS/content.opf")).unwrap();
+    assert!(opf.contains(r#"media-type="image/webp""#));
+    assert!(!opf.contains(r#"media-type="image/jpeg""#));
+}
+
+#[test]
+fn codec_pages_keep_their_folders() {
+    use imageproc::image::{GrayImage, Luma};
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["Book.cbz"]);
+    // both pages are named 001.png, a dark one and a light one
+    let mut zip = ZipWriter::new(File::create(&root.files[0]).unwrap());
+    for (page, gray) in [("Vol 1/Ch 1/001.png", 20), ("Vol 2/Ch 1/001.png", 230)] {
+        let mut png = Vec::new();
+        GrayImage::from_pixel(100, 150, Luma([gray]))
+            .write_to(
+                &mut std::io::Cursor::new(&mut png),
+                imageproc::image::ImageFormat::Png,
+            )
+            .unwrap();
+        zip.start_file(page, SimpleFileOptions::default()).unwrap();
+        zip.write_all(&png).unwrap();
+    }
+    zip.finish().unwrap();
+    let png = ImageFormat::Png {
+        compression: crate::comic::PngCompression::Fast,
+    };
+    let config = ComicConfig {
+        additional_formats: vec![OutputFormat::Epub],
+        format_codecs: HashMap::from([(OutputFormat::Epub, png)]),
+        auto_crop: false,
+        ..config
+    };
+    let batch = run_batch(root, config, Hooks::default());
+
+    let grays: Vec<_> = ["image001.png", "image002.png"]
+        .iter()
+        .map(|name| {
+            let page = batch.read("Book.epub", &format!("OEBPS/Images/{name}"));
+            let page = imageproc::image::load_from_memory(&page)
+                .unwrap()
+                .to_luma8();
+            page.get_pixel(page.width() / 2, page.height() / 2)[0]
+        })
+        .collect();
+    assert!(grays[0] < 128 && grays[1] > 128, "{grays:?}");
+}
+
+#[test]
+fn comic_logs_are_written_next_to_the_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["Book.cbz"]);
+    let config = ComicConfig {
+        comic_logs: crate::comic::ComicLogs::Only,
+        ..config
+    };
+    let batch = run_batch(root, config, Hooks::default());
+
+    let log = std::fs::read_to_string(batch.output_dir.join("Book.log")).unwrap();
+    let lines: Vec<_> = log.lines().collect();
+    assert!(lines[0].contains("INFO  input: "));
+    assert!(lines
+        .iter()
+        .any(|line| line.ends_with("Processed 2 images")));
+    assert!(lines
+        .iter()
+        .any(|line| line.contains("Package completed in")));
+}
+
+#[test]
+fn bench_times_every_stage() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("Book.cbz");
+    let config = test_comic(&input);
+
+    let timings = bench_process(&input, config.clone()).unwrap();
+    assert!(!timings.stage(ComicStage::Process).is_zero());
+    assert!(!timings.stage(ComicStage::Package).is_zero());
+    assert!(timings.stage(ComicStage::Convert).is_zero());
+    // the outputs went to a scratch directory
+    assert!(!dir.path().join("comically").exists());
+
+    std::fs::write(&input, b"not a zip").unwrap();
+    assert!(bench_process(&input, config).is_err());
+}
+
+#[test]
+fn cover_only_epub_has_one_page() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["Book.cbz"]);
+    let config = ComicConfig {
+        output_format: OutputFormat::Epub,
+        additional_formats: vec![OutputFormat::Cbz],
+        cover_only: true,
+        ..config
+    };
+    let batch = run_batch(root, config, Hooks::default());
+
+    let pages = |file: &str| {
+        batch
+            .entries(file)
+            .iter()
+            .filter(|name| name.ends_with(".jpg"))
+            .count()
+    };
+    assert_eq!(pages("Book.epub"), 1);
+    assert_eq!(pages("Book.cbz"), 2);
+}
+
+#[test]
+fn epub_stores_pages_and_deflates_markup() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["Book.cbz"]);
+    let config = ComicConfig {
+        output_format: OutputFormat::Epub,
+        ..config
+    };
+    let batch = run_batch(root, config, Hooks::default());
+
+    let epub = File::open(batch.output_dir.join("Book.epub")).unwrap();
+    let mut epub = zip::ZipArchive::new(epub).unwrap();
+    let mut pages = 0;
+    for index in 0..epub.len() {
+        let entry = epub.by_index_raw(index).unwrap();
+        let name = entry.name().to_string();
+        let expected = if name.ends_with(".jpg") || name == "mimetype" {
+            pages += name.ends_with(".jpg") as usize;
+            CompressionMethod::Stored
+        } else {
+            CompressionMethod::Deflated
+        };
+        assert_eq!(entry.compression(), expected, "{name}");
+    }
+    assert_eq!(pages, 2);
+}
+
+#[test]
+fn cached_pages_are_reused_for_another_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["Book.cbz"]);
+    let cache = dir.path().join("cache");
+    let config = ComicConfig {
+        page_cache: Some(cache.clone()),
+        ..config
+    };
+    run_batch(root.clone(), config.clone(), Hooks::default());
+
+    // marks the cached pages, so the next run shows where its pages came from
+    let entries: Vec<_> = std::fs::read_dir(&cache)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    assert_eq!(entries.len(), 1);
+    for page in std::fs::read_dir(&entries[0]).unwrap() {
+        let page = page.unwrap().path();
+        if page.extension().is_some_and(|ext| ext == "jpg") {
+            std::fs::write(page, b"cached").unwrap();
+        }
+    }
+
+    let epub = ComicConfig {
+        output_format: OutputFormat::Epub,
+        ..config.clone()
+    };
+    let batch = run_batch(root.clone(), epub, Hooks::default());
+    let page = batch.read("Book.epub", "OEBPS/Images/image001.jpg");
+    assert_eq!(page, b"cached");
+
+    // other settings make other pages
+    let brighter = ComicConfig {
+        brightness: 20,
+        ..config
+    };
+    run_batch(root, brighter, Hooks::default());
+    assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 2);
+}
+
+#[test]
+fn cached_pages_keep_same_named_pages_apart() {
+    use imageproc::image::{GrayImage, Luma};
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["Book.cbz"]);
+    // both pages are named 001.png, a dark one and a light one
+    let mut zip = ZipWriter::new(File::create(&root.files[0]).unwrap());
+    for (page, gray) in [("Vol 1/Ch 1/001.png", 20), ("Vol 2/Ch 1/001.png", 230)] {
+        let mut png = Vec::new();
+        GrayImage::from_pixel(100, 150, Luma([gray]))
+            .write_to(
+                &mut std::io::Cursor::new(&mut png),
+                imageproc::image::ImageFormat::Png,
+            )
+            .unwrap();
+        zip.start_file(page, SimpleFileOptions::default()).unwrap();
+        zip.write_all(&png).unwrap();
+    }
+    zip.finish().unwrap();
+    let config = ComicConfig {
+        page_cache: Some(dir.path().join("cache")),
+        auto_crop: false,
+        ..config
+    };
+    let grays = |batch: &TestBatch| -> Vec<u8> {
+        let mut pages: Vec<_> = batch
+            .entries("Book.cbz")
+            .into_iter()
+            .filter(|name| name.ends_with(".jpg"))
+            .collect();
+        pages.sort();
+        pages
+            .iter()
+            .map(|name| {
+                let page = imageproc::image::load_from_memory(&batch.read("Book.cbz", name))
+                    .unwrap()
+                    .to_luma8();
+                page.get_pixel(page.width() / 2, page.height() / 2)[0]
+            })
+            .collect()
+    };
+
+    let fresh = grays(&run_batch(root.clone(), config.clone(), Hooks::default()));
+    let cached = grays(&run_batch(root, config, Hooks::default()));
+    assert_eq!(fresh.len(), 2);
+    assert!(fresh[0] < 128 && fresh[1] > 128, "{fresh:?}");
+    assert_eq!(cached, fresh);
+}
+
+#[test]
+fn page_manifest_maps_pages_to_entries() {
+    use imageproc::image::{GrayImage, Luma};
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["Book.cbz"]);
+    // a page and a spread instead of the two pages
+    let mut zip = ZipWriter::new(File::create(&root.files[0]).unwrap());
+    for (page, (width, height)) in [("001.png", (100, 150)), ("002.png", (300, 150))] {
+        let mut png = Vec::new();
+        GrayImage::from_pixel(width, height, Luma([90]))
+            .write_to(
+                &mut std::io::Cursor::new(&mut png),
+                imageproc::image::ImageFormat::Png,
+            )
+            .unwrap();
+        zip.start_file(page, SimpleFileOptions::default()).unwrap();
+        zip.write_all(&png).unwrap();
+    }
+    zip.finish().unwrap();
+
+    let config = ComicConfig {
+        page_manifest: true,
+        auto_crop: false,
+        ..config
+    };
+    let batch = run_batch(root, config, Hooks::default());
+
+    let manifest = std::fs::read_to_string(batch.output_dir.join("Book.pages.json")).unwrap();
+    let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+    let pages: Vec<_> = manifest
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|page| {
+            (
+                page["source"].as_str().unwrap().to_string(),
+                page["transforms"].to_string(),
+            )
+        })
+        .collect();
+    // the spread is rotated, then split with its right half first
+    assert_eq!(
+        pages,
+        [
+            ("001.png".to_string(), "[]".to_string()),
+            ("002.png".to_string(), r#"["rotated"]"#.to_string()),
+            ("002.png".to_string(), r#"["right half"]"#.to_string()),
+            ("002.png".to_string(), r#"["left half"]"#.to_string()),
+        ]
+    );
+    assert_eq!(manifest[0]["format"], "jpg");
+}
+
+#[test]
+fn apple_books_epub_is_fixed_layout() {
+    let dir = tempfile::tempdir().unwrap();
+    let (root, config) = test_batch(dir.path(), &["Book.cbz"]);
+    let config = ComicConfig {
+        output_format: OutputFormat::Epub,
+        reader_profile: crate::comic::ReaderProfile::AppleBooks,
+        ..config
+    };
+    let batch = run_batch(root, config, Hooks::default());
+
+    let read = |name: &str| String::from_utf8(batch.read("Book.epub", name)).unwrap();
+    assert!(read("META-INF/com.apple.ibooks.display-options.xml").contains("fixed-layout"));
+    let cover = read("OEBPS/cover.html");
+    assert!(!cover.contains("device-width"));
+    assert!(cover.contains("margin: 0"));
 }
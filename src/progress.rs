@@ -0,0 +1,267 @@
+use std::time::Duration;
+
+use crate::comic::{ComicStage, ComicStatus, ProgressEvent};
+
+/// Current state of every comic in a batch, built up from progress events.
+///
+/// Frontends that don't want to interpret the event stream themselves can feed
+/// every event through [`ProgressTracker::handle`] and poll [`ProgressTracker::snapshot`].
+/// Events that arrive after a newer one for the same comic are dropped.
+#[derive(Debug, Default)]
+pub struct ProgressTracker {
+    comics: Vec<ComicSnapshot>,
+    // comics and pages in the whole batch, once the pipeline has counted them
+    plan: Option<(usize, usize)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ComicSnapshot {
+    pub id: usize,
+    pub title: String,
+    // stage currently running, None before processing starts and once finished
+    pub stage: Option<ComicStage>,
+    // 0 to 100
+    pub percent: f64,
+    pub status: ComicPhase,
+    pub images_processed: usize,
+    pub total_images: usize,
+    // sequence number of the latest event applied
+    pub seq: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ComicPhase {
+    Waiting,
+    Running,
+    Succeeded,
+    CompletedWithWarnings { warnings: Vec<String> },
+    Failed { error: String },
+}
+
+impl ComicPhase {
+    pub fn is_success(&self) -> bool {
+        matches!(
+            self,
+            ComicPhase::Succeeded | ComicPhase::CompletedWithWarnings { .. }
+        )
+    }
+}
+
+impl ProgressTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn handle(&mut self, seq: u64, event: &ProgressEvent) {
+        match event {
+            ProgressEvent::RegisterComic { id, file_name } => {
+                let snapshot = ComicSnapshot {
+                    id: *id,
+                    title: file_name.clone(),
+                    stage: None,
+                    percent: 0.0,
+                    status: ComicPhase::Waiting,
+                    images_processed: 0,
+                    total_images: 0,
+                    seq,
+                };
+                match self.comics.iter_mut().find(|comic| comic.id == *id) {
+                    Some(comic) => *comic = snapshot,
+                    None => self.comics.push(snapshot),
+                }
+            }
+            ProgressEvent::ComicStarted { id } => {
+                if let Some(comic) = self.current(*id, seq) {
+                    comic.status = ComicPhase::Running;
+                }
+            }
+            ProgressEvent::ComicUpdate { id, status } => {
+                if let Some(comic) = self.current(*id, seq) {
+                    comic.update(status);
+                }
+            }
+            ProgressEvent::BatchPlan {
+                total_comics,
+                total_pages,
+            } => {
+                self.plan = Some((*total_comics, *total_pages));
+            }
+            ProgressEvent::Warning { .. }
+            | ProgressEvent::PageProcessed { .. }
+            | ProgressEvent::Bundled { .. }
+            | ProgressEvent::ProcessingComplete => {}
+        }
+    }
+
+    /// The comic to apply an event numbered `seq` to, unless it already saw a later one
+    fn current(&mut self, id: usize, seq: u64) -> Option<&mut ComicSnapshot> {
+        let comic = self.comics.iter_mut().find(|comic| comic.id == id)?;
+        if seq < comic.seq {
+            return None;
+        }
+        comic.seq = seq;
+        Some(comic)
+    }
+
+    /// Comics and pages in the whole batch, `None` until `BatchPlan` arrives or without one
+    pub fn plan(&self) -> Option<(usize, usize)> {
+        self.plan
+    }
+
+    /// A copy of every comic's state, safe to hold onto while processing continues
+    pub fn snapshot(&self) -> Vec<ComicSnapshot> {
+        self.comics.clone()
+    }
+}
+
+impl ComicSnapshot {
+    fn update(&mut self, status: &ComicStatus) {
+        match status {
+            ComicStatus::Waiting => {
+                self.status = ComicPhase::Waiting;
+            }
+            ComicStatus::Progress {
+                stage, progress, ..
+            } => {
+                self.status = ComicPhase::Running;
+                self.stage = Some(*stage);
+                self.percent = *progress;
+            }
+            ComicStatus::ImageProcessingStart { total_images, .. } => {
+                self.status = ComicPhase::Running;
+                self.stage = Some(ComicStage::Process);
+                self.total_images = *total_images;
+                self.images_processed = 0;
+                self.percent = 0.0;
+            }
+            ComicStatus::ImageProcessed { count } => {
+                self.images_processed += count;
+                // image processing covers the first half, packaging reports from 50% on
+                if self.total_images > 0 {
+                    let done = self.images_processed.min(self.total_images);
+                    self.percent = 50.0 * done as f64 / self.total_images as f64;
+                }
+            }
+            ComicStatus::ImageProcessingComplete { .. } | ComicStatus::StageCompleted { .. } => {}
+            ComicStatus::Success { .. } => {
+                self.finish(ComicPhase::Succeeded);
+            }
+            ComicStatus::CompletedWithWarnings { warnings, .. } => {
+                self.finish(ComicPhase::CompletedWithWarnings {
+                    warnings: warnings.clone(),
+                });
+            }
+            ComicStatus::Failed { error } => {
+                self.finish(ComicPhase::Failed {
+                    error: error.to_string(),
+                });
+            }
+        }
+    }
+
+    fn finish(&mut self, status: ComicPhase) {
+        self.status = status;
+        self.stage = None;
+        self.percent = 100.0;
+    }
+}
+
+/// Time spent in each stage of a comic
+#[derive(Debug, Clone)]
+pub struct StageTimings {
+    stages: Vec<StageMetrics>,
+}
+
+impl StageTimings {
+    pub fn add_stage(&mut self, stage: ComicStage, duration: Duration) {
+        self.stages.push(StageMetrics { stage, duration });
+    }
+
+    pub fn new() -> Self {
+        Self { stages: Vec::new() }
+    }
+
+    /// Every stage in the order they completed
+    pub fn stages(&self) -> &[StageMetrics] {
+        &self.stages
+    }
+
+    pub fn total(&self) -> Duration {
+        self.stages.iter().map(|s| s.duration).sum()
+    }
+
+    /// Time spent in `stage`, added up when it ran more than once
+    pub fn stage(&self, stage: ComicStage) -> Duration {
+        self.stages
+            .iter()
+            .filter(|s| s.stage == stage)
+            .map(|s| s.duration)
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StageMetrics {
+    pub stage: ComicStage,
+    pub duration: Duration,
+}
+
+#[test]
+fn tracker_snapshot_follows_events() {
+    use crate::comic::ComicStats;
+    use std::time::Instant;
+
+    let mut tracker = ProgressTracker::new();
+    for id in 0..3 {
+        tracker.handle(
+            id as u64,
+            &ProgressEvent::RegisterComic {
+                id,
+                file_name: format!("vol {id}"),
+            },
+        );
+    }
+    let mut seq = 3..;
+    let mut handle = |tracker: &mut ProgressTracker, event| {
+        tracker.handle(seq.next().unwrap(), &event);
+    };
+    handle(&mut tracker, ProgressEvent::ComicStarted { id: 2 });
+
+    let events = [
+        ComicStatus::ImageProcessingStart {
+            total_images: 4,
+            start: Instant::now(),
+        },
+        ComicStatus::ImageProcessed { count: 1 },
+        ComicStatus::ImageProcessed { count: 1 },
+    ];
+    for status in events {
+        handle(&mut tracker, ProgressEvent::ComicUpdate { id: 0, status });
+    }
+    handle(
+        &mut tracker,
+        ProgressEvent::ComicUpdate {
+            id: 1,
+            status: ComicStatus::Success {
+                stats: ComicStats::default(),
+            },
+        },
+    );
+    // sent before the success, but arrived after it
+    tracker.handle(
+        4,
+        &ProgressEvent::ComicUpdate {
+            id: 1,
+            status: ComicStatus::ImageProcessed { count: 1 },
+        },
+    );
+
+    let snapshot = tracker.snapshot();
+    assert_eq!(snapshot[0].status, ComicPhase::Running);
+    assert_eq!(snapshot[0].images_processed, 2);
+    assert_eq!(snapshot[0].percent, 25.0);
+    assert!(snapshot[1].status.is_success());
+    assert_eq!(snapshot[1].percent, 100.0);
+    assert_eq!(snapshot[2].status, ComicPhase::Running);
+    assert_eq!(snapshot[2].percent, 0.0);
+}
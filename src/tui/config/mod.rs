@@ -22,6 +22,7 @@ use std::thread;
 use crate::{
     comic::{ComicConfig, ImageFormat, OutputFormat, PngCompression, SplitStrategy},
     comic_archive,
+    pipeline::InputRoot,
     tui::{
         button::{Button, ButtonVariant},
         config::device_selector::DeviceSelectorState,
@@ -55,6 +56,8 @@ pub enum ModalState {
 #[derive(Debug)]
 pub struct MangaFile {
     pub archive_path: PathBuf,
+    // input directory the file was found in
+    pub root: PathBuf,
     pub name: String,
 }
 
@@ -250,7 +253,8 @@ impl ConfigState {
                 self.config.output_format = match self.config.output_format {
                     OutputFormat::Mobi => OutputFormat::Epub,
                     OutputFormat::Epub => OutputFormat::Cbz,
-                    OutputFormat::Cbz => OutputFormat::Mobi,
+                    OutputFormat::Cbz => OutputFormat::Folder,
+                    OutputFormat::Folder => OutputFormat::Mobi,
                 };
                 // Reset to JPEG when switching to Mobi
                 if self.config.output_format == OutputFormat::Mobi {
@@ -313,16 +317,21 @@ impl ConfigState {
     }
 
     fn send_start_processing(&self) {
-        let selected_paths: Vec<PathBuf> = self
-            .files
-            .iter()
-            .filter(|(_, selected)| *selected)
-            .map(|(file, _)| file.archive_path.clone())
-            .collect();
+        let mut roots = Vec::<InputRoot>::new();
+        for (file, _) in self.files.iter().filter(|(_, selected)| *selected) {
+            match roots.iter_mut().find(|root| root.root == file.root) {
+                Some(root) => root.files.push(file.archive_path.clone()),
+                None => roots.push(InputRoot {
+                    root: file.root.clone(),
+                    files: vec![file.archive_path.clone()],
+                    ..InputRoot::default()
+                }),
+            }
+        }
 
-        if !selected_paths.is_empty() {
+        if !roots.is_empty() {
             let _ = self.event_tx.send(crate::Event::StartProcessing {
-                files: selected_paths,
+                roots,
                 config: self.config.clone(),
                 output_dir: self.output_dir.clone(),
             });
@@ -857,6 +866,7 @@ impl<'a> Widget for SettingsWidget<'a> {
                 OutputFormat::Mobi => "AZW3/MOBI",
                 OutputFormat::Epub => "EPUB",
                 OutputFormat::Cbz => "CBZ",
+                OutputFormat::Folder => "Folder",
             },
             self.state,
         )
@@ -866,7 +876,8 @@ impl<'a> Widget for SettingsWidget<'a> {
             self.state.config.output_format = match self.state.config.output_format {
                 OutputFormat::Mobi => OutputFormat::Epub,
                 OutputFormat::Epub => OutputFormat::Cbz,
-                OutputFormat::Cbz => OutputFormat::Mobi,
+                OutputFormat::Cbz => OutputFormat::Folder,
+                OutputFormat::Folder => OutputFormat::Mobi,
             };
             // Reset to JPEG when switching to Mobi
             if self.state.config.output_format == OutputFormat::Mobi {
@@ -1222,12 +1233,18 @@ fn load_and_process_preview(
     config: &ComicConfig,
     page_index: Option<usize>,
 ) -> anyhow::Result<(DynamicImage, usize, usize)> {
-    let mut archive_files: Vec<_> = comic_archive::unarchive_comic_iter(path)?
-        .filter_map(|r| r.ok())
-        .collect();
+    let mut archive_files: Vec<_> =
+        comic_archive::unarchive_comic_iter(path, config.archive_format)?
+            .filter_map(|r| r.ok())
+            .collect();
 
-    // Sort by filename to ensure consistent ordering
-    archive_files.sort_by(|a, b| a.file_stem().cmp(b.file_stem()));
+    // same order as the converted pages, folders included
+    archive_files.sort_by(|a, b| {
+        comic_archive::natural_cmp(
+            &a.file_name.to_string_lossy(),
+            &b.file_name.to_string_lossy(),
+        )
+    });
 
     if archive_files.is_empty() {
         return Err(anyhow::anyhow!("No images in archive"));
@@ -1247,7 +1264,8 @@ fn load_and_process_preview(
 
     let img = imageproc::image::load_from_memory(&archive_file.data)?;
 
-    let processed_images = crate::image_processor::process_image(img, config);
+    let processed_images =
+        crate::image_processor::process_image(img, config, &archive_file.file_name);
 
     let first_image = processed_images
         .into_iter()
@@ -1259,7 +1277,14 @@ fn load_and_process_preview(
         ImageFormat::Jpeg { quality } | ImageFormat::WebP { quality } => quality,
         _ => 85, // Default quality for preview
     };
-    crate::image_processor::compress_to_jpeg(&first_image, &mut compressed_buffer, quality)?;
+    crate::image_processor::compress_to_jpeg(
+        &first_image,
+        &mut compressed_buffer,
+        quality,
+        None,
+        config.jpeg_subsampling,
+        config.jpeg_progressive,
+    )?;
 
     let compressed_img = imageproc::image::load_from_memory(&compressed_buffer)?;
 
@@ -24,7 +24,8 @@ use std::{
 
 use crate::{
     comic::OutputFormat,
-    pipeline::process_files,
+    comic_archive,
+    pipeline::{process_files_with_hooks, CancelComics, Hooks, OverwriteDecision, PauseBatch},
     tui::{
         config::MangaFile,
         error::ErrorInfo,
@@ -46,7 +47,7 @@ pub enum AppState {
 }
 
 pub fn run(
-    input_dir: Option<PathBuf>,
+    input_dirs: Vec<PathBuf>,
     output_dir: Option<PathBuf>,
 
     terminal: &mut Terminal<impl Backend>,
@@ -56,11 +57,14 @@ pub fn run(
     event_tx: mpsc::Sender<Event>,
     mut event_rx: mpsc::Receiver<Event>,
 ) {
-    let input_dir =
-        input_dir.unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")));
-    let output_dir = output_dir.unwrap_or_else(|| input_dir.join("comically"));
+    let input_dirs = if input_dirs.is_empty() {
+        vec![std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."))]
+    } else {
+        input_dirs
+    };
+    let output_dir = output_dir.unwrap_or_else(|| input_dirs[0].join("comically"));
 
-    let files = match init(&input_dir, &output_dir) {
+    let files = match init(&input_dirs, &output_dir) {
         Ok(files) => files,
         Err(e) => {
             let _ = run_fatal_error(terminal, &mut event_rx, &e, &theme);
@@ -331,9 +335,9 @@ fn process_events(
                 }
             }
             Event::Tick => {}
-            Event::Progress(event) => {
+            Event::Progress { seq, event } => {
                 if let AppState::Processing(processing_state) = &mut app.state {
-                    processing_state.handle_event(event);
+                    processing_state.handle_event(seq, event);
                 }
             }
             Event::Config(event) => {
@@ -341,13 +345,18 @@ fn process_events(
                     config_state.handle_event(event);
                 }
             }
+            Event::ConfirmOverwrite { path, reply } => {
+                if let AppState::Processing(processing_state) = &mut app.state {
+                    processing_state.confirm_overwrite(path, reply);
+                }
+            }
             Event::StartProcessing {
-                files,
+                roots,
                 config,
                 output_dir,
             } => {
-                if config.output_format == OutputFormat::Mobi
-                    && !crate::mobi_converter::is_kindlegen_available()
+                if config.has_output_format(OutputFormat::Mobi)
+                    && !crate::mobi_converter::is_available()
                 {
                     return Err(ErrorInfo::error(
                             "KindleGen not installed",
@@ -357,14 +366,51 @@ fn process_events(
                 }
 
                 let _ = config.save();
+                // the progress stages follow MOBI when it's one of the outputs
+                let progress_format = if config.has_output_format(OutputFormat::Mobi) {
+                    OutputFormat::Mobi
+                } else {
+                    config.output_format
+                };
+                let cancel = CancelComics::default();
+                let pause = PauseBatch::default();
                 app.state = AppState::Processing(progress::ProgressState::new(
                     app.theme,
-                    config.output_format,
+                    progress_format,
+                    cancel.clone(),
+                    pause.clone(),
                 ));
 
                 let event_tx = event_tx.clone();
+                // asked on the progress screen, a closed screen skips the output
+                let prompt_tx = event_tx.clone();
+                let hooks = Hooks {
+                    on_overwrite: Some(Box::new(move |path: &Path| {
+                        let (reply, answer) = mpsc::channel();
+                        let path = path.to_path_buf();
+                        if prompt_tx
+                            .send(Event::ConfirmOverwrite { path, reply })
+                            .is_err()
+                        {
+                            return OverwriteDecision::Skip;
+                        }
+                        answer.recv().unwrap_or(OverwriteDecision::Skip)
+                    })),
+                    cancel,
+                    pause,
+                    ..Hooks::default()
+                };
                 rayon::spawn(move || {
-                    process_files(files, config, output_dir, event_tx);
+                    match process_files_with_hooks(roots, config, output_dir, event_tx, None, hooks)
+                    {
+                        Ok(summary) => log::info!(
+                            "{} of {} comics converted, {} failed",
+                            summary.succeeded,
+                            summary.total(),
+                            summary.failed.len()
+                        ),
+                        Err(e) => log::error!("{e}"),
+                    }
                 });
             }
         }
@@ -372,7 +418,7 @@ fn process_events(
     Ok(true)
 }
 
-fn init(input_dir: &Path, output_dir: &Path) -> Result<Vec<MangaFile>, ErrorInfo> {
+fn init(input_dirs: &[PathBuf], output_dir: &Path) -> Result<Vec<MangaFile>, ErrorInfo> {
     if let Err(e) = create_dir_all(output_dir) {
         return Err(ErrorInfo::error(
             "failed to create output directory",
@@ -381,23 +427,43 @@ fn init(input_dir: &Path, output_dir: &Path) -> Result<Vec<MangaFile>, ErrorInfo
         ));
     }
 
-    match find_manga_files(input_dir) {
-        Ok(files) => {
-            if files.is_empty() {
-                Err(ErrorInfo::error(
-                    "no files found",
-                    format!("directory: {}", input_dir.display()),
-                    Some("supports .cbz .cbr .zip .rar".into()),
+    let mut files = Vec::new();
+    for input_dir in input_dirs {
+        match find_manga_files(input_dir) {
+            Ok(found) => files.extend(found),
+            Err(e) => {
+                return Err(ErrorInfo::error(
+                    "failed to read directory",
+                    format!("directory {}: {e}", input_dir.display()),
+                    Some("check that the directory exists".into()),
                 ))
-            } else {
-                Ok(files)
             }
         }
-        Err(e) => Err(ErrorInfo::error(
-            "failed to read directory",
-            format!("directory {}: {e}", input_dir.display()),
-            Some("check that the directory exists".into()),
-        )),
+    }
+
+    if files.is_empty() {
+        let directories = input_dirs
+            .iter()
+            .map(|dir| dir.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        Err(ErrorInfo::error(
+            "no files found",
+            format!("directory: {}", directories),
+            Some(
+                format!(
+                    "supports {}",
+                    comic_archive::supported_input_formats()
+                        .iter()
+                        .map(|format| format!(".{}", format.extension))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                )
+                .into(),
+            ),
+        ))
+    } else {
+        Ok(files)
     }
 }
 
@@ -409,10 +475,10 @@ fn find_manga_files(dir: &std::path::Path) -> anyhow::Result<Vec<MangaFile>> {
         let path = entry.path();
 
         if let Some(ext) = path.extension() {
-            if matches!(
-                ext.to_str(),
-                Some("cbz") | Some("cbr") | Some("zip") | Some("rar")
-            ) {
+            if comic_archive::supported_input_formats()
+                .iter()
+                .any(|format| ext.to_str() == Some(format.extension))
+            {
                 let name = path
                     .file_stem()
                     .unwrap_or_default()
@@ -420,6 +486,7 @@ fn find_manga_files(dir: &std::path::Path) -> anyhow::Result<Vec<MangaFile>> {
                     .to_string();
                 files.push(MangaFile {
                     archive_path: path,
+                    root: dir.to_path_buf(),
                     name,
                 });
             }
@@ -6,13 +6,19 @@ use ratatui::{
     text::Span,
     widgets::{Block, Gauge, Padding, Paragraph, StatefulWidget, Widget},
 };
-use std::time::{Duration, Instant};
+use std::{
+    path::PathBuf,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
 
 use crate::{
     comic::{ComicStage, ComicStatus, OutputFormat, ProgressEvent},
+    pipeline::{CancelComics, OverwriteDecision, PauseBatch},
+    progress::{ProgressTracker, StageTimings},
     tui::{
         render_title,
-        utils::{themed_block, themed_block_title},
+        utils::{format_bytes, themed_block, themed_block_title},
         Theme,
     },
 };
@@ -21,46 +27,36 @@ pub struct ProgressState {
     start: Instant,
     comics: Vec<ComicState>,
     complete: Option<Duration>,
+    // size of the batch's bundle zip, once written
+    bundle_bytes: Option<u64>,
     scroll_offset: usize,
+    // comic under the cursor, the one 'x' cancels
+    selected: usize,
+    // rows shown by the last draw, to keep the selection on screen
+    visible_height: usize,
+    cancel: CancelComics,
+    // 'p' holds the batch and lets it go on
+    pause: PauseBatch,
+    tracker: ProgressTracker,
     pub theme: Theme,
     pub output_format: OutputFormat,
+    // an existing output waiting for the user to say whether it's replaced
+    overwrite_prompt: Option<(PathBuf, mpsc::Sender<OverwriteDecision>)>,
 }
 
 #[derive(Debug)]
 struct ComicState {
-    title: String,
     status: Vec<ComicStatus>,
+    // a worker has picked it up, even if no stage has reported yet
+    started: bool,
+    // sequence number of the latest status, older ones arriving late are not shown
+    seq: u64,
     timings: StageTimings,
     image_processing_start: Option<Instant>,
     images_processed: usize,
     total_images: usize,
 }
 
-#[derive(Debug, Clone)]
-pub struct StageTimings {
-    stages: Vec<StageMetrics>,
-}
-
-impl StageTimings {
-    pub fn add_stage(&mut self, stage: ComicStage, duration: Duration) {
-        self.stages.push(StageMetrics { stage, duration });
-    }
-
-    pub fn new() -> Self {
-        Self { stages: Vec::new() }
-    }
-
-    pub fn total(&self) -> Duration {
-        self.stages.iter().map(|s| s.duration).sum()
-    }
-}
-
-#[derive(Debug, Clone)]
-struct StageMetrics {
-    stage: ComicStage,
-    duration: Duration,
-}
-
 impl ComicState {
     fn current_status(&self) -> &ComicStatus {
         self.status
@@ -78,27 +74,46 @@ impl ComicState {
 }
 
 impl ProgressState {
-    pub fn new(theme: Theme, output_format: OutputFormat) -> Self {
+    pub fn new(
+        theme: Theme,
+        output_format: OutputFormat,
+        cancel: CancelComics,
+        pause: PauseBatch,
+    ) -> Self {
         Self {
             start: Instant::now(),
             comics: Vec::new(),
             complete: None,
+            bundle_bytes: None,
             scroll_offset: 0,
+            selected: 0,
+            visible_height: 0,
+            cancel,
+            pause,
+            tracker: ProgressTracker::new(),
             theme,
             output_format,
+            overwrite_prompt: None,
         }
     }
 
-    pub fn handle_event(&mut self, event: ProgressEvent) {
+    /// The pipeline waits on `reply` until a key answers, one prompt at a time
+    pub fn confirm_overwrite(&mut self, path: PathBuf, reply: mpsc::Sender<OverwriteDecision>) {
+        self.overwrite_prompt = Some((path, reply));
+    }
+
+    pub fn handle_event(&mut self, seq: u64, event: ProgressEvent) {
+        self.tracker.handle(seq, &event);
         match event {
-            ProgressEvent::RegisterComic { id, file_name } => {
+            ProgressEvent::RegisterComic { id, .. } => {
                 debug_assert!(self.comics.get(id).is_none(), "comic already registered");
                 debug_assert!(id <= self.comics.len(), "id out of bounds");
 
                 if id == self.comics.len() {
                     self.comics.push(ComicState {
-                        title: file_name,
                         status: vec![ComicStatus::Waiting],
+                        started: false,
+                        seq,
                         timings: StageTimings::new(),
                         image_processing_start: None,
                         images_processed: 0,
@@ -106,8 +121,9 @@ impl ProgressState {
                     });
                 } else {
                     self.comics[id] = ComicState {
-                        title: file_name,
                         status: vec![ComicStatus::Waiting],
+                        started: false,
+                        seq,
                         timings: StageTimings::new(),
                         image_processing_start: None,
                         images_processed: 0,
@@ -115,6 +131,11 @@ impl ProgressState {
                     };
                 }
             }
+            ProgressEvent::ComicStarted { id } => {
+                if let Some(comic) = self.comics.get_mut(id) {
+                    comic.started = true;
+                }
+            }
             ProgressEvent::ComicUpdate { id, status } => {
                 if let Some(comic) = self.comics.get_mut(id) {
                     match &status {
@@ -129,19 +150,29 @@ impl ProgressState {
                             comic.images_processed = 0;
                             comic.image_processing_start = Some(*start);
                         }
-                        ComicStatus::ImageProcessed => {
-                            comic.images_processed += 1;
+                        ComicStatus::ImageProcessed { count } => {
+                            comic.images_processed += count;
                         }
                         ComicStatus::ImageProcessingComplete { duration } => {
                             comic.timings.add_stage(ComicStage::Process, *duration);
                         }
                         _ => {}
                     }
-                    comic.status.push(status);
+                    if seq >= comic.seq {
+                        comic.seq = seq;
+                        comic.status.push(status);
+                    }
                 } else {
                     panic!("Comic state not found for id: {}", id);
                 }
             }
+            // shown through CompletedWithWarnings once the comic finishes
+            ProgressEvent::Warning { .. } | ProgressEvent::PageProcessed { .. } => {}
+            // kept by the tracker
+            ProgressEvent::BatchPlan { .. } => {}
+            ProgressEvent::Bundled { bytes, .. } => {
+                self.bundle_bytes = Some(bytes);
+            }
             ProgressEvent::ProcessingComplete => {
                 self.complete = Some(self.start.elapsed());
             }
@@ -149,10 +180,33 @@ impl ProgressState {
     }
 
     pub fn handle_key(&mut self, key: KeyEvent) {
-        if key.code == event::KeyCode::Up || key.code == event::KeyCode::Char('k') {
-            self.scroll_up();
-        } else if key.code == event::KeyCode::Down || key.code == event::KeyCode::Char('j') {
-            self.scroll_down();
+        if self.overwrite_prompt.is_some() {
+            let decision = match key.code {
+                event::KeyCode::Char('y') => OverwriteDecision::Overwrite,
+                event::KeyCode::Char('n') => OverwriteDecision::Skip,
+                event::KeyCode::Char('a') => OverwriteDecision::OverwriteAll,
+                event::KeyCode::Char('s') => OverwriteDecision::SkipAll,
+                _ => return,
+            };
+            if let Some((_, reply)) = self.overwrite_prompt.take() {
+                let _ = reply.send(decision);
+            }
+            return;
+        }
+        match key.code {
+            event::KeyCode::Up | event::KeyCode::Char('k') => self.select_up(),
+            event::KeyCode::Down | event::KeyCode::Char('j') => self.select_down(),
+            event::KeyCode::Char('x') if self.selected < self.comics.len() => {
+                self.cancel.cancel(self.selected)
+            }
+            event::KeyCode::Char('p') if self.complete.is_none() => {
+                if self.pause.is_paused() {
+                    self.pause.resume();
+                } else {
+                    self.pause.pause();
+                }
+            }
+            _ => {}
         }
     }
 
@@ -179,6 +233,20 @@ impl ProgressState {
             self.scroll_offset = self.scroll_offset.saturating_add(1);
         }
     }
+
+    fn select_up(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+        self.scroll_offset = self.scroll_offset.min(self.selected);
+    }
+
+    fn select_down(&mut self) {
+        if self.selected + 1 < self.comics.len() {
+            self.selected += 1;
+        }
+        if self.visible_height > 0 && self.selected >= self.scroll_offset + self.visible_height {
+            self.scroll_offset = self.selected + 1 - self.visible_height;
+        }
+    }
 }
 
 pub struct ProgressScreen<'a> {
@@ -221,58 +289,80 @@ fn draw_header(buf: &mut Buffer, state: &ProgressState, header_area: Rect, theme
     let total = state.comics.len();
 
     let successful = state
-        .comics
+        .tracker
+        .snapshot()
         .iter()
-        .filter(|state| matches!(state.current_status(), ComicStatus::Success))
+        .filter(|comic| comic.status.is_success())
         .count();
 
     let mut total_work = 0.0;
     let mut completed_work = 0.0;
+    // completed work weighted by each comic's pages, for the batch plan's total
+    let mut completed_pages = 0.0;
 
     for comic in &state.comics {
-        match comic.current_status() {
-            ComicStatus::Waiting => {
-                total_work += 1.0;
-            }
+        let done = match comic.current_status() {
+            ComicStatus::Waiting => 0.0,
             ComicStatus::Progress {
                 stage, progress, ..
             } => {
-                total_work += 1.0;
                 // Each stage contributes a portion based on output format
                 let stage_weight = state.output_format.stage_weight(*stage);
-                completed_work += stage_weight * (progress / 100.0);
+                stage_weight * (progress / 100.0)
             }
-            ComicStatus::ImageProcessingStart { .. } | ComicStatus::ImageProcessed => {
-                total_work += 1.0;
+            ComicStatus::ImageProcessingStart { .. } | ComicStatus::ImageProcessed { .. } => {
                 // Image processing is weighted as 50% of the work
                 if comic.total_images > 0 {
                     let image_progress = comic.images_processed as f64 / comic.total_images as f64;
-                    completed_work += 0.5 * image_progress;
+                    0.5 * image_progress
+                } else {
+                    0.0
                 }
             }
-            ComicStatus::Success => {
-                total_work += 1.0;
-                completed_work += 1.0;
-            }
-            ComicStatus::Failed { .. } => {
-                total_work += 1.0;
-                completed_work += 1.0;
-            }
-            _ => {}
-        }
+            ComicStatus::Success { .. } | ComicStatus::CompletedWithWarnings { .. } => 1.0,
+            ComicStatus::Failed { .. } => 1.0,
+            _ => continue,
+        };
+        total_work += 1.0;
+        completed_work += done;
+        completed_pages += done * comic.total_images as f64;
     }
 
-    let progress_ratio = if total_work > 0.0 {
-        completed_work / total_work
-    } else {
-        0.0
+    // with a plan, comics count by their pages, including the ones that haven't started
+    let progress_ratio = match state.tracker.plan() {
+        _ if state.complete.is_some() => 1.0,
+        Some((_, pages)) if pages > 0 => (completed_pages / pages as f64).min(1.0),
+        _ if total_work > 0.0 => completed_work / total_work,
+        _ => 0.0,
     };
+    let pages = state
+        .tracker
+        .plan()
+        .map(|(_, pages)| format!(", {} pages", pages))
+        .unwrap_or_default();
     let elapsed = state.complete.unwrap_or_else(|| state.start.elapsed());
+    let bundle = state
+        .bundle_bytes
+        .map(|bytes| format!(", bundle {}", format_bytes(bytes)))
+        .unwrap_or_default();
+    let paused = if state.pause.is_paused() && state.complete.is_none() {
+        ", paused"
+    } else {
+        ""
+    };
 
     Gauge::default()
         .gauge_style(Style::default().fg(theme.primary_bg))
         .label(Span::styled(
-            format!("{}/{} ({:.1}s)", successful, total, elapsed.as_secs_f64()),
+            format!(
+                "{}/{} ({:.1}s{}{}{})",
+                successful,
+                total,
+                elapsed.as_secs_f64(),
+                pages,
+                bundle,
+                paused
+            ),
             Style::default().fg(theme.gauge_label),
         ))
         .ratio(progress_ratio)
@@ -305,24 +395,27 @@ fn draw_main_content(buf: &mut Buffer, state: &mut ProgressState, area: Rect, th
     }
 
     let visible_height = names_inner_area.height as usize;
+    state.visible_height = visible_height;
 
     let max_scroll = state.comics.len().saturating_sub(visible_height);
     if state.scroll_offset > max_scroll {
         state.scroll_offset = max_scroll;
     }
 
-    let visible_items = {
-        let end_idx = (state.scroll_offset + visible_height).min(state.comics.len());
-        &state.comics[state.scroll_offset..end_idx]
-    };
+    let end_idx = (state.scroll_offset + visible_height).min(state.comics.len());
+    let visible_items = &state.comics[state.scroll_offset..end_idx];
+    // registered in the same order as `comics`
+    let snapshot = state.tracker.snapshot();
+    let visible_titles = &snapshot[state.scroll_offset..end_idx];
 
     let names_layout =
         Layout::vertical(vec![Constraint::Length(1); visible_items.len()]).split(names_inner_area);
     let status_layout =
         Layout::vertical(vec![Constraint::Length(1); visible_items.len()]).split(status_inner_area);
 
-    for (i, comic) in visible_items.iter().enumerate() {
-        draw_file_title(buf, comic, names_layout[i], theme);
+    for (i, comic) in visible_titles.iter().enumerate() {
+        let selected = state.scroll_offset + i == state.selected;
+        draw_file_title(buf, &comic.title, selected, names_layout[i], theme);
     }
 
     for (i, comic) in visible_items.iter().enumerate() {
@@ -339,9 +432,14 @@ fn draw_main_content(buf: &mut Buffer, state: &mut ProgressState, area: Rect, th
     );
 }
 
-fn draw_file_title(buf: &mut Buffer, comic_state: &ComicState, area: Rect, theme: &Theme) {
-    Paragraph::new(comic_state.title.clone())
-        .style(theme.content)
+fn draw_file_title(buf: &mut Buffer, title: &str, selected: bool, area: Rect, theme: &Theme) {
+    let style = if selected {
+        Style::default().fg(theme.content).reversed()
+    } else {
+        Style::default().fg(theme.content)
+    };
+    Paragraph::new(title)
+        .style(style)
         .alignment(Alignment::Left)
         .block(Block::default().padding(Padding::horizontal(1)))
         .render(area, buf);
@@ -350,7 +448,12 @@ fn draw_file_title(buf: &mut Buffer, comic_state: &ComicState, area: Rect, theme
 fn draw_file_status(buf: &mut Buffer, comic_state: &ComicState, area: Rect, theme: &Theme) {
     match comic_state.current_status() {
         ComicStatus::Waiting => {
-            let label = Span::styled("waiting", Style::default().fg(theme.content));
+            let label = if comic_state.started {
+                "starting"
+            } else {
+                "waiting"
+            };
+            let label = Span::styled(label, Style::default().fg(theme.content));
             let gauge = Gauge::default()
                 .gauge_style(theme.border)
                 .ratio(0.0)
@@ -362,11 +465,12 @@ fn draw_file_status(buf: &mut Buffer, comic_state: &ComicState, area: Rect, them
             stage,
             progress,
             start,
+            step,
         } => {
             let elapsed = start.elapsed();
             let color = stage_color(*stage, theme);
             let label = Span::styled(
-                format!("{} {:.1}s", stage, elapsed.as_secs_f64()),
+                format!("{} {:.1}s", step, elapsed.as_secs_f64()),
                 Style::default().fg(theme.gauge_label),
             );
             let gauge = Gauge::default()
@@ -376,7 +480,7 @@ fn draw_file_status(buf: &mut Buffer, comic_state: &ComicState, area: Rect, them
 
             gauge.render(area, buf);
         }
-        ComicStatus::ImageProcessingStart { .. } | ComicStatus::ImageProcessed => {
+        ComicStatus::ImageProcessingStart { .. } | ComicStatus::ImageProcessed { .. } => {
             let elapsed = comic_state
                 .image_processing_start
                 .map(|s| s.elapsed())
@@ -406,10 +510,40 @@ fn draw_file_status(buf: &mut Buffer, comic_state: &ComicState, area: Rect, them
         ComicStatus::StageCompleted { .. } | ComicStatus::ImageProcessingComplete { .. } => {
             unreachable!("not storing this status")
         }
-        ComicStatus::Success => {
+        ComicStatus::Success { stats } | ComicStatus::CompletedWithWarnings { stats, .. } => {
+            let mut details = stats
+                .target_quality
+                .map(|quality| format!(" q{}", quality))
+                .unwrap_or_default();
+            // a single format is the configured one, a mix is worth showing
+            if stats.mixed_formats() {
+                details.push_str(&format!(" ({})", stats.page_formats()));
+            }
+            let [timing_area, stats_area] = Layout::horizontal([
+                Constraint::Fill(1),
+                Constraint::Length(20 + details.chars().count() as u16),
+            ])
+            .areas(area);
+
             StageTimingBar::new(&comic_state.timings, theme)
-                .width(area.width)
-                .render(area, buf);
+                .width(timing_area.width)
+                .render(timing_area, buf);
+
+            // highlight comics that need a second look
+            let (marker, color) = match comic_state.current_status() {
+                ComicStatus::CompletedWithWarnings { .. } => ("! ", theme.accent),
+                _ => ("", theme.content),
+            };
+            Paragraph::new(format!(
+                "{}{} → {}{}",
+                marker,
+                format_bytes(stats.input_bytes),
+                format_bytes(stats.output_bytes),
+                details
+            ))
+            .style(Style::default().fg(color))
+            .alignment(Alignment::Center)
+            .render(stats_area, buf);
         }
         ComicStatus::Failed { error, .. } => {
             let error_text = error.to_string();
@@ -457,10 +591,26 @@ fn draw_footer(buf: &mut Buffer, state: &ProgressState, area: Rect, theme: &Them
     let [controls_area, legend_area] =
         Layout::horizontal([Constraint::Fill(1), Constraint::Fill(1)]).areas(area);
 
+    if let Some((path, _)) = &state.overwrite_prompt {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        Paragraph::new(format!(
+            "{name} exists, overwrite? y: yes | n: no | a: all | s: skip all"
+        ))
+        .style(Style::default().fg(theme.content).bold())
+        .alignment(ratatui::layout::Alignment::Center)
+        .render(area, buf);
+        return;
+    }
+
+    let pause = if state.pause.is_paused() {
+        "p: resume"
+    } else {
+        "p: pause"
+    };
     let keys = if show_scrollbar {
-        "↑/k: up | ↓/j: down | t: toggle theme | q: quit"
+        format!("↑/k: up | ↓/j: down | x: cancel comic | {pause} | t: toggle theme | q: quit")
     } else {
-        "t: toggle theme | q: quit"
+        format!("{pause} | t: toggle theme | q: quit")
     };
 
     let keys = Paragraph::new(keys)
@@ -482,7 +632,7 @@ fn draw_stage_legend(buf: &mut Buffer, area: Rect, theme: &Theme, output_format:
             ComicStage::Convert,
         ],
         OutputFormat::Epub => vec![ComicStage::Process, ComicStage::Package],
-        OutputFormat::Cbz => vec![ComicStage::Process, ComicStage::Package],
+        OutputFormat::Cbz | OutputFormat::Folder => vec![ComicStage::Process, ComicStage::Package],
     };
 
     let constraints = vec![Constraint::Length(16); stages.len()];
@@ -550,11 +700,11 @@ impl<'a> Widget for StageTimingBar<'a> {
         let bar_area = horizontal[0];
         let total_label_area = horizontal[1];
 
-        if !self.timing.stages.is_empty() {
+        if !self.timing.stages().is_empty() {
             // Create Fill constraints proportional to each stage's duration
             let constraints: Vec<Constraint> = self
                 .timing
-                .stages
+                .stages()
                 .iter()
                 .map(|stage| {
                     Constraint::Fill((stage.duration.as_secs_f64() / total * 100.0).round() as u16)
@@ -565,7 +715,7 @@ impl<'a> Widget for StageTimingBar<'a> {
                 .flex(ratatui::layout::Flex::Start)
                 .split(bar_area);
 
-            for (stage, area) in self.timing.stages.iter().zip(stage_areas.iter()) {
+            for (stage, area) in self.timing.stages().iter().zip(stage_areas.iter()) {
                 let color = stage_color(stage.stage, self.theme);
 
                 buf.set_style(*area, Style::default().bg(color));